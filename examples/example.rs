@@ -12,6 +12,17 @@ fn main() {
             include_regex: true,
             include_datetime: true,
             include_cast: true,
+            include_strings: true,
+            include_inflection: true,
+            include_unicode: true,
+            include_similarity: true,
+            include_locale: true,
+            include_decimal: true,
+            include_bigint: true,
+            include_bits: true,
+            include_predicates: true,
+            arithmetic: Default::default(),
+            stringify: Default::default(),
         })
         .init();
 
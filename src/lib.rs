@@ -1,5 +1,6 @@
 #![doc = include_str ! ("./../README.md")]
 #![forbid(unsafe_code)]
+#![allow(clippy::get_first, clippy::collapsible_match, clippy::manual_unwrap_or)]
 
 pub mod types {
     pub type Expr = resolver::Expr;
@@ -12,790 +13,7290 @@ pub mod types {
 
 pub mod template {
     use lazy_static::lazy_static;
-    use regex::Regex;
 
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::ops::Range;
+    use std::sync::{Arc, Weak};
+
+    use crate::eval_wrapper::{builtins, prepare_expression, EvalConfig, Stringify};
     use crate::types::*;
 
     lazy_static! {
-        static ref CONDITION_PATTERN: Regex = Regex::new(r"(<\?([^\?]*)\?>)").unwrap();
         static ref CONTEXT_SYM: String = String::from("$");
     }
 
-    pub fn resolve_template(
-        template: String,
-        context: Value,
-    ) -> Result<String, resolver::Error> {
-        let mut map = std::collections::HashMap::<String, String>::new();
-        for cap in CONDITION_PATTERN.captures_iter(&*template) {
-            let a = &cap[1];
-            let b = cap[2].trim();
-            if !b.is_empty() {
-                let mut expr = Expr::new(b)
-                    .value(CONTEXT_SYM.to_string(), &context);
-                let value = expr.exec()?;
-                let value_str = match value {
-                    Value::Null => "null".into(),
-                    Value::Bool(boolean) => boolean.to_string(),
-                    Value::Number(number) => number.to_string(),
-                    Value::String(string) => string,
-                    Value::Array(arr) => serde_json::to_string(&arr)
-                        .unwrap_or_else(|_| "null".into()),
-                    Value::Object(obj) => serde_json::to_string(&obj)
-                        .unwrap_or_else(|_| "null".into())
-                };
-                map.insert(a.to_string(), value_str);
-            } else {
-                map.insert(a.to_string(), "".into());
-            }
+    /// The markers around template tags, `<?` and `?>` by default.
+    ///
+    /// A backslash right before an opening delimiter (`\<?`) emits the
+    /// delimiter literally, and a doubled backslash emits one backslash
+    /// followed by a tag. Inside a tag, quoted strings may contain the
+    /// closing delimiter.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Delimiters {
+        open: String,
+        close: String,
+    }
+
+    impl Delimiters {
+        pub fn new<S: Into<String>, E: Into<String>>(open: S, close: E) -> Delimiters {
+            Delimiters { open: open.into(), close: close.into() }
         }
 
-        let mut result = template;
-        for (key, value) in map.iter() {
-            // TODO(Dustin): Replace by range?
-            result = result.replace(key, value);
+        pub fn open(&self) -> &str {
+            &self.open
         }
 
-        Ok(result)
+        pub fn close(&self) -> &str {
+            &self.close
+        }
     }
-}
 
-pub mod eval_wrapper {
-    use chrono::{Datelike, Timelike};
-    use resolver::{to_value, Expr};
-    use regex::Regex;
-    // use inflection_rs::inflection;
-    use string_utility::prelude::*;
+    impl Default for Delimiters {
+        fn default() -> Self {
+            Delimiters::new("<?", "?>")
+        }
+    }
 
-    use crate::types::*;
+    /// A lexical piece of a template. Spans are byte ranges into the source.
+    #[derive(Debug, Clone, PartialEq)]
+    enum Token {
+        Text(Range<usize>),
+        /// A tag; `inner` is the trimmed text between the delimiters.
+        Tag { span: Range<usize>, inner: Range<usize> },
+    }
+
+    /// Finds the end of the tag whose content starts at `from`: the first
+    /// closing delimiter outside single or double quoted strings.
+    fn find_close(template: &str, from: usize, close: &str) -> Option<usize> {
+        let mut quote: Option<char> = None;
+        for (i, c) in template[from..].char_indices() {
+            if let Some(q) = quote {
+                if c == q {
+                    quote = None;
+                }
+            } else if template[from + i..].starts_with(close) {
+                return Some(from + i);
+            } else if c == '\'' || c == '"' {
+                quote = Some(c);
+            }
+        }
+        None
+    }
+
+    /// Splits `template` into literal text and tags in a single scan.
+    fn tokenize(template: &str, delimiters: &Delimiters) -> Result<Vec<Token>, resolver::Error> {
+        let (open, close) = (delimiters.open(), delimiters.close());
+        if open.is_empty() || close.is_empty() {
+            return Err(template_error("delimiters must not be empty"));
+        }
+
+        let mut tokens = Vec::new();
+        // Start of the pending literal text, and where to look for a tag.
+        let mut text_start = 0;
+        let mut cursor = 0;
+        while let Some(found) = template[cursor..].find(open) {
+            let tag_start = cursor + found;
+            let backslashes = template[text_start..tag_start]
+                .bytes()
+                .rev()
+                .take_while(|byte| *byte == b'\\')
+                .count();
+            if backslashes % 2 == 1 {
+                // `\<?`: drop the backslash and keep the delimiter as text.
+                if tag_start - 1 > text_start {
+                    tokens.push(Token::Text(text_start..tag_start - 1));
+                }
+                text_start = tag_start;
+                cursor = tag_start + open.len();
+                continue;
+            }
+            let mut inner_start = tag_start + open.len();
+            // `<?-` strips the whitespace before the tag.
+            let trim_before = template[inner_start..].starts_with('-');
+            if trim_before {
+                inner_start += 1;
+            }
+
+            // `\\<?`: one literal backslash, then the tag.
+            let mut text_end = tag_start - backslashes / 2;
+            if trim_before {
+                text_end = text_start + template[text_start..text_end].trim_end().len();
+            }
+            if text_end > text_start {
+                tokens.push(Token::Text(text_start..text_end));
+            }
+
+            let mut inner_end = find_close(template, inner_start, close).ok_or_else(|| {
+                template_error(format!("unterminated `{open}` at byte {tag_start}"))
+            })?;
+            let tag_end = inner_end + close.len();
+            // `-?>` strips the whitespace after it.
+            let trim_after = inner_end > inner_start && template[..inner_end].ends_with('-');
+            if trim_after {
+                inner_end -= 1;
+            }
+            let inner = &template[inner_start..inner_end];
+            let trimmed = inner.trim_start();
+            let start = inner_start + (inner.len() - trimmed.len());
+            let end = start + trimmed.trim_end().len();
+            tokens.push(Token::Tag { span: tag_start..tag_end, inner: start..end });
+
+            text_start = tag_end;
+            if trim_after {
+                let rest = &template[tag_end..];
+                text_start += rest.len() - rest.trim_start().len();
+            }
+            cursor = text_start;
+        }
+        if text_start < template.len() {
+            tokens.push(Token::Text(text_start..template.len()));
+        }
+        Ok(tokens)
+    }
 
+    /// Parsed template: literal text, `<? expr ?>` placeholders and the
+    /// `<?if ?>`/`<?for ?>` blocks that contain them. Text is kept as ranges
+    /// into the source so rendering copies each literal exactly once, and
+    /// expressions as indices into `Template::expressions`.
     #[derive(Debug, Clone)]
-    pub struct EvalConfig {
-        pub include_maths: bool,
-        pub include_datetime: bool,
-        pub include_cast: bool,
-        pub include_regex: bool,
+    enum Node {
+        Text(Range<usize>),
+        /// A placeholder; `raw` ones bypass the template's `Escape` mode.
+        Expr { expression: usize, raw: bool },
+        If {
+            branches: Vec<(usize, Vec<Node>)>,
+            otherwise: Vec<Node>,
+        },
+        For {
+            var: String,
+            iterable: usize,
+            body: Vec<Node>,
+        },
+        /// A named block a template extending this one can override.
+        Block { name: String, body: Vec<Node> },
+        /// `<?include 'name' ?>`, replaced by the named template when a
+        /// `TemplateSet` links the template.
+        Include(String),
+        /// `<?extends 'name' ?>`, likewise resolved by a `TemplateSet`.
+        Extends(String),
     }
 
-    impl EvalConfig {
-        pub fn any(&self) -> bool {
-            self.include_maths
-                || self.include_datetime
-                || self.include_cast
-                || self.include_regex
+    /// A block whose `<?end?>` has not been reached yet.
+    enum OpenBlock {
+        If {
+            branches: Vec<(usize, Vec<Node>)>,
+            // `None` once `<?else?>` has been seen.
+            condition: Option<usize>,
+        },
+        For {
+            var: String,
+            iterable: usize,
+        },
+        Block(String),
+    }
+
+    fn template_error<S: AsRef<str>>(message: S) -> resolver::Error {
+        resolver::Error::Custom(format!("template: {}", message.as_ref()))
+    }
+
+    fn keyword<'a>(tag: &'a str, keyword: &str) -> Option<&'a str> {
+        let rest = tag.strip_prefix(keyword)?;
+        if rest.is_empty() {
+            Some(rest)
+        } else if rest.starts_with(char::is_whitespace) {
+            Some(rest.trim())
+        } else {
+            None
         }
     }
 
-    impl Default for EvalConfig {
-        fn default() -> Self {
-            Self {
-                include_maths: true,
-                include_datetime: true,
-                include_cast: true,
-                include_regex: true,
+    /// Splits `expression` on the `|` pipes that are outside quotes and
+    /// brackets. `||` is left alone.
+    fn split_pipes(expression: &str) -> Vec<&str> {
+        let mut segments = Vec::new();
+        let mut depth = 0_usize;
+        let mut quote: Option<char> = None;
+        let mut start = 0;
+        let mut chars = expression.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if let Some(q) = quote {
+                if c == q {
+                    quote = None;
+                }
+                continue;
+            }
+            match c {
+                '\'' | '"' => quote = Some(c),
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' => depth = depth.saturating_sub(1),
+                '|' if chars.peek().is_some_and(|(_, next)| *next == '|') => {
+                    chars.next();
+                }
+                '|' if depth == 0 => {
+                    segments.push(&expression[start..i]);
+                    start = i + 1;
+                }
+                _ => {}
             }
         }
+        segments.push(&expression[start..]);
+        segments
     }
 
-    fn value_to_string(val: &Value) -> String {
-        match val {
-            Value::Number(x) => x.as_f64().unwrap().to_string(),
-            Value::Bool(x) => x.to_string(),
-            Value::String(x) => x.to_string(),
-            Value::Array(x) => serde_json::to_string(x)
-                .unwrap_or_else(|_| "null".into()),
-            Value::Object(x) => serde_json::to_string(x)
-                .unwrap_or_else(|_| "null".into()),
-            _ => String::from("null"),
+    /// Rewrites `value | name(args) | other` into `other(name(value, args))`,
+    /// so a filter is any function called with the piped value first.
+    fn apply_filters(expression: &str) -> Result<String, resolver::Error> {
+        let mut segments = split_pipes(expression).into_iter();
+        let mut result = segments.next().unwrap_or_default().trim().to_string();
+        for filter in segments {
+            let filter = filter.trim();
+            let (name, args) = match filter.split_once('(') {
+                Some((name, args)) => match args.strip_suffix(')') {
+                    Some(args) => (name.trim_end(), args.trim()),
+                    None => return Err(template_error(format!("invalid filter `{filter}`"))),
+                },
+                None => (filter, ""),
+            };
+            let is_identifier = name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+            if result.is_empty() || !is_identifier {
+                return Err(template_error(format!("invalid filter `{filter}` in `{expression}`")));
+            }
+            result = if args.is_empty() {
+                format!("{name}({result})")
+            } else {
+                format!("{name}({result}, {args})")
+            };
         }
+        Ok(result)
     }
 
-    pub fn math_consts() -> Value {
-        serde_json::json!{{
-            "MIN_INT": i64::MIN,
-            "MAX_INT": i64::MAX,
-            "MAX_FLOAT": f64::MAX,
-            "MIN_FLOAT": f64::MIN,
-            "INC": f64::NAN,
-            "NOT_A_NUMBER": f64::NAN,
-            "INFINITE": f64::INFINITY,
-            "NEG_INFINITE": f64::NEG_INFINITY,
-            "E": std::f64::consts::E,
-            "FRAC_1_SQRT_2": std::f64::consts::FRAC_1_SQRT_2,
-            "FRAC_2_SQRT_PI": std::f64::consts::FRAC_2_SQRT_PI,
-            "FRAC_1_PI": std::f64::consts::FRAC_1_PI,
-            "FRAC_PI_2": std::f64::consts::FRAC_PI_2,
-            "FRAC_PI_3": std::f64::consts::FRAC_PI_3,
-            "FRAC_PI_4": std::f64::consts::FRAC_PI_4,
-            "FRAC_PI_6": std::f64::consts::FRAC_PI_6,
-            "FRAC_PI_8": std::f64::consts::FRAC_PI_8,
-            "LN_2": std::f64::consts::LN_2,
-            "LN_10": std::f64::consts::LN_10,
-            "LOG2_10": std::f64::consts::LOG2_10,
-            "LOG2_E": std::f64::consts::LOG2_E,
-            "LOG10_2": std::f64::consts::LOG10_2,
-            "LOG10_E": std::f64::consts::LOG10_E,
-            "PI": std::f64::consts::PI,
-            "SQRT_2": std::f64::consts::SQRT_2,
-            "TAU": std::f64::consts::TAU,
-        }}
+    /// Removes the `raw` filter from a placeholder, returning whether it was
+    /// there. The first segment is the value, so a variable named `raw` is kept.
+    fn strip_raw(expression: &str) -> (String, bool) {
+        let segments = split_pipes(expression);
+        let filters: Vec<&str> = segments[1..].iter().copied().filter(|segment| segment.trim() != "raw").collect();
+        let raw = filters.len() < segments.len() - 1;
+        let kept: Vec<&str> = std::iter::once(segments[0]).chain(filters).collect();
+        (kept.join("|"), raw)
     }
 
-    #[derive(Clone)]
-    pub struct ExprWrapper {
-        expr: Expr,
-        config: EvalConfig,
+    /// One step of a variable path: `.name`, `['name']` or `[0]`.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub enum Segment {
+        Key(String),
+        Index(usize),
     }
 
-    impl ExprWrapper {
-        pub fn new<S: AsRef<str>>(expression: S) -> ExprWrapper {
-            ExprWrapper {
-                expr: Expr::new(expression.as_ref()),
-                config: Default::default(),
+    /// A variable an expression reads, such as `$.user.tags[0]` or `item`.
+    /// Paths stop at the first computed index.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub struct Reference {
+        pub root: String,
+        pub path: Vec<Segment>,
+    }
+
+    impl Reference {
+        /// Looks the reference up in `contexts`, the last one first, like the
+        /// evaluator does.
+        fn resolve<'v>(&self, contexts: &'v [resolver::Context]) -> Option<&'v Value> {
+            let mut value = contexts.iter().rev().find_map(|context| context.get(&self.root))?;
+            for segment in &self.path {
+                value = match (segment, value) {
+                    (Segment::Key(key), Value::Object(object)) => object.get(key)?,
+                    (Segment::Index(index), Value::Array(array)) => array.get(*index)?,
+                    _ => return None,
+                };
             }
+            Some(value)
         }
+    }
 
-        pub fn config(mut self, config: EvalConfig) -> ExprWrapper {
-            self.config = config;
-            self
+    impl std::fmt::Display for Reference {
+        fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str(&self.root)?;
+            for segment in &self.path {
+                match segment {
+                    Segment::Key(key) => write!(formatter, ".{key}")?,
+                    Segment::Index(index) => write!(formatter, "[{index}]")?,
+                }
+            }
+            Ok(())
         }
+    }
 
-        pub fn init(mut self) -> ExprWrapper {
-            self.expr = expr_wrapper(self.expr.clone(), self.config.clone());
-            self
-        }
+    /// Variables and functions used by an expression.
+    #[derive(Debug, Clone, Default)]
+    struct Usage {
+        references: Vec<Reference>,
+        functions: Vec<String>,
+    }
 
-        pub fn value<T, V>(mut self, name: T, value: V) -> ExprWrapper
-            where T: Into<String>,
-                  V: serde::Serialize
-        {
-            self.expr = self.expr.value(name, value);
-            self
+    fn is_identifier_start(c: char) -> bool {
+        c.is_alphabetic() || c == '_' || c == '$'
+    }
+
+    fn is_identifier_char(c: char) -> bool {
+        c.is_alphanumeric() || c == '_'
+    }
+
+    /// Lists the variables and functions `expression` uses, skipping string
+    /// literals and the operators spelled as words.
+    fn scan_usage(expression: &str) -> Usage {
+        let chars: Vec<char> = expression.chars().collect();
+        let identifier = |start: usize| {
+            let mut end = start + 1;
+            while end < chars.len() && is_identifier_char(chars[end]) {
+                end += 1;
+            }
+            (chars[start..end].iter().collect::<String>(), end)
+        };
+
+        let mut usage = Usage::default();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            if c == '\'' || c == '"' {
+                i += 1;
+                while i < chars.len() && chars[i] != c {
+                    i += 1;
+                }
+                i += 1;
+                continue;
+            }
+            let after_dot = i > 0 && chars[i - 1] == '.';
+            if !is_identifier_start(c) || after_dot || c.is_numeric() {
+                i += 1;
+                continue;
+            }
+
+            let (root, mut end) = identifier(i);
+            if chars[end..].iter().find(|c| !c.is_whitespace()) == Some(&'(') {
+                usage.functions.push(root);
+                i = end;
+                continue;
+            }
+            if matches!(root.as_str(), "true" | "false" | "in" | "not") {
+                i = end;
+                continue;
+            }
+
+            let mut path = Vec::new();
+            loop {
+                if chars.get(end) == Some(&'.') && chars.get(end + 1).is_some_and(|c| is_identifier_start(*c)) {
+                    let (key, next) = identifier(end + 1);
+                    path.push(Segment::Key(key));
+                    end = next;
+                    continue;
+                }
+                if chars.get(end) == Some(&'[') {
+                    let close = chars[end..].iter().position(|c| *c == ']').map(|p| end + p);
+                    if let Some(close) = close {
+                        let inner: String = chars[end + 1..close].iter().collect();
+                        let inner = inner.trim();
+                        let quoted = inner.len() >= 2
+                            && (inner.starts_with('\'') && inner.ends_with('\'')
+                                || inner.starts_with('"') && inner.ends_with('"'));
+                        if let Ok(index) = inner.parse::<usize>() {
+                            path.push(Segment::Index(index));
+                            end = close + 1;
+                            continue;
+                        } else if quoted {
+                            path.push(Segment::Key(inner[1..inner.len() - 1].to_string()));
+                            end = close + 1;
+                            continue;
+                        }
+                    }
+                }
+                break;
+            }
+            usage.references.push(Reference { root, path });
+            i = end;
         }
+        usage
+    }
 
-        pub fn function<T, F>(mut self, name: T, function: F) -> ExprWrapper
-            where T: Into<String>,
-                  F: 'static + Fn(Vec<Value>) -> Result<Value, resolver::Error> + Sync + Send
-        {
-            self.expr = self.expr.function(name, function);
-            self
+    /// What a tag found by `analyze` does.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum TagKind {
+        /// `<? expr ?>`, writing its value.
+        Output,
+        /// `<?if expr ?>` or `<?elif expr ?>`.
+        Condition,
+        /// `<?for name in expr ?>`; the analysed expression is `expr`.
+        Loop,
+    }
+
+    /// A tag evaluating an expression, as found by `analyze`.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Placeholder {
+        pub kind: TagKind,
+        /// Byte range of the whole tag, delimiters included.
+        pub span: Range<usize>,
+        /// The expression, filters included and line breaks turned into
+        /// spaces.
+        pub expression: String,
+        /// Every variable read, loop variables included.
+        pub references: Vec<Reference>,
+        /// Every function called, filters included.
+        pub functions: Vec<String>,
+    }
+
+    impl Placeholder {
+        /// The references into the render context, `$.user.name` and alike.
+        pub fn context_paths(&self) -> impl Iterator<Item = &Reference> {
+            self.references.iter().filter(|reference| reference.root == *CONTEXT_SYM)
         }
+    }
 
-        pub fn exec(&mut self) -> Result<Value, resolver::Error> {
-            self.expr.exec()
+    /// Lists the tags of `template` that evaluate an expression, in order,
+    /// after checking that it compiles. Included and extended templates are
+    /// not followed.
+    pub fn analyze(template: &str) -> Result<Vec<Placeholder>, resolver::Error> {
+        analyze_with(template, &Delimiters::default())
+    }
+
+    /// Like `analyze`, for templates whose tags use `delimiters`.
+    pub fn analyze_with(template: &str, delimiters: &Delimiters) -> Result<Vec<Placeholder>, resolver::Error> {
+        let mut expressions = Vec::new();
+        parse(template, 0, delimiters, &mut expressions)?;
+        let expressions: Vec<String> = expressions.into_iter().map(|(expression, _)| expression).collect();
+        compile_expressions(&expressions)?;
+
+        let mut placeholders = Vec::new();
+        for token in tokenize(template, delimiters)? {
+            let Token::Tag { span, inner } = token else {
+                continue;
+            };
+            let inner = single_line(&template[inner]);
+            let (kind, expression) = if let Some(condition) = keyword(&inner, "if") {
+                (TagKind::Condition, condition.to_string())
+            } else if let Some(condition) = keyword(&inner, "elif") {
+                (TagKind::Condition, condition.to_string())
+            } else if let Some(header) = keyword(&inner, "for") {
+                let iterable = header.split_once(" in ").map_or("", |(_, iterable)| iterable);
+                (TagKind::Loop, iterable.trim().to_string())
+            } else if ["else", "end"].iter().any(|kw| keyword(&inner, kw).is_some())
+                || ["include", "extends", "block"]
+                    .iter()
+                    .any(|kw| keyword(&inner, kw).is_some_and(|arg| !arg.is_empty()))
+                || inner.is_empty()
+            {
+                continue;
+            } else {
+                (TagKind::Output, strip_raw(&inner).0.trim().to_string())
+            };
+            let usage = scan_usage(&apply_filters(&expression)?);
+            placeholders.push(Placeholder {
+                kind,
+                span,
+                expression,
+                references: usage.references,
+                functions: usage.functions,
+            });
         }
+        Ok(placeholders)
     }
 
-    /// This function is DEPRECATED see README.md for new usage.
-    #[deprecated]
-    pub fn expr_wrapper(exp: Expr, config: EvalConfig) -> Expr {
-        if !config.any() {
-            return exp;
+    /// 1-based line and column of byte `offset` in `source`.
+    fn position(source: &str, offset: usize) -> (usize, usize) {
+        let before = &source[..offset];
+        let line = before.matches('\n').count() + 1;
+        let column = before[before.rfind('\n').map_or(0, |i| i + 1)..].chars().count() + 1;
+        (line, column)
+    }
+
+    /// Functions every template can use as filters, whatever its
+    /// `EvalConfig`: `default(value, fallback, falsy = false)`, `json` and
+    /// `escape`. `upper`, `lower`, `trim`, `truncate` and `join` are the
+    /// string built-ins.
+    fn standard_filters(functions: &mut resolver::Functions, stringify: &Stringify) {
+        functions.insert("default".to_string(), resolver::Function::new(|values| {
+            let (value, fallback) = match values.as_slice() {
+                [value, fallback] | [value, fallback, _] => (value, fallback),
+                _ => return Err(resolver::Error::Custom("default expects 2 or 3 arguments".into())),
+            };
+            let falsy = values.get(2).is_some_and(truthy);
+            if value.is_null() || (falsy && !truthy(value)) {
+                Ok(fallback.clone())
+            } else {
+                Ok(value.clone())
+            }
+        }));
+        functions.insert("json".to_string(), resolver::Function::new(|values| {
+            match values.as_slice() {
+                [value] => Ok(to_value(value.to_string())),
+                _ => Err(resolver::Error::Custom("json expects 1 argument".into())),
+            }
+        }));
+        functions.insert("escape".to_string(), resolver::Function::new(|values| {
+            match values.as_slice() {
+                [Value::String(value)] => Ok(to_value(escape_html(value))),
+                [value] => Ok(to_value(escape_html(&value.to_string()))),
+                _ => Err(resolver::Error::Custom("escape expects 1 argument".into())),
+            }
+        }));
+        if !functions.contains_key("truncate")
+            && let Some(truncate) = builtins(&EvalConfig { stringify: stringify.clone(), ..Default::default() })
+                .functions
+                .remove("truncate")
+        {
+            functions.insert("truncate".to_string(), truncate);
         }
+    }
 
-        let mut result = exp;
+    fn escape_html(value: &str) -> String {
+        escape_markup(value, "&#39;")
+    }
 
-        if config.include_cast {
-            result = result
-                .function("int", |value| {
-                    if value.is_empty() {
-                        return Ok(to_value(0_i64));
-                    }
-                    let v = match value.get(0) {
-                        None => to_value(0),
-                        Some(value) => value.to_owned(),
-                    };
+    fn escape_markup(value: &str, apostrophe: &str) -> String {
+        let mut result = String::with_capacity(value.len());
+        for c in value.chars() {
+            match c {
+                '&' => result.push_str("&amp;"),
+                '<' => result.push_str("&lt;"),
+                '>' => result.push_str("&gt;"),
+                '"' => result.push_str("&quot;"),
+                '\'' => result.push_str(apostrophe),
+                c => result.push(c),
+            }
+        }
+        result
+    }
 
-                    let num: i64 = match v {
-                        Value::Number(x) => {
-                            if x.is_f64() {
-                                x.as_f64().unwrap_or(0_f64) as i64
-                            } else {
-                                x.as_i64().unwrap_or(0)
-                            }
-                        }
-                        Value::Bool(x) => {
-                            if x {
-                                1
-                            } else {
-                                0
+    /// How placeholder results are escaped before they are written out.
+    /// Append the `raw` filter to a placeholder (`<? $.body | raw ?>`) to
+    /// insert its result unchanged.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum Escape {
+        /// Results are inserted as they are.
+        #[default]
+        None,
+        /// `&`, `<`, `>`, `"` and `'` become character references.
+        Html,
+        /// Like `Html`, using `&apos;` for `'`.
+        Xml,
+        /// Escaped for use inside a JSON string literal; the quotes are left
+        /// to the template.
+        Json,
+        /// Percent-encoded as a URL component; only unreserved characters are
+        /// kept.
+        Url,
+        /// Quoted as a single POSIX shell word.
+        Shell,
+    }
+
+    impl Escape {
+        pub fn apply(self, value: &str) -> String {
+            match self {
+                Escape::None => value.to_string(),
+                Escape::Html => escape_html(value),
+                Escape::Xml => escape_markup(value, "&apos;"),
+                Escape::Json => {
+                    let quoted = Value::String(value.to_string()).to_string();
+                    quoted[1..quoted.len() - 1].to_string()
+                }
+                Escape::Url => {
+                    let mut result = String::with_capacity(value.len());
+                    for byte in value.bytes() {
+                        match byte {
+                            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                                result.push(byte as char)
                             }
+                            _ => result.push_str(&format!("%{byte:02X}")),
                         }
-                        Value::String(x) => atoi(x),
-                        _ => 0,
-                    };
-                    Ok(to_value(num))
-                })
-                .function("float", |value| {
-                    if value.is_empty() {
-                        return Ok(to_value(f64::NAN));
                     }
-                    let v = match value.get(0) {
-                        None => to_value(0_f64),
-                        Some(value) => value.to_owned(),
-                    };
-                    let num: f64 = match v {
-                        Value::Number(x) => x.as_f64().unwrap_or(0_f64),
-                        Value::Bool(x) => {
-                            if x {
-                                1.0
-                            } else {
-                                0.0
-                            }
-                        }
-                        Value::String(x) => match x.parse::<f64>() {
-                            Ok(x) => x,
-                            _ => f64::NAN,
-                        },
-                        _ => f64::NAN,
-                    };
+                    result
+                }
+                Escape::Shell => format!("'{}'", value.replace('\'', r"'\''")),
+            }
+        }
+    }
 
-                    Ok(to_value(num))
-                })
-                .function("bool", |value| {
-                    if value.is_empty() {
-                        return Ok(to_value(false));
-                    }
-                    let v = match value.get(0) {
-                        None => to_value(false),
-                        Some(value) => value.to_owned(),
-                    };
+    /// Builds the node tree of `template`, collecting every expression it
+    /// evaluates into `expressions`.
+    /// Spans in the result are offset by `base`, the position of `template`
+    /// in the buffer holding every source a `Template` was linked from.
+    fn parse(
+        template: &str,
+        base: usize,
+        delimiters: &Delimiters,
+        expressions: &mut Vec<(String, Range<usize>)>,
+    ) -> Result<Vec<Node>, resolver::Error> {
+        let mut stack: Vec<(OpenBlock, Vec<Node>)> = Vec::new();
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut tag = 0..0;
+        let mut add_expression = |expression: &str, tag: &Range<usize>| {
+            expressions.push((apply_filters(expression)?, tag.clone()));
+            Ok::<_, resolver::Error>(expressions.len() - 1)
+        };
 
-                    let result: bool = match v {
-                        Value::Number(x) => x.as_f64().unwrap_or(0_f64) != 0.0,
-                        Value::Bool(x) => x,
-                        Value::String(x) => !x.is_empty(),
-                        Value::Array(x) => !x.is_empty(),
-                        Value::Object(x) => !x.is_empty(),
-                        _ => false,
-                    };
+        for token in tokenize(template, delimiters)? {
+            let (span, inner) = match token {
+                Token::Text(range) => {
+                    nodes.push(Node::Text(range.start + base..range.end + base));
+                    continue;
+                }
+                Token::Tag { span, inner } => (span, single_line(&template[inner])),
+            };
+            tag = span.start + base..span.end + base;
+            let inner = inner.as_str();
 
-                    Ok(to_value(result))
-                })
-                .function("str", |value| {
-                    if value.is_empty() {
-                        return Ok(to_value("".to_string()));
+            if let Some(condition) = keyword(inner, "if") {
+                if condition.is_empty() {
+                    return Err(template_error("<?if?> requires a condition"));
+                }
+                let condition = Some(add_expression(condition, &tag)?);
+                stack.push((OpenBlock::If { branches: vec![], condition }, std::mem::take(&mut nodes)));
+            } else if let Some(condition) = keyword(inner, "elif") {
+                match stack.last_mut() {
+                    Some((OpenBlock::If { branches, condition: current @ Some(_) }, _)) => {
+                        branches.push((current.take().unwrap(), std::mem::take(&mut nodes)));
+                        *current = Some(add_expression(condition, &tag)?);
                     }
-                    let v = match value.get(0) {
-                        None => to_value("".to_string()),
-                        Some(value) => value.to_owned(),
-                    };
-
-                    let result: String = match v {
-                        Value::Number(x) => {
-                            if x.is_f64() {
-                                x.as_f64().unwrap_or(0_f64).to_string()
-                            } else {
-                                x.as_i64().unwrap_or(0_i64).to_string()
-                            }
+                    _ => return Err(template_error("<?elif?> without a matching <?if?>")),
+                }
+            } else if keyword(inner, "else").is_some() {
+                match stack.last_mut() {
+                    Some((OpenBlock::If { branches, condition: current @ Some(_) }, _)) => {
+                        branches.push((current.take().unwrap(), std::mem::take(&mut nodes)));
+                    }
+                    _ => return Err(template_error("<?else?> without a matching <?if?>")),
+                }
+            } else if let Some(header) = keyword(inner, "for") {
+                let (var, iterable) = header
+                    .split_once(" in ")
+                    .map(|(var, iterable)| (var.trim(), iterable.trim()))
+                    .filter(|(var, iterable)| !var.is_empty() && !iterable.is_empty())
+                    .ok_or_else(|| template_error(format!("invalid loop <?for {header} ?>")))?;
+                let block = OpenBlock::For { var: var.to_string(), iterable: add_expression(iterable, &tag)? };
+                stack.push((block, std::mem::take(&mut nodes)));
+            } else if keyword(inner, "end").is_some() {
+                let (block, parent) = stack
+                    .pop()
+                    .ok_or_else(|| template_error("<?end?> without an open block"))?;
+                let body = std::mem::replace(&mut nodes, parent);
+                nodes.push(match block {
+                    OpenBlock::If { mut branches, condition } => match condition {
+                        Some(condition) => {
+                            branches.push((condition, body));
+                            Node::If { branches, otherwise: vec![] }
                         }
-                        Value::Bool(x) => x.to_string(),
-                        Value::String(x) => x,
-                        Value::Array(x) => serde_json::to_string(&x)
-                            .unwrap_or_else(|_| "null".to_string()),
-                        Value::Object(x) => serde_json::to_string(&x)
-                            .unwrap_or_else(|_| "null".to_string()),
-                        _ => "null".to_string(),
-                    };
-                    Ok(to_value(result))
+                        None => Node::If { branches, otherwise: body },
+                    },
+                    OpenBlock::For { var, iterable } => Node::For { var, iterable, body },
+                    OpenBlock::Block(name) => Node::Block { name, body },
                 });
+            } else if let Some(name) = keyword(inner, "include").filter(|name| !name.is_empty()) {
+                nodes.push(Node::Include(template_name(name)?));
+            } else if let Some(name) = keyword(inner, "extends").filter(|name| !name.is_empty()) {
+                nodes.push(Node::Extends(template_name(name)?));
+            } else if let Some(name) = keyword(inner, "block").filter(|name| !name.is_empty()) {
+                if !name.chars().all(is_identifier_char) {
+                    return Err(template_error(format!("invalid block name `{name}`")));
+                }
+                stack.push((OpenBlock::Block(name.to_string()), std::mem::take(&mut nodes)));
+            } else if !inner.is_empty() {
+                let (expression, raw) = strip_raw(inner);
+                let expression = add_expression(&expression, &tag)?;
+                nodes.push(Node::Expr { expression, raw });
+            }
         }
 
-        if config.include_maths {
-            result = result
-                .value("maths", math_consts())
-                .value("NAN", to_value(f64::NAN))
-                .value("INFINITY", to_value(f64::INFINITY))
-                .value("NEG_INFINITY", to_value(f64::NEG_INFINITY));
+        if !stack.is_empty() {
+            return Err(template_error("missing <?end?>"));
         }
+        Ok(nodes)
+    }
 
-        if config.include_regex {
-            result = result.function("is_match", |value| {
-                if value.len() < 2 {
-                    return Ok(to_value(false));
+    /// Turns the line breaks and tabs outside string literals into spaces, as
+    /// the expression parser only separates tokens with spaces.
+    fn single_line(tag: &str) -> String {
+        let mut quote: Option<char> = None;
+        tag.chars()
+            .map(|c| match quote {
+                Some(q) => {
+                    if c == q {
+                        quote = None;
+                    }
+                    c
                 }
-
-                let v = value.get(0).unwrap();
-                let pattern = value.get(1).unwrap().as_str().unwrap();
-
-                let value: String = value_to_string(v);
-
-                let prog = Regex::new(pattern).unwrap();
-                let is_match = prog.is_match(&value);
-                Ok(to_value(is_match))
-            }).function("extract", |value| {
-                if value.len() < 2 {
-                    return Ok(to_value(false));
+                None if c == '\'' || c == '"' => {
+                    quote = Some(c);
+                    c
                 }
+                None if c.is_whitespace() => ' ',
+                None => c,
+            })
+            .collect()
+    }
 
-                let v = value
-                    .get(0).expect("missing first positional argument (string)");
-                let pattern = value
-                    .get(1).expect("missing second positional argument (pattern)")
-                    .as_str().expect("second positional arguments needs to be a string");
-
-                let value: String = value_to_string(v);
-                let prog = Regex::new(pattern).unwrap();
-                match prog.find(&value) {
-                    None => Ok(to_value("".to_string())),
-                    Some(m) => {
-                        let (start, end) = (m.start(), m.end());
-                        Ok(to_value(value.substring(start..end)))
-                    }
-                }
-            });
+    /// The quoted template name of an `include` or `extends` tag.
+    fn template_name(quoted: &str) -> Result<String, resolver::Error> {
+        let unquoted = quoted
+            .strip_prefix('\'')
+            .and_then(|name| name.strip_suffix('\''))
+            .or_else(|| quoted.strip_prefix('"').and_then(|name| name.strip_suffix('"')));
+        match unquoted {
+            Some(name) if !name.is_empty() => Ok(name.to_string()),
+            _ => Err(template_error(format!("expected a quoted template name, got `{quoted}`"))),
         }
+    }
 
-        if config.include_datetime {
-            result = result
-                .function("get_day", |values| {
-                    let current_time = eval_tz_parse_args(values, 1);
-                    Ok(to_value(current_time.date_naive().day()))
-                })
-                .function("get_month", |values| {
-                    let current_time = eval_tz_parse_args(values, 1);
-                    Ok(to_value(current_time.date_naive().month()))
-                })
-                .function("get_year", |values| {
-                    let current_time = eval_tz_parse_args(values, 1);
-                    Ok(to_value(current_time.date_naive().year()))
-                })
-                .function("get_weekday", |values| {
-                    let current_time = eval_tz_parse_args(values, 1);
-                    Ok(to_value(
-                        current_time.date_naive().weekday().number_from_monday(),
-                    ))
-                })
-                .function("is_weekday", |values| {
-                    let current_time = eval_tz_parse_args(values, 1);
-                    let weekday = current_time.date_naive().weekday().number_from_monday();
-                    Ok(to_value(weekday < 6))
-                })
-                .function("is_weekend", |values| {
-                    let current_time = eval_tz_parse_args(values, 1);
-                    let weekday = current_time.date_naive().weekday();
-                    let weekends = [chrono::Weekday::Sat, chrono::Weekday::Sun];
-                    Ok(to_value(weekends.contains(&weekday)))
-                })
-                .function("get_time", |extract| {
-                    if extract.len() < 2 {
-                        let t = now("_".to_owned());
-                        return Ok(to_value(t.hour()));
-                    }
-
-                    let v: String = match extract.get(1).unwrap() {
-                        Value::Number(x) => {
-                            if x.is_f64() {
-                                x.as_f64().unwrap().to_string()
-                            } else if x.is_i64() {
-                                x.as_i64().unwrap().to_string()
-                            } else if x.is_u64() {
-                                x.as_u64().unwrap().to_string()
-                            } else {
-                                x.to_string()
-                            }
-                        }
-                        Value::Bool(x) => x.to_string(),
-                        Value::String(x) => x.to_string(),
-                        Value::Array(x) => serde_json::to_string(x).unwrap(),
-                        Value::Object(x) => serde_json::to_string(x).unwrap(),
-                        _ => String::from("null"),
-                    };
-
-                    let dt = eval_tz_parse_args(extract, 2);
-                    let current_time = dt.time();
-
-                    let result = match v.as_str() {
-                        "h" | "hour" | "hours" => current_time.hour(),
-                        "m" | "minute" | "minutes" => current_time.minute(),
-                        "s" | "second" | "seconds" => current_time.second(),
-                        _ => current_time.hour(),
-                    };
-                    Ok(to_value(result))
-                });
-        }
+    type TemplateFunction = dyn Fn(Vec<Value>) -> Result<Value, resolver::Error> + Sync + Send;
 
-        result
+    /// Compiled expressions of the templates rendered on this thread, keyed by
+    /// the address of each template's liveness token. `resolver::Expr` is not
+    /// `Send`, so every thread compiles a template's expressions once, on the
+    /// first render, and reuses them afterwards. Dropping a template evicts its
+    /// entry on the dropping thread; entries other threads hold for it are
+    /// pruned the next time those threads store one.
+    type CompiledCache = HashMap<usize, (Weak<()>, Vec<Expr>)>;
 
-        // TODO: is_nan(n), is_min_int(n), is_int_max(n), includes(arr)
-        // TODO: min(arr), max(arr), abs(n), pow(n, p), sum(arr), reverse(arr), sort(arr), unique(arr)
+    thread_local! {
+        static COMPILED: RefCell<CompiledCache> = RefCell::new(HashMap::new());
     }
 
-    fn eval_tz_parse_args(
-        arguments: Vec<Value>,
-        min_args: usize,
-    ) -> chrono::DateTime<chrono_tz::Tz> {
-        let default_tz = "_".to_owned();
-        if arguments.is_empty() || arguments.len() < min_args {
-            log::warn!("No arguments");
-            return now(default_tz);
-        }
-
-        let v: Option<String> = match arguments.get(0).unwrap() {
-            Value::String(x) => Some(x.to_string()),
-            _ => None,
-        };
-
-        match v {
-            None => {
-                log::warn!("Invalid Timezone");
-                now(default_tz)
-            }
-            Some(timezone) => now(timezone)
-        }
+    fn compile_expressions(expressions: &[String]) -> Result<Vec<Expr>, resolver::Error> {
+        expressions.iter().map(|expression| Expr::new(expression.as_str()).compile()).collect()
     }
 
-    fn now(tz: String) -> chrono::DateTime<chrono_tz::Tz> {
-        chrono::offset::Utc::now()
-            .with_timezone(&str_to_tz(tz))
+    /// Gathers the sources of a template and of the templates it includes or
+    /// extends into one buffer, and their nodes into one tree.
+    struct Linker<'a> {
+        loader: Option<&'a dyn Loader>,
+        delimiters: &'a Delimiters,
+        source: String,
+        files: Vec<(String, Range<usize>)>,
+        expressions: Vec<(String, Range<usize>)>,
+        /// Templates being linked, outermost first, to detect cycles.
+        stack: Vec<String>,
+        /// Overrides from the templates extending the one being linked.
+        blocks: HashMap<String, Vec<Node>>,
     }
 
-    fn str_to_tz(timezone: String) -> chrono_tz::Tz {
-        match timezone.parse() {
-            Ok(tz) => tz,
-            Err(_err) => {
-                log::warn!("Defaulted to UTC timezone");
-                chrono_tz::UTC
+    impl<'a> Linker<'a> {
+        fn new(loader: Option<&'a dyn Loader>, delimiters: &'a Delimiters) -> Linker<'a> {
+            Linker {
+                loader,
+                delimiters,
+                source: String::new(),
+                files: Vec::new(),
+                expressions: Vec::new(),
+                stack: Vec::new(),
+                blocks: HashMap::new(),
             }
         }
-    }
 
-    fn atoi(s: String) -> i64 {
-        let mut item = s
-            .trim()
-            .split(char::is_whitespace)
-            .next()
-            .unwrap_or("")
-            .split(char::is_alphabetic)
-            .next()
-            .unwrap_or("");
+        fn load(&mut self, name: &str) -> Result<Vec<Node>, resolver::Error> {
+            if self.stack.iter().any(|loading| loading == name) {
+                let cycle = [self.stack.as_slice(), &[name.to_string()]].concat().join(" -> ");
+                return Err(template_error(format!("recursive template `{name}`: {cycle}")));
+            }
+            let loader = self
+                .loader
+                .ok_or_else(|| template_error(format!("cannot load `{name}` outside a TemplateSet")))?;
+            let source = loader.load(name)?;
+            self.stack.push(name.to_string());
+            let linked = self.add(Some(name), &source);
+            self.stack.pop();
+            linked
+        }
 
-        let mut end_idx = 0;
-        for (pos, c) in item.chars().enumerate() {
-            if pos == 0 {
-                continue;
+        fn add(&mut self, name: Option<&str>, source: &str) -> Result<Vec<Node>, resolver::Error> {
+            let base = self.source.len();
+            self.source.push_str(source);
+            if let Some(name) = name {
+                self.files.push((name.to_string(), base..self.source.len()));
             }
+            let nodes = parse(&self.source[base..], base, self.delimiters, &mut self.expressions)?;
 
-            if !c.is_alphanumeric() {
-                end_idx = pos;
-                break;
+            let parent = nodes.iter().find_map(|node| match node {
+                Node::Extends(parent) => Some(parent.clone()),
+                _ => None,
+            });
+            match parent {
+                // Only the blocks of a template extending another one matter;
+                // the ones it is extended by take precedence.
+                Some(parent) => {
+                    collect_blocks(nodes, &mut self.blocks);
+                    self.load(&parent)
+                }
+                None => self.expand(nodes),
             }
         }
 
-        if end_idx > 0 {
-            item = &item[0..end_idx];
+        /// Replaces includes by the templates they name and blocks by their
+        /// overrides.
+        fn expand(&mut self, nodes: Vec<Node>) -> Result<Vec<Node>, resolver::Error> {
+            let mut result = Vec::with_capacity(nodes.len());
+            for node in nodes {
+                match node {
+                    Node::Include(name) => {
+                        // Included templates do not see the includer's overrides.
+                        let blocks = std::mem::take(&mut self.blocks);
+                        let included = self.load(&name);
+                        self.blocks = blocks;
+                        result.extend(included?);
+                    }
+                    Node::Extends(name) => {
+                        return Err(template_error(format!("<?extends '{name}' ?> must be at the top level")));
+                    }
+                    Node::Block { name, body } => {
+                        let body = match self.blocks.remove(&name) {
+                            Some(overridden) => {
+                                let expanded = self.expand(overridden.clone());
+                                self.blocks.insert(name.clone(), overridden);
+                                expanded?
+                            }
+                            None => self.expand(body)?,
+                        };
+                        result.push(Node::Block { name, body });
+                    }
+                    Node::If { branches, otherwise } => {
+                        let branches = branches
+                            .into_iter()
+                            .map(|(condition, body)| Ok((condition, self.expand(body)?)))
+                            .collect::<Result<_, resolver::Error>>()?;
+                        let otherwise = self.expand(otherwise)?;
+                        result.push(Node::If { branches, otherwise });
+                    }
+                    Node::For { var, iterable, body } => {
+                        let body = self.expand(body)?;
+                        result.push(Node::For { var, iterable, body });
+                    }
+                    node @ (Node::Text(_) | Node::Expr { .. }) => result.push(node),
+                }
+            }
+            Ok(result)
         }
+    }
 
-        let result = item.parse::<i64>();
-        match result {
-            Ok(v) => v,
-            Err(error) => match error.kind() {
-                std::num::IntErrorKind::NegOverflow => i64::MIN,
-                std::num::IntErrorKind::PosOverflow => i64::MAX,
-                std::num::IntErrorKind::InvalidDigit => {
-                    let result = item.parse::<f64>();
-                    match result {
-                        Ok(v) => v.round() as i64,
-                        _ => 0,
+    /// Adds the blocks defined anywhere in `nodes` to `blocks`, unless an
+    /// override is already there.
+    fn collect_blocks(nodes: Vec<Node>, blocks: &mut HashMap<String, Vec<Node>>) {
+        for node in nodes {
+            match node {
+                Node::Block { name, body } => {
+                    collect_blocks(body.clone(), blocks);
+                    blocks.entry(name).or_insert(body);
+                }
+                Node::If { branches, otherwise } => {
+                    for (_, body) in branches {
+                        collect_blocks(body, blocks);
                     }
+                    collect_blocks(otherwise, blocks);
                 }
-                _ => 0,
-            },
+                Node::For { body, .. } => collect_blocks(body, blocks),
+                _ => {}
+            }
         }
     }
-}
 
+    /// A template parsed once and rendered any number of times, from any
+    /// number of threads.
+    ///
+    /// ```
+    /// use eval_utility::{eval_wrapper::EvalConfig, template::Template};
+    ///
+    /// let template = Template::compile("Hi <? title($.name) ?>!", &EvalConfig::default()).unwrap();
+    /// let context = serde_json::json!({"name": "kar"});
+    /// assert_eq!(template.render(&context).unwrap(), "Hi Kar!");
+    /// ```
+    pub struct Template {
+        /// Sources of the template and of every template it was linked with.
+        source: String,
+        /// Name and span in `source` of each template loaded by a
+        /// `TemplateSet`.
+        files: Vec<(String, Range<usize>)>,
+        nodes: Vec<Node>,
+        expressions: Vec<String>,
+        /// Span in `source` of the tag each expression comes from.
+        tags: Vec<Range<usize>>,
+        usage: Vec<Usage>,
+        functions: resolver::Functions,
+        values: resolver::Context,
+        escape: Escape,
+        stringify: Stringify,
+        context_symbol: String,
+        spread_context: bool,
+        token: Arc<()>,
+    }
 
-#[cfg(test)]
-mod eval {
-    use chrono::offset::Utc as Date;
-    use chrono::{Datelike, Timelike};
-    use resolver::to_value;
-    use serde_json::json;
-
-    use crate::{eval_wrapper::{EvalConfig, ExprWrapper}, template};
+    impl Template {
+        /// Parses `source` and checks the syntax of every expression in it.
+        /// Expressions can use the built-ins enabled by `config`.
+        pub fn compile<S: Into<String>>(source: S, config: &EvalConfig) -> Result<Template, resolver::Error> {
+            Template::compile_with(source, config, &Delimiters::default())
+        }
 
-    #[derive(Default)]
-    struct Spec;
+        /// Like `compile`, for templates whose tags use `delimiters`.
+        pub fn compile_with<S: Into<String>>(
+            source: S,
+            config: &EvalConfig,
+            delimiters: &Delimiters,
+        ) -> Result<Template, resolver::Error> {
+            let mut linker = Linker::new(None, delimiters);
+            let nodes = linker.add(None, &source.into())?;
+            Template::link(linker, nodes, config)
+        }
 
-    impl Spec {
-        pub fn eval<S: AsRef<str>>(&self, expression: S) -> resolver::Value {
-            let mut expr = ExprWrapper::new(expression.as_ref())
-                .config(EvalConfig {
-                    include_maths: true,
-                    include_regex: true,
-                    include_datetime: true,
-                    include_cast: true,
-                })
-                .init();
-            let result = expr.exec();
+        fn link(linker: Linker, nodes: Vec<Node>, config: &EvalConfig) -> Result<Template, resolver::Error> {
+            let Linker { source, files, expressions, .. } = linker;
+            let (expressions, tags): (Vec<String>, Vec<Range<usize>>) = expressions.into_iter().unzip();
+            let usage = expressions.iter().map(|expression| scan_usage(expression)).collect();
+            let expressions = expressions
+                .iter()
+                .map(|expression| prepare_expression(expression, config))
+                .collect::<Result<Vec<String>, _>>()?;
+            let compiled = compile_expressions(&expressions)?;
 
-            if result.is_err() {
-                panic!(
-                    "Failed to parse expression: \"{}\" {:?}",
-                    expression.as_ref(),
-                    result
-                )
-            }
+            let mut builtins = builtins(config);
+            standard_filters(&mut builtins.functions, &config.stringify);
+            let template = Template {
+                source,
+                files,
+                nodes,
+                usage,
+                expressions,
+                tags,
+                functions: builtins.functions,
+                values: builtins.values,
+                escape: Escape::None,
+                stringify: config.stringify.clone(),
+                context_symbol: CONTEXT_SYM.clone(),
+                spread_context: false,
+                token: Arc::new(()),
+            };
+            template.store_compiled(compiled);
+            Ok(template)
+        }
 
-            result.unwrap()
+        /// Binds `name` for every render, like `ExprWrapper::value`.
+        pub fn value<T, V>(mut self, name: T, value: V) -> Template
+            where T: Into<String>,
+                  V: serde::Serialize
+        {
+            self.values.insert(name.into(), to_value(value));
+            self
         }
-    }
 
-    #[test]
-    fn maths_consts() {
+        /// Registers a custom function, taking precedence over built-ins of the
+        /// same name, like `ExprWrapper::function`.
+        pub fn function<T, F>(mut self, name: T, function: F) -> Template
+            where T: Into<String>,
+                  F: 'static + Fn(Vec<Value>) -> Result<Value, resolver::Error> + Sync + Send
+        {
+            self.functions.insert(name.into(), resolver::Function::new(function));
+            self
+        }
+
+        /// Escapes every placeholder result with `escape`, except for `raw`
+        /// placeholders.
+        pub fn escape(mut self, escape: Escape) -> Template {
+            self.escape = escape;
+            self
+        }
+
+        /// Binds the render context to `symbol` instead of `$`.
+        pub fn context_symbol<S: Into<String>>(mut self, symbol: S) -> Template {
+            self.context_symbol = symbol.into();
+            self
+        }
+
+        /// Also binds each top-level key of the render context, when it is an
+        /// object, so `<? name ?>` reads `$.name`.
+        pub fn spread_context(mut self, spread: bool) -> Template {
+            self.spread_context = spread;
+            self
+        }
+
+        /// Renders the template with `context` bound to `$`.
+        pub fn render(&self, context: &Value) -> Result<String, resolver::Error> {
+            self.render_with(context, &RenderOptions::default())
+        }
+
+        /// Renders the template with `context` bound to `$`, streaming the
+        /// output into `writer` as it is produced.
+        pub fn render_to<W: std::io::Write>(&self, context: &Value, writer: W) -> Result<(), resolver::Error> {
+            self.render_to_with(context, writer, &RenderOptions::default())
+        }
+
+        /// Like `render`, handling null values, missing values and errors as
+        /// `options` say.
+        pub fn render_with(&self, context: &Value, options: &RenderOptions) -> Result<String, resolver::Error> {
+            Ok(self.render_with_errors(context, options)?.output)
+        }
+
+        /// Like `render_with`, also returning the errors rendering continued
+        /// past when `options.continue_on_error` is set.
+        pub fn render_with_errors(&self, context: &Value, options: &RenderOptions) -> Result<Rendered, resolver::Error> {
+            self.render_bindings(&self.bind(context), options)
+        }
+
+        /// Renders the template with each of `bindings` as a variable, rather
+        /// than with a single context.
+        pub fn render_bindings(&self, bindings: &Bindings, options: &RenderOptions) -> Result<Rendered, resolver::Error> {
+            let mut output = String::with_capacity(self.source.len());
+            let errors = self.render_nodes(bindings.values.clone(), options, &mut output)?;
+            Ok(Rendered { output, errors })
+        }
+
+        /// Like `render_to`, handling null values, missing values and errors
+        /// as `options` say.
+        pub fn render_to_with<W: std::io::Write>(
+            &self,
+            context: &Value,
+            writer: W,
+            options: &RenderOptions,
+        ) -> Result<(), resolver::Error> {
+            self.render_nodes(self.bind(context).values, options, &mut IoOutput(writer)).map(|_| ())
+        }
+
+        fn bind(&self, context: &Value) -> Bindings {
+            let bindings = if self.spread_context { Bindings::new().spread(context) } else { Bindings::new() };
+            bindings.value(self.context_symbol.clone(), context)
+        }
+
+        fn render_nodes(
+            &self,
+            bindings: resolver::Context,
+            options: &RenderOptions,
+            out: &mut dyn Output,
+        ) -> Result<Vec<RenderError>, resolver::Error> {
+            // Taken out of the cache for the duration of the render, so custom
+            // functions may render templates themselves.
+            let compiled = match self.take_compiled() {
+                Some(compiled) => compiled,
+                None => compile_expressions(&self.expressions)?,
+            };
+
+            let mut renderer = Renderer {
+                template: self,
+                compiled: &compiled,
+                options,
+                contexts: vec![self.values.clone(), bindings],
+                errors: Vec::new(),
+            };
+            let rendered = renderer.render(&self.nodes, out);
+            let errors = renderer.errors;
+            self.store_compiled(compiled);
+            rendered?;
+            if errors.is_empty() || options.continue_on_error {
+                Ok(errors)
+            } else {
+                let errors: Vec<String> = errors.iter().map(RenderError::to_string).collect();
+                Err(template_error(errors.join("; ")))
+            }
+        }
+
+        /// Describes a failure of the tag evaluating `expression`.
+        fn error(&self, expression: usize, message: String) -> RenderError {
+            let tag = self.tags[expression].clone();
+            let file = self.files.iter().rev().find(|(_, range)| range.contains(&tag.start));
+            let (template, (line, column)) = match file {
+                Some((name, range)) => {
+                    (Some(name.clone()), position(&self.source[range.clone()], tag.start - range.start))
+                }
+                None => (None, position(&self.source, tag.start)),
+            };
+            RenderError { message, template, line, column, tag: self.source[tag].to_string() }
+        }
+
+        fn cache_key(&self) -> usize {
+            Arc::as_ptr(&self.token) as usize
+        }
+
+        fn take_compiled(&self) -> Option<Vec<Expr>> {
+            COMPILED.with(|cache| {
+                cache.borrow_mut().remove(&self.cache_key()).map(|(_, compiled)| compiled)
+            })
+        }
+
+        fn store_compiled(&self, compiled: Vec<Expr>) {
+            COMPILED.with(|cache| {
+                let mut cache = cache.borrow_mut();
+                cache.retain(|_, (token, _)| token.strong_count() > 0);
+                cache.insert(self.cache_key(), (Arc::downgrade(&self.token), compiled));
+            });
+        }
+    }
+
+    impl Drop for Template {
+        fn drop(&mut self) {
+            let key = self.cache_key();
+            let _ = COMPILED.try_with(|cache| {
+                if let Ok(mut cache) = cache.try_borrow_mut() {
+                    cache.remove(&key);
+                }
+            });
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn compiled_templates() -> usize {
+        COMPILED.with(|cache| cache.borrow().len())
+    }
+
+    impl std::fmt::Debug for Template {
+        fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.debug_struct("Template").field("source", &self.source).finish()
+        }
+    }
+
+    /// How placeholders that evaluate to `null` are written.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum NullRendering {
+        /// As the text `null`.
+        #[default]
+        Literal,
+        /// As nothing.
+        Empty,
+        /// As a render error.
+        Error,
+    }
+
+    /// How placeholders reading a variable or path that does not exist are
+    /// handled. Only placeholders are checked, not `<?if ?>` conditions or
+    /// `<?for ?>` iterables, and placeholders using `??` or the `default`
+    /// filter are never reported as missing.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum MissingValues {
+        /// Render them like any other `null`.
+        #[default]
+        Null,
+        /// Fail the render.
+        Error,
+    }
+
+    /// Per-render options of a `Template`.
+    #[derive(Debug, Clone)]
+    pub struct RenderOptions {
+        pub null: NullRendering,
+        pub missing: MissingValues,
+        /// Keep rendering past missing values and `null` errors, leaving the
+        /// failing placeholders empty, then fail with a single error listing
+        /// each of them and its position. Implies `MissingValues::Error`.
+        pub strict: bool,
+        /// Keep rendering past every error, writing `error_marker` in place of
+        /// each failing tag; see `Template::render_with_errors` for the list
+        /// of errors.
+        pub continue_on_error: bool,
+        pub error_marker: String,
+    }
+
+    impl Default for RenderOptions {
+        fn default() -> Self {
+            Self {
+                null: NullRendering::default(),
+                missing: MissingValues::default(),
+                strict: false,
+                continue_on_error: false,
+                error_marker: "[ERROR]".into(),
+            }
+        }
+    }
+
+    impl RenderOptions {
+        /// Options failing on anything missing or null, reporting all of them.
+        pub fn strict() -> RenderOptions {
+            RenderOptions {
+                null: NullRendering::Error,
+                missing: MissingValues::Error,
+                strict: true,
+                ..Default::default()
+            }
+        }
+    }
+
+    /// A failure of a tag while rendering.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct RenderError {
+        pub message: String,
+        /// The template the tag is in, for templates linked by a `TemplateSet`.
+        pub template: Option<String>,
+        /// 1-based position of the tag.
+        pub line: usize,
+        pub column: usize,
+        /// Source text of the tag.
+        pub tag: String,
+    }
+
+    impl std::fmt::Display for RenderError {
+        fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(formatter, "{} in `{}` at line {}, column {}", self.message, self.tag, self.line, self.column)?;
+            if let Some(template) = &self.template {
+                write!(formatter, " of `{template}`")?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Output of `Template::render_with_errors`.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Rendered {
+        pub output: String,
+        /// Errors rendering continued past, in order.
+        pub errors: Vec<RenderError>,
+    }
+
+    /// Named values a template is rendered with, for
+    /// `Template::render_bindings`.
+    #[derive(Debug, Clone, Default)]
+    pub struct Bindings {
+        values: resolver::Context,
+    }
+
+    impl Bindings {
+        pub fn new() -> Bindings {
+            Default::default()
+        }
+
+        pub fn value<T, V>(mut self, name: T, value: V) -> Bindings
+            where T: Into<String>,
+                  V: serde::Serialize
+        {
+            self.values.insert(name.into(), to_value(value));
+            self
+        }
+
+        /// Binds each top-level key of `object`; anything but an object is
+        /// ignored.
+        pub fn spread<V: serde::Serialize>(mut self, object: V) -> Bindings {
+            if let Value::Object(entries) = to_value(object) {
+                self.values.extend(entries);
+            }
+            self
+        }
+    }
+
+    /// Destination of rendered text.
+    trait Output {
+        fn push_str(&mut self, text: &str) -> Result<(), resolver::Error>;
+    }
+
+    impl Output for String {
+        fn push_str(&mut self, text: &str) -> Result<(), resolver::Error> {
+            String::push_str(self, text);
+            Ok(())
+        }
+    }
+
+    struct IoOutput<W>(W);
+
+    impl<W: std::io::Write> Output for IoOutput<W> {
+        fn push_str(&mut self, text: &str) -> Result<(), resolver::Error> {
+            self.0
+                .write_all(text.as_bytes())
+                .map_err(|err| template_error(format!("failed to write output: {err}")))
+        }
+    }
+
+    /// State of a single render. `contexts` holds the custom values, the
+    /// context under `$`, then one frame per enclosing loop; later frames
+    /// shadow earlier ones.
+    struct Renderer<'a> {
+        template: &'a Template,
+        compiled: &'a [Expr],
+        options: &'a RenderOptions,
+        contexts: Vec<resolver::Context>,
+        /// Errors held back until the end of the render.
+        errors: Vec<RenderError>,
+    }
+
+    impl Renderer<'_> {
+        fn eval(&self, expression: usize) -> Result<Value, RenderError> {
+            resolver::ExecOptions::new(&self.compiled[expression])
+                .contexts(&self.contexts)
+                .functions(&self.template.functions)
+                .exec()
+                .map_err(|err| self.template.error(expression, err.to_string()))
+        }
+
+        /// Handles the failure of a tag: fails the render right away, unless
+        /// `held` (a strict render's missing or null value) or rendering
+        /// continues past errors.
+        fn fail(&mut self, error: RenderError, held: bool, out: &mut dyn Output) -> Result<(), resolver::Error> {
+            if self.options.continue_on_error {
+                out.push_str(&self.options.error_marker)?;
+            } else if !held {
+                return Err(template_error(error.to_string()));
+            }
+            self.errors.push(error);
+            Ok(())
+        }
+
+        /// Checks the variables a placeholder reads against the missing value
+        /// policy, returning whether to evaluate it.
+        fn check_missing(&mut self, expression: usize, out: &mut dyn Output) -> Result<bool, resolver::Error> {
+            if self.options.missing == MissingValues::Null && !self.options.strict {
+                return Ok(true);
+            }
+            let usage = &self.template.usage[expression];
+            if usage.functions.iter().any(|name| name == "default")
+                || self.template.expressions[expression].contains("??")
+            {
+                return Ok(true);
+            }
+            let missing: Vec<String> = usage
+                .references
+                .iter()
+                .filter(|reference| reference.resolve(&self.contexts).is_none())
+                .map(|reference| format!("`{reference}`"))
+                .collect();
+            if missing.is_empty() {
+                return Ok(true);
+            }
+            let error = self.template.error(expression, format!("missing {}", missing.join(", ")));
+            self.fail(error, self.options.strict, out)?;
+            Ok(false)
+        }
+
+        /// Applies the null rendering policy, returning whether to write the
+        /// placeholder as `null`.
+        fn check_null(&mut self, expression: usize, out: &mut dyn Output) -> Result<bool, resolver::Error> {
+            match self.options.null {
+                NullRendering::Literal => Ok(true),
+                NullRendering::Empty => Ok(false),
+                NullRendering::Error => {
+                    let error = self.template.error(expression, "null value".to_string());
+                    self.fail(error, self.options.strict, out)?;
+                    Ok(false)
+                }
+            }
+        }
+
+        fn render(&mut self, nodes: &[Node], out: &mut dyn Output) -> Result<(), resolver::Error> {
+            for node in nodes {
+                match node {
+                    Node::Text(range) => out.push_str(&self.template.source[range.clone()])?,
+                    Node::Expr { expression, raw } => {
+                        if !self.check_missing(*expression, out)? {
+                            continue;
+                        }
+                        let value = match self.eval(*expression) {
+                            Ok(value) => value,
+                            Err(error) => {
+                                self.fail(error, false, out)?;
+                                continue;
+                            }
+                        };
+                        if value.is_null() && !self.check_null(*expression, out)? {
+                            continue;
+                        }
+                        let value_str = match value {
+                            Value::String(string) => string,
+                            value => self.template.stringify.apply(&value),
+                        };
+                        if *raw {
+                            out.push_str(&value_str)?;
+                        } else {
+                            out.push_str(&self.template.escape.apply(&value_str))?;
+                        }
+                    }
+                    Node::If { branches, otherwise } => {
+                        let mut selected = Some(otherwise);
+                        for (condition, body) in branches {
+                            match self.eval(*condition) {
+                                Ok(value) if truthy(&value) => {
+                                    selected = Some(body);
+                                    break;
+                                }
+                                Ok(_) => {}
+                                Err(error) => {
+                                    self.fail(error, false, out)?;
+                                    selected = None;
+                                    break;
+                                }
+                            }
+                        }
+                        if let Some(selected) = selected {
+                            self.render(selected, out)?;
+                        }
+                    }
+                    Node::For { var, iterable, body } => {
+                        let items: Vec<Value> = match self.eval(*iterable) {
+                            Ok(Value::Null) => vec![],
+                            Ok(Value::Array(items)) => items,
+                            Ok(Value::Object(obj)) => obj.keys().map(to_value).collect(),
+                            Ok(Value::String(x)) => x.chars().map(|c| to_value(c.to_string())).collect(),
+                            Ok(x) => {
+                                let error = self.template.error(*iterable, format!("cannot iterate over {x}"));
+                                self.fail(error, false, out)?;
+                                continue;
+                            }
+                            Err(error) => {
+                                self.fail(error, false, out)?;
+                                continue;
+                            }
+                        };
+                        let length = items.len();
+                        for (index, item) in items.into_iter().enumerate() {
+                            let mut frame = resolver::Context::new();
+                            frame.insert(var.clone(), item);
+                            frame.insert("loop".to_string(), serde_json::json!({
+                                "index": index,
+                                "first": index == 0,
+                                "last": index + 1 == length,
+                                "length": length,
+                            }));
+                            self.contexts.push(frame);
+                            let rendered = self.render(body, out);
+                            self.contexts.pop();
+                            rendered?;
+                        }
+                    }
+                    Node::Block { body, .. } => self.render(body, out)?,
+                    Node::Include(name) | Node::Extends(name) => {
+                        return Err(template_error(format!("`{name}` was not linked")));
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn truthy(value: &Value) -> bool {
+        match value {
+            Value::Null => false,
+            Value::Bool(x) => *x,
+            Value::Number(x) => x.as_f64().unwrap_or(0_f64) != 0.0,
+            Value::String(x) => !x.is_empty(),
+            Value::Array(x) => !x.is_empty(),
+            Value::Object(x) => !x.is_empty(),
+        }
+    }
+
+    /// Compiles templates with the same built-ins, custom functions and values
+    /// an `ExprWrapper` would evaluate its expression with.
+    #[derive(Clone, Default)]
+    pub struct TemplateResolver {
+        config: EvalConfig,
+        delimiters: Delimiters,
+        escape: Escape,
+        options: RenderOptions,
+        context_symbol: Option<String>,
+        spread_context: bool,
+        values: Vec<(String, Value)>,
+        functions: Vec<(String, Arc<TemplateFunction>)>,
+    }
+
+    impl TemplateResolver {
+        pub fn new() -> TemplateResolver {
+            Default::default()
+        }
+
+        pub fn config(mut self, config: EvalConfig) -> TemplateResolver {
+            self.config = config;
+            self
+        }
+
+        pub fn delimiters(mut self, delimiters: Delimiters) -> TemplateResolver {
+            self.delimiters = delimiters;
+            self
+        }
+
+        pub fn escape(mut self, escape: Escape) -> TemplateResolver {
+            self.escape = escape;
+            self
+        }
+
+        pub fn context_symbol<S: Into<String>>(mut self, symbol: S) -> TemplateResolver {
+            self.context_symbol = Some(symbol.into());
+            self
+        }
+
+        pub fn spread_context(mut self, spread: bool) -> TemplateResolver {
+            self.spread_context = spread;
+            self
+        }
+
+        pub fn options(mut self, options: RenderOptions) -> TemplateResolver {
+            self.options = options;
+            self
+        }
+
+        pub fn value<T, V>(mut self, name: T, value: V) -> TemplateResolver
+            where T: Into<String>,
+                  V: serde::Serialize
+        {
+            self.values.push((name.into(), to_value(value)));
+            self
+        }
+
+        pub fn function<T, F>(mut self, name: T, function: F) -> TemplateResolver
+            where T: Into<String>,
+                  F: 'static + Fn(Vec<Value>) -> Result<Value, resolver::Error> + Sync + Send
+        {
+            self.functions.push((name.into(), Arc::new(function)));
+            self
+        }
+
+        /// Compiles `template` with this resolver's configuration, values and
+        /// functions, for rendering it repeatedly.
+        pub fn compile<S: Into<String>>(&self, template: S) -> Result<Template, resolver::Error> {
+            Ok(self.configure(Template::compile_with(template, &self.config, &self.delimiters)?))
+        }
+
+        fn configure(&self, template: Template) -> Template {
+            let mut result = template.escape(self.escape).spread_context(self.spread_context);
+            if let Some(symbol) = &self.context_symbol {
+                result = result.context_symbol(symbol.clone());
+            }
+            for (name, value) in &self.values {
+                result = result.value(name.clone(), value);
+            }
+            for (name, function) in &self.functions {
+                let function = Arc::clone(function);
+                result = result.function(name.clone(), move |args| function(args));
+            }
+            result
+        }
+
+        pub fn resolve<S: Into<String>>(
+            &self,
+            template: S,
+            context: &Value,
+        ) -> Result<String, resolver::Error> {
+            self.compile(template)?.render_with(context, &self.options)
+        }
+    }
+
+    /// Where a `TemplateSet` finds the templates named by `<?include ?>` and
+    /// `<?extends ?>` tags.
+    pub trait Loader: Send + Sync {
+        /// Returns the source of the template called `name`.
+        fn load(&self, name: &str) -> Result<String, resolver::Error>;
+    }
+
+    /// Templates kept in memory, by name.
+    #[derive(Debug, Clone, Default)]
+    pub struct MemoryLoader {
+        templates: HashMap<String, String>,
+    }
+
+    impl MemoryLoader {
+        pub fn new() -> MemoryLoader {
+            Default::default()
+        }
+
+        pub fn template<N: Into<String>, S: Into<String>>(mut self, name: N, source: S) -> MemoryLoader {
+            self.templates.insert(name.into(), source.into());
+            self
+        }
+    }
+
+    impl Loader for MemoryLoader {
+        fn load(&self, name: &str) -> Result<String, resolver::Error> {
+            self.templates
+                .get(name)
+                .cloned()
+                .ok_or_else(|| template_error(format!("unknown template `{name}`")))
+        }
+    }
+
+    /// Templates read from the files under a directory. Names are paths
+    /// relative to it and cannot leave it.
+    #[derive(Debug, Clone)]
+    pub struct DirectoryLoader {
+        root: std::path::PathBuf,
+        extension: Option<String>,
+    }
+
+    impl DirectoryLoader {
+        pub fn new<P: Into<std::path::PathBuf>>(root: P) -> DirectoryLoader {
+            DirectoryLoader { root: root.into(), extension: None }
+        }
+
+        /// Appends `.extension` to names that do not have one.
+        pub fn extension<S: Into<String>>(mut self, extension: S) -> DirectoryLoader {
+            self.extension = Some(extension.into());
+            self
+        }
+    }
+
+    impl Loader for DirectoryLoader {
+        fn load(&self, name: &str) -> Result<String, resolver::Error> {
+            let relative = std::path::Path::new(name);
+            let inside = relative
+                .components()
+                .all(|component| matches!(component, std::path::Component::Normal(_)));
+            if !inside {
+                return Err(template_error(format!("invalid template name `{name}`")));
+            }
+            let mut path = self.root.join(relative);
+            if let (Some(extension), None) = (&self.extension, path.extension()) {
+                path.set_extension(extension);
+            }
+            std::fs::read_to_string(&path)
+                .map_err(|err| template_error(format!("cannot load `{name}`: {err}")))
+        }
+    }
+
+    /// Templates that include and extend each other, found through a
+    /// `Loader` and compiled with the settings of a `TemplateResolver`.
+    ///
+    /// `<?include 'footer' ?>` inserts another template in place. A template
+    /// starting with `<?extends 'base' ?>` renders as `base`, with each
+    /// `<?block name ?>...<?end?>` it defines replacing the block of the same
+    /// name in `base`.
+    pub struct TemplateSet {
+        loader: Box<dyn Loader>,
+        resolver: TemplateResolver,
+    }
+
+    impl TemplateSet {
+        pub fn new<L: Loader + 'static>(loader: L) -> TemplateSet {
+            TemplateSet { loader: Box::new(loader), resolver: TemplateResolver::default() }
+        }
+
+        pub fn resolver(mut self, resolver: TemplateResolver) -> TemplateSet {
+            self.resolver = resolver;
+            self
+        }
+
+        /// Loads and compiles the template called `name`, with everything it
+        /// includes or extends.
+        pub fn get(&self, name: &str) -> Result<Template, resolver::Error> {
+            let mut linker = Linker::new(Some(self.loader.as_ref()), &self.resolver.delimiters);
+            let nodes = linker.load(name)?;
+            Ok(self.resolver.configure(Template::link(linker, nodes, &self.resolver.config)?))
+        }
+
+        pub fn render(&self, name: &str, context: &Value) -> Result<String, resolver::Error> {
+            self.get(name)?.render_with(context, &self.resolver.options)
+        }
+    }
+
+    /// Renders `template` against `context`, which expressions reach as `$`.
+    ///
+    /// Besides `<? expr ?>` placeholders, templates support conditional blocks
+    /// (`<?if $.vip ?>...<?elif $.member ?>...<?else?>...<?end?>`) and loops
+    /// (`<?for item in $.items ?>...<?end?>`). Inside a loop, `loop.index`
+    /// (zero based), `loop.first`, `loop.last` and `loop.length` describe the
+    /// current iteration. Any expression can be piped through filters, as in
+    /// `<? $.name | default('guest') | upper ?>`: each filter is a function
+    /// called with the value on its left as the first argument. Wrap a
+    /// bitwise or in parentheses to keep it from being read as a pipe.
+    /// Write `\<?` for a literal `<?`; `TemplateResolver::delimiters` picks
+    /// other tag markers. A `-` just inside a delimiter, as in `<?- $.a -?>`,
+    /// strips the whitespace, line breaks included, on that side of the tag.
+    /// Tags may span several lines.
+    ///
+    /// Expressions can use every built-in enabled by the default `EvalConfig`;
+    /// see `TemplateResolver` and `Template` to customise them or to render
+    /// the same template repeatedly, and `TemplateSet` for templates that
+    /// include or extend others.
+    pub fn resolve_template(
+        template: String,
+        context: Value,
+    ) -> Result<String, resolver::Error> {
+        TemplateResolver::default().resolve(template, &context)
+    }
+}
+
+pub mod eval_wrapper {
+    use chrono::{Datelike, Timelike};
+    use resolver::{to_value, Expr};
+    use regex::Regex;
+    use inflection_rs::inflection;
+    use string_utility::prelude::*;
+    use unicode_general_category::{get_general_category, GeneralCategory};
+    use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+    use unicode_segmentation::UnicodeSegmentation;
+    use unicode_width::UnicodeWidthStr;
+
+    use std::sync::Arc;
+
+    use crate::types::*;
+
+    /// Which built-ins an expression can call, and how it treats numbers and
+    /// text. Every built-in group is included by default; set its
+    /// `include_*` flag to `false` to leave it out.
+    #[derive(Debug, Clone)]
+    pub struct EvalConfig {
+        pub include_maths: bool,
+        pub include_datetime: bool,
+        pub include_cast: bool,
+        pub include_regex: bool,
+        pub include_strings: bool,
+        pub include_inflection: bool,
+        pub include_unicode: bool,
+        pub include_similarity: bool,
+        pub include_locale: bool,
+        /// `decimal`, `round_half_even`, `round_half_up` and the `dec_*`
+        /// arithmetic.
+        pub include_decimal: bool,
+        /// `bigint` and the `bigint_*` arithmetic.
+        pub include_bigint: bool,
+        /// `hex`, `oct`, `bin` and the bitwise built-ins.
+        pub include_bits: bool,
+        /// `is_nan`, `is_int`, `is_between`, `approx_eq` and the other numeric
+        /// predicates. JSON has no NaN or infinity, so while this is on `NAN`,
+        /// `INFINITY`, `NEG_INFINITY`, their `maths` counterparts and such
+        /// results of `float` are the strings `"NaN"`, `"inf"` and `"-inf"`,
+        /// which the predicates read back; otherwise they are null.
+        pub include_predicates: bool,
+        /// How operators treat numbers.
+        pub arithmetic: Arithmetic,
+        /// How built-ins taking strings, and templates, turn other values
+        /// into text.
+        pub stringify: Stringify,
+    }
+
+    impl EvalConfig {
+        pub fn any(&self) -> bool {
+            self.include_maths
+                || self.include_datetime
+                || self.include_cast
+                || self.include_regex
+                || self.include_strings
+                || self.include_inflection
+                || self.include_unicode
+                || self.include_similarity
+                || self.include_locale
+                || self.include_decimal
+                || self.include_bigint
+                || self.include_bits
+                || self.include_predicates
+                || self.arithmetic != Arithmetic::Float
+        }
+    }
+
+    impl Default for EvalConfig {
+        fn default() -> Self {
+            Self {
+                include_maths: true,
+                include_datetime: true,
+                include_cast: true,
+                include_regex: true,
+                include_strings: true,
+                include_inflection: true,
+                include_unicode: true,
+                include_similarity: true,
+                include_locale: true,
+                include_decimal: true,
+                include_bigint: true,
+                include_bits: true,
+                include_predicates: true,
+                arithmetic: Arithmetic::default(),
+                stringify: Stringify::default(),
+            }
+        }
+    }
+
+    /// How `+ - * / % **`, unary minus and comparisons treat numbers.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum Arithmetic {
+        /// The resolver's own operators, on `f64` and `i64`.
+        #[default]
+        Float,
+        /// Integer results that do not fit in `i64` or `u64`, and `int()` of
+        /// values out of range, are errors rather than wrapping, saturating
+        /// or truncating. Integer comparisons are exact.
+        Checked,
+    }
+
+    /// How numbers are written by a `Stringify` policy.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct NumberFormat {
+        /// Write floats with an integral value with a fractional part (`2.0`,
+        /// the default) rather than like integers (`2`). Integers are always
+        /// exact.
+        pub float_point: bool,
+        /// Fixed number of digits after the decimal point, for every number.
+        pub decimals: Option<usize>,
+        /// Separator between groups of three digits in the integer part.
+        pub group_separator: Option<char>,
+        pub decimal_separator: char,
+    }
+
+    impl Default for NumberFormat {
+        fn default() -> Self {
+            Self {
+                float_point: true,
+                decimals: None,
+                group_separator: None,
+                decimal_separator: '.',
+            }
+        }
+    }
+
+    /// How arrays and objects are written by a `Stringify` policy.
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub enum CollectionFormat {
+        /// Compact JSON.
+        #[default]
+        Json,
+        /// Indented JSON.
+        PrettyJson,
+        /// Items, or `key: value` pairs, stringified with the same policy and
+        /// joined with a separator.
+        Joined(String),
+    }
+
+    /// Turns values into text for templates and for the string built-ins,
+    /// e.g. `upper(12)` or `join(items, ', ')`.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Stringify {
+        pub numbers: NumberFormat,
+        pub collections: CollectionFormat,
+        pub true_text: String,
+        pub false_text: String,
+    }
+
+    impl Default for Stringify {
+        fn default() -> Self {
+            Self {
+                numbers: NumberFormat::default(),
+                collections: CollectionFormat::default(),
+                true_text: "true".into(),
+                false_text: "false".into(),
+            }
+        }
+    }
+
+    impl Stringify {
+        pub fn apply(&self, value: &Value) -> String {
+            match value {
+                Value::Null => "null".into(),
+                Value::Bool(true) => self.true_text.clone(),
+                Value::Bool(false) => self.false_text.clone(),
+                Value::Number(number) => self.number(number),
+                Value::String(string) => string.clone(),
+                Value::Array(items) => match &self.collections {
+                    CollectionFormat::Joined(separator) => {
+                        items.iter().map(|item| self.apply(item)).collect::<Vec<_>>().join(separator)
+                    }
+                    format => self.json(value, format),
+                },
+                Value::Object(entries) => match &self.collections {
+                    CollectionFormat::Joined(separator) => entries
+                        .iter()
+                        .map(|(key, item)| format!("{key}: {}", self.apply(item)))
+                        .collect::<Vec<_>>()
+                        .join(separator),
+                    format => self.json(value, format),
+                },
+            }
+        }
+
+        fn json(&self, value: &Value, format: &CollectionFormat) -> String {
+            let json = match format {
+                CollectionFormat::PrettyJson => serde_json::to_string_pretty(value),
+                _ => serde_json::to_string(value),
+            };
+            json.unwrap_or_else(|_| "null".into())
+        }
+
+        fn number(&self, number: &serde_json::Number) -> String {
+            let format = &self.numbers;
+            let text = match (number.as_i64(), number.as_u64(), number.as_f64()) {
+                (Some(x), _, _) => match format.decimals {
+                    Some(0) | None => x.to_string(),
+                    Some(decimals) => format!("{x}.{}", "0".repeat(decimals)),
+                },
+                (_, Some(x), _) => match format.decimals {
+                    Some(0) | None => x.to_string(),
+                    Some(decimals) => format!("{x}.{}", "0".repeat(decimals)),
+                },
+                (_, _, Some(x)) => match format.decimals {
+                    Some(decimals) => format!("{x:.decimals$}"),
+                    None if x.fract() == 0.0 && x.abs() < 1e16 && !format.float_point => format!("{x:.0}"),
+                    None => number.to_string(),
+                },
+                _ => number.to_string(),
+            };
+            if format.group_separator.is_none() && format.decimal_separator == '.' {
+                return text;
+            }
+
+            let (sign, unsigned) = match text.strip_prefix('-') {
+                Some(unsigned) => ("-", unsigned),
+                None => ("", text.as_str()),
+            };
+            // Exponents are left alone, grouping only applies to plain digits.
+            let (mantissa, exponent) = match unsigned.find(['e', 'E']) {
+                Some(i) => unsigned.split_at(i),
+                None => (unsigned, ""),
+            };
+            let (integer, fraction) = match mantissa.split_once('.') {
+                Some((integer, fraction)) => (integer, Some(fraction)),
+                None => (mantissa, None),
+            };
+            let integer = match format.group_separator {
+                Some(separator) if exponent.is_empty() => crate::formatting::group_digits(integer, separator, 3),
+                _ => integer.to_string(),
+            };
+            match fraction {
+                Some(fraction) => format!("{sign}{integer}{}{fraction}{exponent}", format.decimal_separator),
+                None => format!("{sign}{integer}{exponent}"),
+            }
+        }
+    }
+
+    /// Stringifies `val` with the default policy, for arguments that name
+    /// something (a locale, a pattern) rather than text being transformed.
+    pub(crate) fn value_to_string(val: &Value) -> String {
+        Stringify::default().apply(val)
+    }
+
+    pub fn math_consts() -> Value {
+        serde_json::json!{{
+            "MIN_INT": i64::MIN,
+            "MAX_INT": i64::MAX,
+            "MAX_FLOAT": f64::MAX,
+            "MIN_FLOAT": f64::MIN,
+            "INC": f64::NAN,
+            "NOT_A_NUMBER": f64::NAN,
+            "INFINITE": f64::INFINITY,
+            "NEG_INFINITE": f64::NEG_INFINITY,
+            "E": std::f64::consts::E,
+            "FRAC_1_SQRT_2": std::f64::consts::FRAC_1_SQRT_2,
+            "FRAC_2_SQRT_PI": std::f64::consts::FRAC_2_SQRT_PI,
+            "FRAC_1_PI": std::f64::consts::FRAC_1_PI,
+            "FRAC_PI_2": std::f64::consts::FRAC_PI_2,
+            "FRAC_PI_3": std::f64::consts::FRAC_PI_3,
+            "FRAC_PI_4": std::f64::consts::FRAC_PI_4,
+            "FRAC_PI_6": std::f64::consts::FRAC_PI_6,
+            "FRAC_PI_8": std::f64::consts::FRAC_PI_8,
+            "LN_2": std::f64::consts::LN_2,
+            "LN_10": std::f64::consts::LN_10,
+            "LOG2_10": std::f64::consts::LOG2_10,
+            "LOG2_E": std::f64::consts::LOG2_E,
+            "LOG10_2": std::f64::consts::LOG10_2,
+            "LOG10_E": std::f64::consts::LOG10_E,
+            "PI": std::f64::consts::PI,
+            "SQRT_2": std::f64::consts::SQRT_2,
+            "TAU": std::f64::consts::TAU,
+        }}
+    }
+
+    type WrappedFunction = Arc<dyn Fn(Vec<Value>) -> Result<Value, resolver::Error> + Sync + Send>;
+
+    #[derive(Clone)]
+    pub struct ExprWrapper {
+        expr: Expr,
+        config: EvalConfig,
+        expression: String,
+        /// Values and functions given so far, replayed onto the expression
+        /// when `init` rewrites it, see `prepare_expression`.
+        values: Vec<(String, Value)>,
+        functions: Vec<(String, WrappedFunction)>,
+        /// Why `init` could not rewrite the expression, reported by `exec`.
+        failure: Option<String>,
+    }
+
+    impl ExprWrapper {
+        pub fn new<S: AsRef<str>>(expression: S) -> ExprWrapper {
+            ExprWrapper {
+                expr: Expr::new(expression.as_ref()),
+                config: Default::default(),
+                expression: expression.as_ref().to_string(),
+                values: Vec::new(),
+                functions: Vec::new(),
+                failure: None,
+            }
+        }
+
+        pub fn config(mut self, config: EvalConfig) -> ExprWrapper {
+            self.config = config;
+            self
+        }
+
+        #[allow(deprecated)]
+        pub fn init(mut self) -> ExprWrapper {
+            let mut expr = self.expr;
+            let prepared = prepare_expression(&self.expression, &self.config);
+            if prepared.as_deref() != Ok(self.expression.as_str()) {
+                match prepared {
+                    Ok(expression) => expr = Expr::new(expression),
+                    Err(err) => self.failure = Some(err.to_string()),
+                }
+                for (name, value) in &self.values {
+                    expr = expr.value(name, value);
+                }
+                for (name, function) in &self.functions {
+                    let function = Arc::clone(function);
+                    expr = expr.function(name, move |values| function(values));
+                }
+            }
+            self.expr = expr_wrapper(expr, self.config.clone());
+            self
+        }
+
+        pub fn value<T, V>(mut self, name: T, value: V) -> ExprWrapper
+            where T: Into<String>,
+                  V: serde::Serialize
+        {
+            let (name, value) = (name.into(), to_value(value));
+            self.expr = self.expr.value(name.clone(), &value);
+            self.values.push((name, value));
+            self
+        }
+
+        pub fn function<T, F>(mut self, name: T, function: F) -> ExprWrapper
+            where T: Into<String>,
+                  F: 'static + Fn(Vec<Value>) -> Result<Value, resolver::Error> + Sync + Send
+        {
+            let (name, function) = (name.into(), Arc::new(function));
+            let shared = Arc::clone(&function);
+            self.expr = self.expr.function(name.clone(), move |values| shared(values));
+            self.functions.push((name, function));
+            self
+        }
+
+        pub fn exec(&mut self) -> Result<Value, resolver::Error> {
+            if let Some(failure) = &self.failure {
+                return Err(resolver::Error::Custom(failure.clone()));
+            }
+            self.expr.exec()
+        }
+    }
+
+    /// Rewrites the operators of `expression` when `config` evaluates them
+    /// outside of the resolver. Expressions the rewrite cannot parse are an
+    /// error rather than silently falling back to float semantics.
+    pub(crate) fn prepare_expression(expression: &str, config: &EvalConfig) -> Result<String, resolver::Error> {
+        let expression = match config.include_predicates {
+            true => crate::arithmetic::non_finite_literals(expression),
+            false => expression.to_string(),
+        };
+        if config.arithmetic == Arithmetic::Float {
+            return Ok(expression);
+        }
+        crate::arithmetic::rewrite(&expression)
+    }
+
+    /// This function is DEPRECATED see README.md for new usage.
+    #[deprecated]
+    pub fn expr_wrapper(exp: Expr, config: EvalConfig) -> Expr {
+        if !config.any() {
+            return exp;
+        }
+
+        let builtins = builtins(&config);
+        let mut result = exp;
+        for (name, value) in builtins.values {
+            result = result.value(name, value);
+        }
+        for (name, function) in builtins.functions {
+            result = result.function(name, move |values| (function.compiled)(values));
+        }
+        result
+    }
+
+    /// Built-in functions and values selected by an `EvalConfig`, collected
+    /// so they can be attached to an `Expr` or shared between evaluations.
+    #[derive(Default)]
+    pub(crate) struct Builtins {
+        pub(crate) functions: resolver::Functions,
+        pub(crate) values: resolver::Context,
+        stringify: Arc<Stringify>,
+    }
+
+    impl Builtins {
+        fn function<F>(mut self, name: &str, function: F) -> Builtins
+            where F: 'static + Fn(Vec<Value>) -> Result<Value, resolver::Error> + Sync + Send
+        {
+            self.functions.insert(name.to_string(), resolver::Function::new(function));
+            self
+        }
+
+        /// Registers `function`, which turns values into text with the
+        /// `Stringify` policy of the `EvalConfig` passed to `builtins`.
+        fn text_function<F>(mut self, name: &str, function: F) -> Builtins
+            where F: 'static + Fn(Vec<Value>, &Stringify) -> Result<Value, resolver::Error> + Sync + Send
+        {
+            let stringify = Arc::clone(&self.stringify);
+            self.functions.insert(name.to_string(), resolver::Function::new(move |values| {
+                function(values, &stringify)
+            }));
+            self
+        }
+
+        fn value<V: serde::Serialize>(mut self, name: &str, value: V) -> Builtins {
+            self.values.insert(name.to_string(), to_value(value));
+            self
+        }
+    }
+
+    pub(crate) fn builtins(config: &EvalConfig) -> Builtins {
+        let mut result = Builtins {
+            stringify: Arc::new(config.stringify.clone()),
+            ..Default::default()
+        };
+        let sentinels = config.include_predicates;
+
+        if config.include_cast {
+            let checked = config.arithmetic == Arithmetic::Checked;
+            result = result
+                .function("int", move |value| {
+                    if value.is_empty() {
+                        return Ok(to_value(0_i64));
+                    }
+                    if let Some(base) = value.get(1) {
+                        let Value::String(text) = &value[0] else {
+                            return Err(resolver::Error::Custom("int(): can't convert non-string with explicit base".into()));
+                        };
+                        let base = base.as_u64().ok_or(resolver::Error::ExpectedNumber)?;
+                        let parsed = parse_int_radix(text, base)?;
+                        return crate::arithmetic::from_i128(parsed).ok_or_else(|| out_of_range(text));
+                    }
+                    let v = match value.get(0) {
+                        None => to_value(0),
+                        Some(value) => value.to_owned(),
+                    };
+
+                    let num: i64 = match v {
+                        Value::Number(x) => {
+                            if x.is_f64() {
+                                float_to_int(x.as_f64().unwrap_or(0_f64), checked)?
+                            } else if checked {
+                                x.as_i64().ok_or_else(|| out_of_range(&x))?
+                            } else {
+                                x.as_i64().unwrap_or(0)
+                            }
+                        }
+                        Value::Bool(x) => {
+                            if x {
+                                1
+                            } else {
+                                0
+                            }
+                        }
+                        Value::String(x) => atoi(x, checked)?,
+                        _ => 0,
+                    };
+                    Ok(to_value(num))
+                })
+                .function("float", move |value| {
+                    if value.is_empty() {
+                        return Ok(float_value(f64::NAN, sentinels));
+                    }
+                    let v = match value.get(0) {
+                        None => to_value(0_f64),
+                        Some(value) => value.to_owned(),
+                    };
+                    let num: f64 = match v {
+                        Value::Number(x) => x.as_f64().unwrap_or(0_f64),
+                        Value::Bool(x) => {
+                            if x {
+                                1.0
+                            } else {
+                                0.0
+                            }
+                        }
+                        Value::String(x) => match x.parse::<f64>() {
+                            Ok(x) => x,
+                            _ => f64::NAN,
+                        },
+                        _ => f64::NAN,
+                    };
+
+                    Ok(float_value(num, sentinels))
+                })
+                .function("bool", |value| {
+                    if value.is_empty() {
+                        return Ok(to_value(false));
+                    }
+                    let v = match value.get(0) {
+                        None => to_value(false),
+                        Some(value) => value.to_owned(),
+                    };
+
+                    let result: bool = match v {
+                        Value::Number(x) => x.as_f64().unwrap_or(0_f64) != 0.0,
+                        Value::Bool(x) => x,
+                        Value::String(x) => !x.is_empty(),
+                        Value::Array(x) => !x.is_empty(),
+                        Value::Object(x) => !x.is_empty(),
+                        _ => false,
+                    };
+
+                    Ok(to_value(result))
+                })
+                .function("str", |value| {
+                    if value.is_empty() {
+                        return Ok(to_value("".to_string()));
+                    }
+                    let v = match value.get(0) {
+                        None => to_value("".to_string()),
+                        Some(value) => value.to_owned(),
+                    };
+
+                    let result: String = match v {
+                        Value::Number(x) => {
+                            if x.is_f64() {
+                                x.as_f64().unwrap_or(0_f64).to_string()
+                            } else {
+                                x.as_i64().unwrap_or(0_i64).to_string()
+                            }
+                        }
+                        Value::Bool(x) => x.to_string(),
+                        Value::String(x) => x,
+                        Value::Array(x) => serde_json::to_string(&x)
+                            .unwrap_or_else(|_| "null".to_string()),
+                        Value::Object(x) => serde_json::to_string(&x)
+                            .unwrap_or_else(|_| "null".to_string()),
+                        _ => "null".to_string(),
+                    };
+                    Ok(to_value(result))
+                });
+        }
+
+        if config.include_maths {
+            let mut maths = math_consts();
+            for (name, x) in [("INC", f64::NAN), ("NOT_A_NUMBER", f64::NAN), ("INFINITE", f64::INFINITY), ("NEG_INFINITE", f64::NEG_INFINITY)] {
+                maths[name] = float_value(x, sentinels);
+            }
+            result = result
+                .value("maths", maths)
+                .value("NAN", float_value(f64::NAN, sentinels))
+                .value("INFINITY", float_value(f64::INFINITY, sentinels))
+                .value("NEG_INFINITY", float_value(f64::NEG_INFINITY, sentinels))
+                .function("round", |values| {
+                    expect_args(&values, 1)?;
+                    let x = values[0].as_f64().ok_or(resolver::Error::ExpectedNumber)?;
+                    let scale = 10_f64.powi(arg_i64(&values, 1).unwrap_or(0).clamp(-308, 308) as i32);
+                    Ok(to_value((x * scale).round() / scale))
+                });
+        }
+
+        if config.include_predicates {
+            result = result
+                .function("is_nan", |values| {
+                    expect_args(&values, 1)?;
+                    Ok(to_value(as_float(&values[0]).is_some_and(f64::is_nan)))
+                })
+                .function("is_finite", |values| {
+                    expect_args(&values, 1)?;
+                    Ok(to_value(as_float(&values[0]).is_some_and(f64::is_finite)))
+                })
+                .function("is_infinite", |values| {
+                    expect_args(&values, 1)?;
+                    Ok(to_value(as_float(&values[0]).is_some_and(f64::is_infinite)))
+                })
+                .function("is_int", |values| {
+                    expect_args(&values, 1)?;
+                    Ok(to_value(matches!(&values[0], Value::Number(n) if !n.is_f64())))
+                })
+                .function("is_float", |values| {
+                    expect_args(&values, 1)?;
+                    Ok(to_value(matches!(&values[0], Value::Number(n) if n.is_f64())))
+                })
+                .function("is_min_int", |values| {
+                    expect_args(&values, 1)?;
+                    Ok(to_value(values[0].as_i64() == Some(i64::MIN)))
+                })
+                .function("is_max_int", |values| {
+                    expect_args(&values, 1)?;
+                    Ok(to_value(values[0].as_i64() == Some(i64::MAX)))
+                })
+                .function("is_between", |values| {
+                    expect_args(&values, 3)?;
+                    let inclusive = match values.get(3) {
+                        None | Some(Value::Null) => true,
+                        Some(Value::Bool(inclusive)) => *inclusive,
+                        Some(_) => return Err(resolver::Error::ExpectedBoolean(values[3].clone())),
+                    };
+                    let (low, high) = (compare(&values[0], &values[1]), compare(&values[0], &values[2]));
+                    Ok(to_value(match (low, high) {
+                        (Some(low), Some(high)) if inclusive => low.is_ge() && high.is_le(),
+                        (Some(low), Some(high)) => low.is_gt() && high.is_lt(),
+                        _ => false,
+                    }))
+                })
+                .function("approx_eq", |values| {
+                    expect_args(&values, 2)?;
+                    let number = |idx: usize, default: f64| match values.get(idx) {
+                        None | Some(Value::Null) if idx > 1 => Ok(default),
+                        Some(value) => as_float(value).ok_or(resolver::Error::ExpectedNumber),
+                        None => Err(resolver::Error::ExpectedNumber),
+                    };
+                    let (a, b) = (number(0, 0.0)?, number(1, 0.0)?);
+                    let (rel_tol, abs_tol) = (number(2, 1e-9)?, number(3, 0.0)?);
+                    if rel_tol < 0.0 || abs_tol < 0.0 {
+                        return Err(resolver::Error::Custom("approx_eq(): tolerances must be non-negative".into()));
+                    }
+                    if a == b {
+                        return Ok(to_value(true));
+                    }
+                    if a.is_infinite() || b.is_infinite() {
+                        return Ok(to_value(false));
+                    }
+                    let difference = (a - b).abs();
+                    Ok(to_value(difference <= (rel_tol * a.abs().max(b.abs())).max(abs_tol)))
+                });
+        }
+
+        if config.include_decimal {
+            result = result
+                .function("decimal", |values| {
+                    expect_args(&values, 1)?;
+                    let x = crate::arithmetic::to_decimal(&values[0])?;
+                    Ok(crate::arithmetic::from_decimal(&x, crate::arithmetic::is_integral(&values[0], &x)))
+                })
+                .function("round_half_even", |values| {
+                    crate::arithmetic::round(&values, bigdecimal::RoundingMode::HalfEven)
+                })
+                .function("round_half_up", |values| {
+                    crate::arithmetic::round(&values, bigdecimal::RoundingMode::HalfUp)
+                })
+                .function("dec_cmp", |values| crate::arithmetic::decimal(crate::arithmetic::Operator::Eq, &values));
+            for (name, operator) in [
+                ("dec_add", crate::arithmetic::Operator::Add),
+                ("dec_sub", crate::arithmetic::Operator::Sub),
+                ("dec_mul", crate::arithmetic::Operator::Mul),
+                ("dec_div", crate::arithmetic::Operator::Div),
+            ] {
+                result = result.function(name, move |values| crate::arithmetic::decimal(operator, &values));
+            }
+        }
+
+        if config.include_bigint {
+            result = result
+                .function("bigint", |values| {
+                    expect_args(&values, 1)?;
+                    Ok(to_value(crate::arithmetic::to_bigint(&values[0])?.to_string()))
+                })
+                .function("bigint_cmp", |values| crate::arithmetic::bigint(crate::arithmetic::Operator::Eq, &values));
+            for (name, operator) in [
+                ("bigint_add", crate::arithmetic::Operator::Add),
+                ("bigint_sub", crate::arithmetic::Operator::Sub),
+                ("bigint_mul", crate::arithmetic::Operator::Mul),
+                ("bigint_div", crate::arithmetic::Operator::Div),
+                ("bigint_mod", crate::arithmetic::Operator::Rem),
+                ("bigint_pow", crate::arithmetic::Operator::Pow),
+            ] {
+                result = result.function(name, move |values| crate::arithmetic::bigint(operator, &values));
+            }
+        }
+
+        if config.include_bits {
+            for (name, prefix) in [("hex", "0x"), ("oct", "0o"), ("bin", "0b")] {
+                result = result.function(name, move |values| {
+                    let n = int_arg(&values, 0, name)?;
+                    let sign = if n < 0 { "-" } else { "" };
+                    let digits = match prefix {
+                        "0x" => format!("{:x}", n.unsigned_abs()),
+                        "0o" => format!("{:o}", n.unsigned_abs()),
+                        _ => format!("{:b}", n.unsigned_abs()),
+                    };
+                    Ok(to_value(format!("{sign}{prefix}{digits}")))
+                });
+            }
+            for (name, fold) in [
+                ("bit_and", (|a, b| a & b) as fn(i128, i128) -> i128),
+                ("bit_or", |a, b| a | b),
+                ("bit_xor", |a, b| a ^ b),
+            ] {
+                result = result.function(name, move |values| {
+                    expect_args(&values, 2)?;
+                    let mut acc = int_arg(&values, 0, name)?;
+                    for idx in 1..values.len() {
+                        acc = fold(acc, int_arg(&values, idx, name)?);
+                    }
+                    int_result(acc, name)
+                });
+            }
+            result = result
+                .function("bit_not", |values| int_result(!int_arg(&values, 0, "bit_not")?, "bit_not"))
+                .function("shl", |values| {
+                    let (n, shift) = (int_arg(&values, 0, "shl")?, shift_arg(&values, "shl")?);
+                    let shifted = n << shift;
+                    if shifted >> shift != n {
+                        return Err(resolver::Error::Custom(format!("shl(): {n} << {shift} does not fit in 64 bits")));
+                    }
+                    int_result(shifted, "shl")
+                })
+                .function("shr", |values| {
+                    let (n, shift) = (int_arg(&values, 0, "shr")?, shift_arg(&values, "shr")?);
+                    int_result(n >> shift, "shr")
+                })
+                .function("bit_test", |values| {
+                    let (n, bit) = (int_arg(&values, 0, "bit_test")?, int_arg(&values, 1, "bit_test")?);
+                    if bit < 0 {
+                        return Err(resolver::Error::Custom("bit_test(): negative bit index".into()));
+                    }
+                    Ok(to_value((n >> bit.min(127)) & 1 == 1))
+                })
+                .function("popcount", |values| {
+                    Ok(to_value(int_arg(&values, 0, "popcount")?.unsigned_abs().count_ones()))
+                });
+        }
+
+        if config.include_regex {
+            result = result.text_function("is_match", |value, stringify| {
+                if value.len() < 2 {
+                    return Ok(to_value(false));
+                }
+
+                let v = value.get(0).unwrap();
+                let pattern = value.get(1).unwrap().as_str().unwrap();
+
+                let value: String = stringify.apply(v);
+
+                let prog = Regex::new(pattern).unwrap();
+                let is_match = prog.is_match(&value);
+                Ok(to_value(is_match))
+            }).text_function("extract", |value, stringify| {
+                if value.len() < 2 {
+                    return Ok(to_value(false));
+                }
+
+                let v = value
+                    .get(0).expect("missing first positional argument (string)");
+                let pattern = value
+                    .get(1).expect("missing second positional argument (pattern)")
+                    .as_str().expect("second positional arguments needs to be a string");
+
+                let value: String = stringify.apply(v);
+                let prog = Regex::new(pattern).unwrap();
+                match prog.find(&value) {
+                    None => Ok(to_value("".to_string())),
+                    Some(m) => {
+                        let (start, end) = (m.start(), m.end());
+                        Ok(to_value(value.substring(start..end)))
+                    }
+                }
+            });
+        }
+
+        if config.include_datetime {
+            result = result
+                .function("get_day", |values| {
+                    let current_time = eval_tz_parse_args(values, 1);
+                    Ok(to_value(current_time.date_naive().day()))
+                })
+                .function("get_month", |values| {
+                    let current_time = eval_tz_parse_args(values, 1);
+                    Ok(to_value(current_time.date_naive().month()))
+                })
+                .function("get_year", |values| {
+                    let current_time = eval_tz_parse_args(values, 1);
+                    Ok(to_value(current_time.date_naive().year()))
+                })
+                .function("get_weekday", |values| {
+                    let current_time = eval_tz_parse_args(values, 1);
+                    Ok(to_value(
+                        current_time.date_naive().weekday().number_from_monday(),
+                    ))
+                })
+                .function("is_weekday", |values| {
+                    let current_time = eval_tz_parse_args(values, 1);
+                    let weekday = current_time.date_naive().weekday().number_from_monday();
+                    Ok(to_value(weekday < 6))
+                })
+                .function("is_weekend", |values| {
+                    let current_time = eval_tz_parse_args(values, 1);
+                    let weekday = current_time.date_naive().weekday();
+                    let weekends = [chrono::Weekday::Sat, chrono::Weekday::Sun];
+                    Ok(to_value(weekends.contains(&weekday)))
+                })
+                .function("get_time", |extract| {
+                    if extract.len() < 2 {
+                        let t = now("_".to_owned());
+                        return Ok(to_value(t.hour()));
+                    }
+
+                    let v: String = match extract.get(1).unwrap() {
+                        Value::Number(x) => {
+                            if x.is_f64() {
+                                x.as_f64().unwrap().to_string()
+                            } else if x.is_i64() {
+                                x.as_i64().unwrap().to_string()
+                            } else if x.is_u64() {
+                                x.as_u64().unwrap().to_string()
+                            } else {
+                                x.to_string()
+                            }
+                        }
+                        Value::Bool(x) => x.to_string(),
+                        Value::String(x) => x.to_string(),
+                        Value::Array(x) => serde_json::to_string(x).unwrap(),
+                        Value::Object(x) => serde_json::to_string(x).unwrap(),
+                        _ => String::from("null"),
+                    };
+
+                    let dt = eval_tz_parse_args(extract, 2);
+                    let current_time = dt.time();
+
+                    let result = match v.as_str() {
+                        "h" | "hour" | "hours" => current_time.hour(),
+                        "m" | "minute" | "minutes" => current_time.minute(),
+                        "s" | "second" | "seconds" => current_time.second(),
+                        _ => current_time.hour(),
+                    };
+                    Ok(to_value(result))
+                });
+        }
+
+        if config.include_strings {
+            result = result
+                .text_function("upper", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    Ok(to_value(s.to_uppercase()))
+                })
+                .text_function("lower", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    Ok(to_value(s.to_lowercase()))
+                })
+                .text_function("title", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    Ok(to_value(str_title(&s)))
+                })
+                .text_function("capitalize", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    let mut chars = s.chars();
+                    let result: String = match chars.next() {
+                        None => String::new(),
+                        Some(first) => first.to_uppercase()
+                            .chain(chars.flat_map(char::to_lowercase))
+                            .collect(),
+                    };
+                    Ok(to_value(result))
+                })
+                .text_function("strip", |values, stringify| str_strip(values, true, true, stringify))
+                .text_function("lstrip", |values, stringify| str_strip(values, true, false, stringify))
+                .text_function("rstrip", |values, stringify| str_strip(values, false, true, stringify))
+                .text_function("startswith", |values, stringify| {
+                    str_affix(values, |s, affix| s.starts_with(affix), stringify)
+                })
+                .text_function("endswith", |values, stringify| {
+                    str_affix(values, |s, affix| s.ends_with(affix), stringify)
+                })
+                .text_function("find", |values, stringify| {
+                    expect_args(&values, 2)?;
+                    let (s, offset) = str_window(&values, 2, stringify);
+                    let sub = arg_string(&values, 1, stringify).unwrap_or_default();
+                    let found = s.find(&sub)
+                        .map(|idx| (offset + s[..idx].chars().count()) as i64);
+                    Ok(to_value(found.unwrap_or(-1)))
+                })
+                .text_function("rfind", |values, stringify| {
+                    expect_args(&values, 2)?;
+                    let (s, offset) = str_window(&values, 2, stringify);
+                    let sub = arg_string(&values, 1, stringify).unwrap_or_default();
+                    let found = s.rfind(&sub)
+                        .map(|idx| (offset + s[..idx].chars().count()) as i64);
+                    Ok(to_value(found.unwrap_or(-1)))
+                })
+                .text_function("count", |values, stringify| {
+                    expect_args(&values, 2)?;
+                    let (s, _) = str_window(&values, 2, stringify);
+                    let sub = arg_string(&values, 1, stringify).unwrap_or_default();
+                    let count = if sub.is_empty() {
+                        s.chars().count() + 1
+                    } else {
+                        s.matches(&sub).count()
+                    };
+                    Ok(to_value(count))
+                })
+                .text_function("replace", |values, stringify| {
+                    expect_args(&values, 3)?;
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    let old = arg_string(&values, 1, stringify).unwrap_or_default();
+                    let new = arg_string(&values, 2, stringify).unwrap_or_default();
+                    let result = match arg_i64(&values, 3) {
+                        Some(count) if count >= 0 => s.replacen(&old, &new, count as usize),
+                        _ => s.replace(&old, &new),
+                    };
+                    Ok(to_value(result))
+                })
+                .text_function("split", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    let maxsplit = arg_i64(&values, 2)
+                        .filter(|n| *n >= 0)
+                        .map(|n| n as usize);
+                    let parts = match arg_string(&values, 1, stringify) {
+                        None => str_split_whitespace(&s, maxsplit),
+                        Some(sep) if sep.is_empty() => {
+                            return Err(resolver::Error::Custom("split(): empty separator".into()));
+                        }
+                        Some(sep) => match maxsplit {
+                            None => s.split(sep.as_str()).map(String::from).collect(),
+                            Some(n) => s.splitn(n + 1, sep.as_str()).map(String::from).collect(),
+                        },
+                    };
+                    Ok(to_value(parts))
+                })
+                .text_function("join", |values, stringify| {
+                    expect_args(&values, 2)?;
+                    // Python's `sep.join(arr)` reads as `join(sep, arr)`, but the
+                    // resolver built-in it replaces takes `join(arr, sep)`; accept both.
+                    let (sep, items) = match (&values[0], &values[1]) {
+                        (Value::Array(items), sep) => (stringify.apply(sep), items),
+                        (sep, Value::Array(items)) => (stringify.apply(sep), items),
+                        (a, b) => {
+                            return Err(resolver::Error::Custom(format!(
+                                "join() requires a separator and an array, got {a:?}, {b:?}"
+                            )));
+                        }
+                    };
+                    let parts: Vec<String> = items.iter().map(|item| stringify.apply(item)).collect();
+                    Ok(to_value(parts.join(&sep)))
+                })
+                .text_function("zfill", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    let width = arg_i64(&values, 1).unwrap_or(0).max(0) as usize;
+                    let len = s.chars().count();
+                    if len >= width {
+                        return Ok(to_value(s));
+                    }
+                    let zeros = "0".repeat(width - len);
+                    let result = match s.strip_prefix(['+', '-']) {
+                        Some(rest) => format!("{}{}{}", &s[..1], zeros, rest),
+                        None => format!("{zeros}{s}"),
+                    };
+                    Ok(to_value(result))
+                })
+                .text_function("center", |values, stringify| str_pad(values, "center", Align::Center, stringify))
+                .text_function("ljust", |values, stringify| str_pad(values, "ljust", Align::Left, stringify))
+                .text_function("rjust", |values, stringify| str_pad(values, "rjust", Align::Right, stringify))
+                .text_function("isdigit", |values, stringify| str_all(values, is_digit, stringify))
+                .text_function("isalpha", |values, stringify| str_all(values, char::is_alphabetic, stringify))
+                .text_function("isalnum", |values, stringify| str_all(values, char::is_alphanumeric, stringify))
+                .text_function("isspace", |values, stringify| str_all(values, char::is_whitespace, stringify))
+                .text_function("format", |values, stringify| {
+                    expect_args(&values, 1)?;
+                    let template = stringify.apply(&values[0]);
+                    Ok(to_value(crate::formatting::format_braces(&template, &values[1..], stringify)?))
+                })
+                .text_function("sprintf", |values, stringify| {
+                    expect_args(&values, 1)?;
+                    let template = stringify.apply(&values[0]);
+                    Ok(to_value(crate::formatting::format_percent(&template, &values[1..], stringify)?))
+                })
+                .function("slice", |values| {
+                    expect_args(&values, 1)?;
+                    let step = arg_i64(&values, 3).unwrap_or(1);
+                    if step == 0 {
+                        return Err(resolver::Error::Custom("slice(): step cannot be zero".into()));
+                    }
+                    let (start, end) = (arg_i64(&values, 1), arg_i64(&values, 2));
+                    match &values[0] {
+                        Value::String(x) => {
+                            let chars: Vec<char> = x.chars().collect();
+                            let result: String = py_slice(chars.len(), start, end, step)
+                                .map(|idx| chars[idx])
+                                .collect();
+                            Ok(to_value(result))
+                        }
+                        Value::Array(x) => {
+                            let result: Vec<Value> = py_slice(x.len(), start, end, step)
+                                .map(|idx| x[idx].clone())
+                                .collect();
+                            Ok(to_value(result))
+                        }
+                        Value::Null => Ok(Value::Null),
+                        x => Err(resolver::Error::Custom(format!(
+                            "slice() requires a string or an array, got {x:?}"
+                        ))),
+                    }
+                })
+                .function("at", |values| {
+                    expect_args(&values, 2)?;
+                    let idx = int_arg(&values, 1, "at")?;
+                    let resolve = |len: usize| -> Option<usize> {
+                        let idx = if idx < 0 { len as i128 + idx } else { idx };
+                        usize::try_from(idx).ok().filter(|idx| *idx < len)
+                    };
+                    match &values[0] {
+                        Value::String(x) => {
+                            let found = resolve(x.chars().count())
+                                .and_then(|idx| x.chars().nth(idx))
+                                .map(|c| to_value(c.to_string()));
+                            Ok(found.unwrap_or(Value::Null))
+                        }
+                        Value::Array(x) => {
+                            let found = resolve(x.len()).map(|idx| x[idx].clone());
+                            Ok(found.unwrap_or(Value::Null))
+                        }
+                        Value::Null => Ok(Value::Null),
+                        x => Err(resolver::Error::Custom(format!(
+                            "at() requires a string or an array, got {x:?}"
+                        ))),
+                    }
+                });
+        }
+
+        if config.include_inflection {
+            result = result
+                .text_function("camelize", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    let uppercase_first = match values.get(1) {
+                        Some(Value::Bool(x)) => *x,
+                        _ => true,
+                    };
+                    Ok(to_value(camelize(&s, uppercase_first)))
+                })
+                .text_function("underscore", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    Ok(to_value(inflection::underscore(s)))
+                })
+                .text_function("snake_case", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    Ok(to_value(inflection::underscore(s)))
+                })
+                .text_function("dasherize", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    Ok(to_value(inflection::dasherize(s)))
+                })
+                .text_function("kebab_case", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    Ok(to_value(inflection::dasherize(inflection::underscore(s))))
+                })
+                .text_function("titleize", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    Ok(to_value(titleize(&s)))
+                })
+                .text_function("humanize", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    Ok(to_value(humanize(&s)))
+                })
+                .text_function("pluralize", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    Ok(to_value(inflection::pluralize(s)))
+                })
+                .text_function("singularize", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    Ok(to_value(inflection::singularize(s)))
+                })
+                .function("ordinalize", |values| {
+                    let n = match values.first() {
+                        Some(Value::String(x)) => x.trim().parse::<i64>().ok(),
+                        _ => arg_i64(&values, 0),
+                    };
+                    match n {
+                        Some(n) => Ok(to_value(inflection::ordinalize_i64(n))),
+                        None => Err(resolver::Error::ExpectedNumber),
+                    }
+                })
+                .text_function("parameterize", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    let sep = arg_string(&values, 1, stringify).unwrap_or_else(|| "-".into());
+                    Ok(to_value(inflection::parameterize_with_sep(s, sep)))
+                });
+        }
+
+        if config.include_unicode {
+            result = result
+                .text_function("normalize", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    let form = arg_string(&values, 1, stringify).unwrap_or_else(|| "NFC".into());
+                    let result: String = match form.to_uppercase().as_str() {
+                        "NFC" => s.nfc().collect(),
+                        "NFD" => s.nfd().collect(),
+                        "NFKC" => s.nfkc().collect(),
+                        "NFKD" => s.nfkd().collect(),
+                        _ => {
+                            return Err(resolver::Error::Custom(format!(
+                                "normalize(): invalid normalization form '{form}'"
+                            )));
+                        }
+                    };
+                    Ok(to_value(result))
+                })
+                .text_function("char_len", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    Ok(to_value(s.chars().count()))
+                })
+                .text_function("grapheme_len", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    Ok(to_value(s.graphemes(true).count()))
+                })
+                .text_function("display_width", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    Ok(to_value(s.width()))
+                })
+                .text_function("remove_accents", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    let result: String = s.nfd()
+                        .filter(|c| !is_combining_mark(*c))
+                        .nfc()
+                        .collect();
+                    Ok(to_value(result))
+                })
+                .text_function("casefold", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    Ok(to_value(casefold(&s)))
+                })
+                .text_function("truncate", |values, stringify| {
+                    expect_args(&values, 2)?;
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    let max_len = int_arg(&values, 1, "truncate")?;
+                    if max_len < 0 {
+                        return Err(resolver::Error::Custom("truncate(): length must be non-negative".into()));
+                    }
+                    let max_len = usize::try_from(max_len).unwrap_or(usize::MAX);
+                    let ellipsis = arg_string(&values, 2, stringify).unwrap_or_else(|| "…".into());
+                    Ok(to_value(truncate_graphemes(&s, max_len, &ellipsis)))
+                });
+        }
+
+        if config.include_similarity {
+            result = result
+                .text_function("levenshtein", |values, stringify| {
+                    expect_args(&values, 2)?;
+                    let (a, b) = (stringify.apply(&values[0]), stringify.apply(&values[1]));
+                    Ok(to_value(strsim::levenshtein(&a, &b)))
+                })
+                .text_function("damerau_levenshtein", |values, stringify| {
+                    expect_args(&values, 2)?;
+                    let (a, b) = (stringify.apply(&values[0]), stringify.apply(&values[1]));
+                    Ok(to_value(strsim::damerau_levenshtein(&a, &b)))
+                })
+                .text_function("jaro_winkler", |values, stringify| {
+                    expect_args(&values, 2)?;
+                    let (a, b) = (stringify.apply(&values[0]), stringify.apply(&values[1]));
+                    Ok(to_value(strsim::jaro_winkler(&a, &b)))
+                })
+                .text_function("similarity", |values, stringify| {
+                    expect_args(&values, 2)?;
+                    let (a, b) = (stringify.apply(&values[0]), stringify.apply(&values[1]));
+                    Ok(to_value(strsim::normalized_damerau_levenshtein(&a, &b)))
+                })
+                .text_function("soundex", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    Ok(to_value(soundex(&s)))
+                })
+                .text_function("metaphone", |values, stringify| {
+                    let s = arg_string(&values, 0, stringify).unwrap_or_default();
+                    Ok(to_value(metaphone(&s)))
+                })
+                .text_function("fuzzy_match", |values, stringify| {
+                    expect_args(&values, 2)?;
+                    let s = stringify.apply(&values[0]);
+                    let candidates = match &values[1] {
+                        Value::Array(x) => x,
+                        x => {
+                            return Err(resolver::Error::Custom(format!(
+                                "fuzzy_match() requires an array of candidates, got {x:?}"
+                            )));
+                        }
+                    };
+                    let threshold = values.get(2).and_then(Value::as_f64).unwrap_or(0.6);
+
+                    let mut best: Option<(f64, &Value)> = None;
+                    for candidate in candidates {
+                        let score = strsim::normalized_damerau_levenshtein(&s, &stringify.apply(candidate));
+                        if score >= threshold && best.is_none_or(|(best, _)| score > best) {
+                            best = Some((score, candidate));
+                        }
+                    }
+                    Ok(best.map(|(_, candidate)| candidate.clone()).unwrap_or(Value::Null))
+                });
+        }
+
+        if config.include_locale {
+            result = result
+                .function("format_number", |values| {
+                    expect_args(&values, 1)?;
+                    Ok(to_value(crate::locale::format_number(&values)?))
+                })
+                .function("format_currency", |values| {
+                    expect_args(&values, 2)?;
+                    Ok(to_value(crate::locale::format_currency(&values)?))
+                })
+                .function("format_percent", |values| {
+                    expect_args(&values, 1)?;
+                    Ok(to_value(crate::locale::format_percent(&values)?))
+                })
+                .function("format_date", |values| {
+                    expect_args(&values, 1)?;
+                    Ok(to_value(crate::locale::format_date(&values)?))
+                })
+                .function("format_relative", |values| {
+                    expect_args(&values, 1)?;
+                    Ok(to_value(crate::locale::format_relative(&values)?))
+                });
+        }
+
+        if config.arithmetic != Arithmetic::Float {
+            result = result.function(crate::arithmetic::INDEX, |values| crate::arithmetic::index(&values));
+            for (name, operator) in crate::arithmetic::OPERATORS {
+                result = result.function(name, move |values| crate::arithmetic::checked(operator, &values));
+            }
+        }
+
+        result
+
+        // TODO: includes(arr)
+        // TODO: min(arr), max(arr), abs(n), pow(n, p), sum(arr), reverse(arr), sort(arr), unique(arr)
+    }
+
+    fn eval_tz_parse_args(
+        arguments: Vec<Value>,
+        min_args: usize,
+    ) -> chrono::DateTime<chrono_tz::Tz> {
+        let default_tz = "_".to_owned();
+        if arguments.is_empty() || arguments.len() < min_args {
+            log::warn!("No arguments");
+            return now(default_tz);
+        }
+
+        let v: Option<String> = match arguments.get(0).unwrap() {
+            Value::String(x) => Some(x.to_string()),
+            _ => None,
+        };
+
+        match v {
+            None => {
+                log::warn!("Invalid Timezone");
+                now(default_tz)
+            }
+            Some(timezone) => now(timezone)
+        }
+    }
+
+    fn now(tz: String) -> chrono::DateTime<chrono_tz::Tz> {
+        chrono::offset::Utc::now()
+            .with_timezone(&str_to_tz(tz))
+    }
+
+    fn str_to_tz(timezone: String) -> chrono_tz::Tz {
+        match timezone.parse() {
+            Ok(tz) => tz,
+            Err(_err) => {
+                log::warn!("Defaulted to UTC timezone");
+                chrono_tz::UTC
+            }
+        }
+    }
+
+    fn expect_args(values: &[Value], min_args: usize) -> Result<(), resolver::Error> {
+        if values.len() < min_args {
+            return Err(resolver::Error::ArgumentsLess(min_args));
+        }
+        Ok(())
+    }
+
+    fn arg_string(values: &[Value], idx: usize, stringify: &Stringify) -> Option<String> {
+        match values.get(idx) {
+            None | Some(Value::Null) => None,
+            Some(value) => Some(stringify.apply(value)),
+        }
+    }
+
+    /// Reads argument `idx` of the integer built-in `name`.
+    fn int_arg(values: &[Value], idx: usize, name: &str) -> Result<i128, resolver::Error> {
+        let value = values.get(idx).ok_or(resolver::Error::ArgumentsLess(idx + 1))?;
+        crate::arithmetic::as_i128(value).ok_or_else(|| {
+            resolver::Error::Custom(format!("{name}(): expected an integer, got {}", value_to_string(value)))
+        })
+    }
+
+    fn int_result(n: i128, name: &str) -> Result<Value, resolver::Error> {
+        crate::arithmetic::from_i128(n)
+            .ok_or_else(|| resolver::Error::Custom(format!("{name}(): {n} does not fit in 64 bits")))
+    }
+
+    /// Reads a number, or a string holding one, as a float. Null is not a
+    /// number; NaN and the infinities arrive as `"NaN"`, `"inf"` and `"-inf"`.
+    fn as_float(value: &Value) -> Option<f64> {
+        match value {
+            Value::Number(n) => n.as_f64(),
+            Value::String(s) => s.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Writes a float, as the string `as_float` reads back when it is NaN or
+    /// infinite and `sentinels` is set, and as null otherwise.
+    fn float_value(x: f64, sentinels: bool) -> Value {
+        match x {
+            _ if x.is_finite() || !sentinels => to_value(x),
+            _ if x.is_nan() => to_value("NaN"),
+            _ if x > 0.0 => to_value("inf"),
+            _ => to_value("-inf"),
+        }
+    }
+
+    /// Orders two numbers, exactly when both are integers, or two strings.
+    fn compare(a: &Value, b: &Value) -> Option<std::cmp::Ordering> {
+        match (a, b) {
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            (Value::Number(x), Value::Number(y)) => {
+                match (crate::arithmetic::as_i128(a), crate::arithmetic::as_i128(b)) {
+                    (Some(a), Some(b)) => Some(a.cmp(&b)),
+                    _ => x.as_f64()?.partial_cmp(&y.as_f64()?),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn shift_arg(values: &[Value], name: &str) -> Result<u32, resolver::Error> {
+        match int_arg(values, 1, name)? {
+            shift @ 0..=64 => Ok(shift as u32),
+            shift => Err(resolver::Error::Custom(format!("{name}(): shift count {shift} is not between 0 and 64"))),
+        }
+    }
+
+    fn arg_i64(values: &[Value], idx: usize) -> Option<i64> {
+        match values.get(idx) {
+            Some(Value::Number(x)) => x.as_i64().or_else(|| x.as_f64().map(|f| f as i64)),
+            _ => None,
+        }
+    }
+
+    /// Resolves Python-style `start`/`end` indices (negative values count from
+    /// the end) against a sequence of `len` items, clamping to its bounds.
+    fn py_bounds(len: usize, start: Option<i64>, end: Option<i64>) -> (usize, usize) {
+        let resolve = |idx: i64| -> usize {
+            if idx < 0 {
+                (len as i64 + idx).max(0) as usize
+            } else {
+                (idx as usize).min(len)
+            }
+        };
+        let start = start.map(resolve).unwrap_or(0);
+        let end = end.map(resolve).unwrap_or(len);
+        (start, end.max(start))
+    }
+
+    /// Yields the indices selected by the Python slice `[start:end:step]` over
+    /// a sequence of `len` items. `step` must not be zero.
+    fn py_slice(
+        len: usize,
+        start: Option<i64>,
+        end: Option<i64>,
+        step: i64,
+    ) -> impl Iterator<Item = usize> {
+        let len = len as i64;
+        let (lower, upper) = if step < 0 { (-1, len - 1) } else { (0, len) };
+        let resolve = |idx: i64| -> i64 {
+            let idx = if idx < 0 { idx + len } else { idx };
+            idx.clamp(lower, upper)
+        };
+        let (default_start, default_end) = if step < 0 { (upper, lower) } else { (lower, upper) };
+        let mut idx = start.map(resolve).unwrap_or(default_start);
+        let end = end.map(resolve).unwrap_or(default_end);
+
+        std::iter::from_fn(move || {
+            let in_range = if step < 0 { idx > end } else { idx < end };
+            if !in_range {
+                return None;
+            }
+            let current = idx as usize;
+            // A step past the end of the sequence can't overflow back into it.
+            idx = idx.checked_add(step).unwrap_or(end);
+            Some(current)
+        })
+    }
+
+    /// Byte offset of the `idx`-th char of `s`, or `s.len()` when out of range.
+    fn char_to_byte(s: &str, idx: usize) -> usize {
+        s.char_indices().nth(idx).map(|(pos, _)| pos).unwrap_or(s.len())
+    }
+
+    /// Returns the part of the first argument selected by the optional
+    /// `start`/`end` arguments found at `bounds_idx`, and the char offset it begins at.
+    fn str_window(values: &[Value], bounds_idx: usize, stringify: &Stringify) -> (String, usize) {
+        let s = arg_string(values, 0, stringify).unwrap_or_default();
+        let (start, end) = py_bounds(
+            s.chars().count(),
+            arg_i64(values, bounds_idx),
+            arg_i64(values, bounds_idx + 1),
+        );
+        let window = s[char_to_byte(&s, start)..char_to_byte(&s, end)].to_string();
+        (window, start)
+    }
+
+    fn str_title(s: &str) -> String {
+        let mut result = String::with_capacity(s.len());
+        let mut prev_cased = false;
+        for c in s.chars() {
+            if prev_cased {
+                result.extend(c.to_lowercase());
+            } else {
+                result.extend(c.to_uppercase());
+            }
+            prev_cased = c.is_alphabetic();
+        }
+        result
+    }
+
+    fn str_strip(values: Vec<Value>, left: bool, right: bool, stringify: &Stringify) -> Result<Value, resolver::Error> {
+        let s = arg_string(&values, 0, stringify).unwrap_or_default();
+        let chars: Option<Vec<char>> = arg_string(&values, 1, stringify).map(|x| x.chars().collect());
+        let matches = |c: char| match &chars {
+            None => c.is_whitespace(),
+            Some(chars) => chars.contains(&c),
+        };
+        let mut result = s.as_str();
+        if left {
+            result = result.trim_start_matches(matches);
+        }
+        if right {
+            result = result.trim_end_matches(matches);
+        }
+        Ok(to_value(result))
+    }
+
+    fn str_affix<F>(values: Vec<Value>, test: F, stringify: &Stringify) -> Result<Value, resolver::Error>
+        where F: Fn(&str, &str) -> bool
+    {
+        expect_args(&values, 2)?;
+        let (s, _) = str_window(&values, 2, stringify);
+        let result = match &values[1] {
+            Value::Array(affixes) => affixes
+                .iter()
+                .any(|affix| test(&s, &stringify.apply(affix))),
+            affix => test(&s, &stringify.apply(affix)),
+        };
+        Ok(to_value(result))
+    }
+
+    fn str_split_whitespace(s: &str, maxsplit: Option<usize>) -> Vec<String> {
+        let mut parts = Vec::new();
+        let mut rest = s.trim_start();
+        while !rest.is_empty() {
+            if maxsplit.is_some_and(|n| parts.len() >= n) {
+                parts.push(rest.to_string());
+                break;
+            }
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            parts.push(rest[..end].to_string());
+            rest = rest[end..].trim_start();
+        }
+        parts
+    }
+
+    enum Align {
+        Left,
+        Right,
+        Center,
+    }
+
+    fn str_pad(values: Vec<Value>, name: &str, align: Align, stringify: &Stringify) -> Result<Value, resolver::Error> {
+        let s = arg_string(&values, 0, stringify).unwrap_or_default();
+        let width = arg_i64(&values, 1).unwrap_or(0).max(0) as usize;
+        let fill = match arg_string(&values, 2, stringify) {
+            None => ' ',
+            Some(fill) => {
+                let mut chars = fill.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => c,
+                    _ => {
+                        return Err(resolver::Error::Custom(format!(
+                            "{name}(): the fill character must be exactly one character long"
+                        )));
+                    }
+                }
+            }
+        };
+
+        let len = s.chars().count();
+        if len >= width {
+            return Ok(to_value(s));
+        }
+        let pad = width - len;
+        let left = match align {
+            Align::Left => 0,
+            Align::Right => pad,
+            // Same rounding as CPython: odd padding favours the left only when
+            // the requested width is odd too.
+            Align::Center => pad / 2 + (pad & width & 1),
+        };
+        let fill_str = |n: usize| std::iter::repeat_n(fill, n).collect::<String>();
+        Ok(to_value(format!("{}{}{}", fill_str(left), s, fill_str(pad - left))))
+    }
+
+    fn str_all(values: Vec<Value>, test: fn(char) -> bool, stringify: &Stringify) -> Result<Value, resolver::Error> {
+        let s = arg_string(&values, 0, stringify).unwrap_or_default();
+        Ok(to_value(!s.is_empty() && s.chars().all(test)))
+    }
+
+    /// Python's `str.isdigit`: decimal digits (`Nd`) plus the characters whose
+    /// numeric type is Digit, e.g. superscripts and circled digits. Fractions
+    /// such as `½` and numerals such as `Ⅻ` are numeric but not digits.
+    fn is_digit(c: char) -> bool {
+        const DIGITS: [(char, char); 20] = [
+            ('\u{B2}', '\u{B3}'), ('\u{B9}', '\u{B9}'), ('\u{1369}', '\u{1371}'),
+            ('\u{19DA}', '\u{19DA}'), ('\u{2070}', '\u{2070}'), ('\u{2074}', '\u{2079}'),
+            ('\u{2080}', '\u{2089}'), ('\u{2460}', '\u{2468}'), ('\u{2474}', '\u{247C}'),
+            ('\u{2488}', '\u{2490}'), ('\u{24EA}', '\u{24EA}'), ('\u{24F5}', '\u{24FD}'),
+            ('\u{24FF}', '\u{24FF}'), ('\u{2776}', '\u{277E}'), ('\u{2780}', '\u{2788}'),
+            ('\u{278A}', '\u{2792}'), ('\u{10A40}', '\u{10A43}'), ('\u{10E60}', '\u{10E68}'),
+            ('\u{11052}', '\u{1105A}'), ('\u{1F100}', '\u{1F10A}'),
+        ];
+        c.is_ascii_digit()
+            || get_general_category(c) == GeneralCategory::DecimalNumber
+            || DIGITS.iter().any(|&(low, high)| (low..=high).contains(&c))
+    }
+
+    // `camelize`, `humanize` and `titleize` are implemented here rather than
+    // delegated to `inflection_rs`, whose versions slice strings by byte
+    // offsets and panic on non-ASCII input or repeated underscores.
+
+    fn camelize(s: &str, uppercase_first: bool) -> String {
+        let mut result = String::with_capacity(s.len());
+        let mut upper_next = uppercase_first;
+        for (pos, c) in s.chars().enumerate() {
+            if c == '_' {
+                upper_next = true;
+            } else if upper_next {
+                result.extend(c.to_uppercase());
+                upper_next = false;
+            } else if pos == 0 {
+                result.extend(c.to_lowercase());
+            } else {
+                result.push(c);
+            }
+        }
+        result
+    }
+
+    fn humanize(s: &str) -> String {
+        let s = s.strip_suffix("_id").unwrap_or(s).replace('_', " ").to_lowercase();
+        let mut chars = s.trim_start().chars();
+        match chars.next() {
+            None => String::new(),
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+        }
+    }
+
+    fn titleize(s: &str) -> String {
+        let mut result = String::with_capacity(s.len());
+        let mut word_start = true;
+        for c in humanize(&inflection::underscore(s)).chars() {
+            if word_start {
+                result.extend(c.to_uppercase());
+            } else {
+                result.push(c);
+            }
+            word_start = c.is_whitespace();
+        }
+        result
+    }
+
+    /// Python's `str.casefold`: the full case folding of CaseFolding.txt
+    /// (statuses C and F). Characters whose folding differs from their
+    /// lowercase mapping are looked up in `CASE_FOLDING`, the rest are lowercased.
+    fn casefold(s: &str) -> String {
+        let mut result = String::with_capacity(s.len());
+        for c in s.chars() {
+            match CASE_FOLDING.binary_search_by_key(&c, |(from, _)| *from) {
+                Ok(idx) => result.push_str(CASE_FOLDING[idx].1),
+                Err(_) => result.extend(c.to_lowercase()),
+            }
+        }
+        result
+    }
+
+    /// Generated from Unicode 14.0.0 CaseFolding.txt: every code point whose
+    /// full case folding is not its lowercase mapping, sorted by code point.
+    const CASE_FOLDING: [(char, &str); 297] = [
+        ('\u{B5}', "\u{3BC}"), ('\u{DF}', "\u{73}\u{73}"), ('\u{149}', "\u{2BC}\u{6E}"),
+        ('\u{17F}', "\u{73}"), ('\u{1F0}', "\u{6A}\u{30C}"), ('\u{345}', "\u{3B9}"),
+        ('\u{390}', "\u{3B9}\u{308}\u{301}"), ('\u{3B0}', "\u{3C5}\u{308}\u{301}"),
+        ('\u{3C2}', "\u{3C3}"), ('\u{3D0}', "\u{3B2}"), ('\u{3D1}', "\u{3B8}"),
+        ('\u{3D5}', "\u{3C6}"), ('\u{3D6}', "\u{3C0}"), ('\u{3F0}', "\u{3BA}"),
+        ('\u{3F1}', "\u{3C1}"), ('\u{3F5}', "\u{3B5}"), ('\u{587}', "\u{565}\u{582}"),
+        ('\u{13A0}', "\u{13A0}"), ('\u{13A1}', "\u{13A1}"), ('\u{13A2}', "\u{13A2}"),
+        ('\u{13A3}', "\u{13A3}"), ('\u{13A4}', "\u{13A4}"), ('\u{13A5}', "\u{13A5}"),
+        ('\u{13A6}', "\u{13A6}"), ('\u{13A7}', "\u{13A7}"), ('\u{13A8}', "\u{13A8}"),
+        ('\u{13A9}', "\u{13A9}"), ('\u{13AA}', "\u{13AA}"), ('\u{13AB}', "\u{13AB}"),
+        ('\u{13AC}', "\u{13AC}"), ('\u{13AD}', "\u{13AD}"), ('\u{13AE}', "\u{13AE}"),
+        ('\u{13AF}', "\u{13AF}"), ('\u{13B0}', "\u{13B0}"), ('\u{13B1}', "\u{13B1}"),
+        ('\u{13B2}', "\u{13B2}"), ('\u{13B3}', "\u{13B3}"), ('\u{13B4}', "\u{13B4}"),
+        ('\u{13B5}', "\u{13B5}"), ('\u{13B6}', "\u{13B6}"), ('\u{13B7}', "\u{13B7}"),
+        ('\u{13B8}', "\u{13B8}"), ('\u{13B9}', "\u{13B9}"), ('\u{13BA}', "\u{13BA}"),
+        ('\u{13BB}', "\u{13BB}"), ('\u{13BC}', "\u{13BC}"), ('\u{13BD}', "\u{13BD}"),
+        ('\u{13BE}', "\u{13BE}"), ('\u{13BF}', "\u{13BF}"), ('\u{13C0}', "\u{13C0}"),
+        ('\u{13C1}', "\u{13C1}"), ('\u{13C2}', "\u{13C2}"), ('\u{13C3}', "\u{13C3}"),
+        ('\u{13C4}', "\u{13C4}"), ('\u{13C5}', "\u{13C5}"), ('\u{13C6}', "\u{13C6}"),
+        ('\u{13C7}', "\u{13C7}"), ('\u{13C8}', "\u{13C8}"), ('\u{13C9}', "\u{13C9}"),
+        ('\u{13CA}', "\u{13CA}"), ('\u{13CB}', "\u{13CB}"), ('\u{13CC}', "\u{13CC}"),
+        ('\u{13CD}', "\u{13CD}"), ('\u{13CE}', "\u{13CE}"), ('\u{13CF}', "\u{13CF}"),
+        ('\u{13D0}', "\u{13D0}"), ('\u{13D1}', "\u{13D1}"), ('\u{13D2}', "\u{13D2}"),
+        ('\u{13D3}', "\u{13D3}"), ('\u{13D4}', "\u{13D4}"), ('\u{13D5}', "\u{13D5}"),
+        ('\u{13D6}', "\u{13D6}"), ('\u{13D7}', "\u{13D7}"), ('\u{13D8}', "\u{13D8}"),
+        ('\u{13D9}', "\u{13D9}"), ('\u{13DA}', "\u{13DA}"), ('\u{13DB}', "\u{13DB}"),
+        ('\u{13DC}', "\u{13DC}"), ('\u{13DD}', "\u{13DD}"), ('\u{13DE}', "\u{13DE}"),
+        ('\u{13DF}', "\u{13DF}"), ('\u{13E0}', "\u{13E0}"), ('\u{13E1}', "\u{13E1}"),
+        ('\u{13E2}', "\u{13E2}"), ('\u{13E3}', "\u{13E3}"), ('\u{13E4}', "\u{13E4}"),
+        ('\u{13E5}', "\u{13E5}"), ('\u{13E6}', "\u{13E6}"), ('\u{13E7}', "\u{13E7}"),
+        ('\u{13E8}', "\u{13E8}"), ('\u{13E9}', "\u{13E9}"), ('\u{13EA}', "\u{13EA}"),
+        ('\u{13EB}', "\u{13EB}"), ('\u{13EC}', "\u{13EC}"), ('\u{13ED}', "\u{13ED}"),
+        ('\u{13EE}', "\u{13EE}"), ('\u{13EF}', "\u{13EF}"), ('\u{13F0}', "\u{13F0}"),
+        ('\u{13F1}', "\u{13F1}"), ('\u{13F2}', "\u{13F2}"), ('\u{13F3}', "\u{13F3}"),
+        ('\u{13F4}', "\u{13F4}"), ('\u{13F5}', "\u{13F5}"), ('\u{13F8}', "\u{13F0}"),
+        ('\u{13F9}', "\u{13F1}"), ('\u{13FA}', "\u{13F2}"), ('\u{13FB}', "\u{13F3}"),
+        ('\u{13FC}', "\u{13F4}"), ('\u{13FD}', "\u{13F5}"), ('\u{1C80}', "\u{432}"),
+        ('\u{1C81}', "\u{434}"), ('\u{1C82}', "\u{43E}"), ('\u{1C83}', "\u{441}"),
+        ('\u{1C84}', "\u{442}"), ('\u{1C85}', "\u{442}"), ('\u{1C86}', "\u{44A}"),
+        ('\u{1C87}', "\u{463}"), ('\u{1C88}', "\u{A64B}"), ('\u{1E96}', "\u{68}\u{331}"),
+        ('\u{1E97}', "\u{74}\u{308}"), ('\u{1E98}', "\u{77}\u{30A}"), ('\u{1E99}', "\u{79}\u{30A}"),
+        ('\u{1E9A}', "\u{61}\u{2BE}"), ('\u{1E9B}', "\u{1E61}"), ('\u{1E9E}', "\u{73}\u{73}"),
+        ('\u{1F50}', "\u{3C5}\u{313}"), ('\u{1F52}', "\u{3C5}\u{313}\u{300}"),
+        ('\u{1F54}', "\u{3C5}\u{313}\u{301}"), ('\u{1F56}', "\u{3C5}\u{313}\u{342}"),
+        ('\u{1F80}', "\u{1F00}\u{3B9}"), ('\u{1F81}', "\u{1F01}\u{3B9}"),
+        ('\u{1F82}', "\u{1F02}\u{3B9}"), ('\u{1F83}', "\u{1F03}\u{3B9}"),
+        ('\u{1F84}', "\u{1F04}\u{3B9}"), ('\u{1F85}', "\u{1F05}\u{3B9}"),
+        ('\u{1F86}', "\u{1F06}\u{3B9}"), ('\u{1F87}', "\u{1F07}\u{3B9}"),
+        ('\u{1F88}', "\u{1F00}\u{3B9}"), ('\u{1F89}', "\u{1F01}\u{3B9}"),
+        ('\u{1F8A}', "\u{1F02}\u{3B9}"), ('\u{1F8B}', "\u{1F03}\u{3B9}"),
+        ('\u{1F8C}', "\u{1F04}\u{3B9}"), ('\u{1F8D}', "\u{1F05}\u{3B9}"),
+        ('\u{1F8E}', "\u{1F06}\u{3B9}"), ('\u{1F8F}', "\u{1F07}\u{3B9}"),
+        ('\u{1F90}', "\u{1F20}\u{3B9}"), ('\u{1F91}', "\u{1F21}\u{3B9}"),
+        ('\u{1F92}', "\u{1F22}\u{3B9}"), ('\u{1F93}', "\u{1F23}\u{3B9}"),
+        ('\u{1F94}', "\u{1F24}\u{3B9}"), ('\u{1F95}', "\u{1F25}\u{3B9}"),
+        ('\u{1F96}', "\u{1F26}\u{3B9}"), ('\u{1F97}', "\u{1F27}\u{3B9}"),
+        ('\u{1F98}', "\u{1F20}\u{3B9}"), ('\u{1F99}', "\u{1F21}\u{3B9}"),
+        ('\u{1F9A}', "\u{1F22}\u{3B9}"), ('\u{1F9B}', "\u{1F23}\u{3B9}"),
+        ('\u{1F9C}', "\u{1F24}\u{3B9}"), ('\u{1F9D}', "\u{1F25}\u{3B9}"),
+        ('\u{1F9E}', "\u{1F26}\u{3B9}"), ('\u{1F9F}', "\u{1F27}\u{3B9}"),
+        ('\u{1FA0}', "\u{1F60}\u{3B9}"), ('\u{1FA1}', "\u{1F61}\u{3B9}"),
+        ('\u{1FA2}', "\u{1F62}\u{3B9}"), ('\u{1FA3}', "\u{1F63}\u{3B9}"),
+        ('\u{1FA4}', "\u{1F64}\u{3B9}"), ('\u{1FA5}', "\u{1F65}\u{3B9}"),
+        ('\u{1FA6}', "\u{1F66}\u{3B9}"), ('\u{1FA7}', "\u{1F67}\u{3B9}"),
+        ('\u{1FA8}', "\u{1F60}\u{3B9}"), ('\u{1FA9}', "\u{1F61}\u{3B9}"),
+        ('\u{1FAA}', "\u{1F62}\u{3B9}"), ('\u{1FAB}', "\u{1F63}\u{3B9}"),
+        ('\u{1FAC}', "\u{1F64}\u{3B9}"), ('\u{1FAD}', "\u{1F65}\u{3B9}"),
+        ('\u{1FAE}', "\u{1F66}\u{3B9}"), ('\u{1FAF}', "\u{1F67}\u{3B9}"),
+        ('\u{1FB2}', "\u{1F70}\u{3B9}"), ('\u{1FB3}', "\u{3B1}\u{3B9}"),
+        ('\u{1FB4}', "\u{3AC}\u{3B9}"), ('\u{1FB6}', "\u{3B1}\u{342}"),
+        ('\u{1FB7}', "\u{3B1}\u{342}\u{3B9}"), ('\u{1FBC}', "\u{3B1}\u{3B9}"),
+        ('\u{1FBE}', "\u{3B9}"), ('\u{1FC2}', "\u{1F74}\u{3B9}"), ('\u{1FC3}', "\u{3B7}\u{3B9}"),
+        ('\u{1FC4}', "\u{3AE}\u{3B9}"), ('\u{1FC6}', "\u{3B7}\u{342}"),
+        ('\u{1FC7}', "\u{3B7}\u{342}\u{3B9}"), ('\u{1FCC}', "\u{3B7}\u{3B9}"),
+        ('\u{1FD2}', "\u{3B9}\u{308}\u{300}"), ('\u{1FD3}', "\u{3B9}\u{308}\u{301}"),
+        ('\u{1FD6}', "\u{3B9}\u{342}"), ('\u{1FD7}', "\u{3B9}\u{308}\u{342}"),
+        ('\u{1FE2}', "\u{3C5}\u{308}\u{300}"), ('\u{1FE3}', "\u{3C5}\u{308}\u{301}"),
+        ('\u{1FE4}', "\u{3C1}\u{313}"), ('\u{1FE6}', "\u{3C5}\u{342}"),
+        ('\u{1FE7}', "\u{3C5}\u{308}\u{342}"), ('\u{1FF2}', "\u{1F7C}\u{3B9}"),
+        ('\u{1FF3}', "\u{3C9}\u{3B9}"), ('\u{1FF4}', "\u{3CE}\u{3B9}"),
+        ('\u{1FF6}', "\u{3C9}\u{342}"), ('\u{1FF7}', "\u{3C9}\u{342}\u{3B9}"),
+        ('\u{1FFC}', "\u{3C9}\u{3B9}"), ('\u{AB70}', "\u{13A0}"), ('\u{AB71}', "\u{13A1}"),
+        ('\u{AB72}', "\u{13A2}"), ('\u{AB73}', "\u{13A3}"), ('\u{AB74}', "\u{13A4}"),
+        ('\u{AB75}', "\u{13A5}"), ('\u{AB76}', "\u{13A6}"), ('\u{AB77}', "\u{13A7}"),
+        ('\u{AB78}', "\u{13A8}"), ('\u{AB79}', "\u{13A9}"), ('\u{AB7A}', "\u{13AA}"),
+        ('\u{AB7B}', "\u{13AB}"), ('\u{AB7C}', "\u{13AC}"), ('\u{AB7D}', "\u{13AD}"),
+        ('\u{AB7E}', "\u{13AE}"), ('\u{AB7F}', "\u{13AF}"), ('\u{AB80}', "\u{13B0}"),
+        ('\u{AB81}', "\u{13B1}"), ('\u{AB82}', "\u{13B2}"), ('\u{AB83}', "\u{13B3}"),
+        ('\u{AB84}', "\u{13B4}"), ('\u{AB85}', "\u{13B5}"), ('\u{AB86}', "\u{13B6}"),
+        ('\u{AB87}', "\u{13B7}"), ('\u{AB88}', "\u{13B8}"), ('\u{AB89}', "\u{13B9}"),
+        ('\u{AB8A}', "\u{13BA}"), ('\u{AB8B}', "\u{13BB}"), ('\u{AB8C}', "\u{13BC}"),
+        ('\u{AB8D}', "\u{13BD}"), ('\u{AB8E}', "\u{13BE}"), ('\u{AB8F}', "\u{13BF}"),
+        ('\u{AB90}', "\u{13C0}"), ('\u{AB91}', "\u{13C1}"), ('\u{AB92}', "\u{13C2}"),
+        ('\u{AB93}', "\u{13C3}"), ('\u{AB94}', "\u{13C4}"), ('\u{AB95}', "\u{13C5}"),
+        ('\u{AB96}', "\u{13C6}"), ('\u{AB97}', "\u{13C7}"), ('\u{AB98}', "\u{13C8}"),
+        ('\u{AB99}', "\u{13C9}"), ('\u{AB9A}', "\u{13CA}"), ('\u{AB9B}', "\u{13CB}"),
+        ('\u{AB9C}', "\u{13CC}"), ('\u{AB9D}', "\u{13CD}"), ('\u{AB9E}', "\u{13CE}"),
+        ('\u{AB9F}', "\u{13CF}"), ('\u{ABA0}', "\u{13D0}"), ('\u{ABA1}', "\u{13D1}"),
+        ('\u{ABA2}', "\u{13D2}"), ('\u{ABA3}', "\u{13D3}"), ('\u{ABA4}', "\u{13D4}"),
+        ('\u{ABA5}', "\u{13D5}"), ('\u{ABA6}', "\u{13D6}"), ('\u{ABA7}', "\u{13D7}"),
+        ('\u{ABA8}', "\u{13D8}"), ('\u{ABA9}', "\u{13D9}"), ('\u{ABAA}', "\u{13DA}"),
+        ('\u{ABAB}', "\u{13DB}"), ('\u{ABAC}', "\u{13DC}"), ('\u{ABAD}', "\u{13DD}"),
+        ('\u{ABAE}', "\u{13DE}"), ('\u{ABAF}', "\u{13DF}"), ('\u{ABB0}', "\u{13E0}"),
+        ('\u{ABB1}', "\u{13E1}"), ('\u{ABB2}', "\u{13E2}"), ('\u{ABB3}', "\u{13E3}"),
+        ('\u{ABB4}', "\u{13E4}"), ('\u{ABB5}', "\u{13E5}"), ('\u{ABB6}', "\u{13E6}"),
+        ('\u{ABB7}', "\u{13E7}"), ('\u{ABB8}', "\u{13E8}"), ('\u{ABB9}', "\u{13E9}"),
+        ('\u{ABBA}', "\u{13EA}"), ('\u{ABBB}', "\u{13EB}"), ('\u{ABBC}', "\u{13EC}"),
+        ('\u{ABBD}', "\u{13ED}"), ('\u{ABBE}', "\u{13EE}"), ('\u{ABBF}', "\u{13EF}"),
+        ('\u{FB00}', "\u{66}\u{66}"), ('\u{FB01}', "\u{66}\u{69}"), ('\u{FB02}', "\u{66}\u{6C}"),
+        ('\u{FB03}', "\u{66}\u{66}\u{69}"), ('\u{FB04}', "\u{66}\u{66}\u{6C}"),
+        ('\u{FB05}', "\u{73}\u{74}"), ('\u{FB06}', "\u{73}\u{74}"), ('\u{FB13}', "\u{574}\u{576}"),
+        ('\u{FB14}', "\u{574}\u{565}"), ('\u{FB15}', "\u{574}\u{56B}"),
+        ('\u{FB16}', "\u{57E}\u{576}"), ('\u{FB17}', "\u{574}\u{56D}"),
+    ];
+
+    /// Shortens `s` to at most `max_len` grapheme clusters, ending with
+    /// `ellipsis` when anything was cut. Clusters are never split.
+    fn truncate_graphemes(s: &str, max_len: usize, ellipsis: &str) -> String {
+        if s.graphemes(true).count() <= max_len {
+            return s.to_string();
+        }
+        let keep = max_len.saturating_sub(ellipsis.graphemes(true).count());
+        let mut result: String = s.graphemes(true).take(keep).collect();
+        result.push_str(ellipsis);
+        result
+    }
+
+    /// American Soundex code (`R163` for "Robert"), or an empty string when
+    /// `s` holds no ASCII letters.
+    fn soundex(s: &str) -> String {
+        let code = |c: char| match c {
+            'B' | 'F' | 'P' | 'V' => Some('1'),
+            'C' | 'G' | 'J' | 'K' | 'Q' | 'S' | 'X' | 'Z' => Some('2'),
+            'D' | 'T' => Some('3'),
+            'L' => Some('4'),
+            'M' | 'N' => Some('5'),
+            'R' => Some('6'),
+            _ => None,
+        };
+
+        let mut letters = s.chars()
+            .filter(char::is_ascii_alphabetic)
+            .map(|c| c.to_ascii_uppercase());
+        let Some(first) = letters.next() else {
+            return String::new();
+        };
+
+        let mut result = String::from(first);
+        let mut prev = code(first);
+        for c in letters {
+            let current = code(c);
+            if current.is_some() && current != prev {
+                result.extend(current);
+                if result.len() == 4 {
+                    break;
+                }
+            }
+            // H and W do not separate letters with the same code; vowels do.
+            if c != 'H' && c != 'W' {
+                prev = current;
+            }
+        }
+        format!("{result:0<4}")
+    }
+
+    /// Lawrence Philips' original Metaphone key; `0` stands for "th".
+    fn metaphone(s: &str) -> String {
+        let mut word: Vec<char> = s.chars()
+            .filter(char::is_ascii_alphabetic)
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        word.dedup_by(|a, b| a == b && *a != 'C');
+
+        match word.as_slice() {
+            ['A', 'E', ..] | ['G' | 'K' | 'P', 'N', ..] | ['W', 'R', ..] => {
+                word.remove(0);
+            }
+            ['X', ..] => word[0] = 'S',
+            ['W', 'H', ..] => {
+                word.remove(1);
+            }
+            _ => {}
+        }
+
+        let is_vowel = |c: Option<&char>| matches!(c, Some('A' | 'E' | 'I' | 'O' | 'U'));
+        let is_front = |c: Option<&char>| matches!(c, Some('E' | 'I' | 'Y'));
+        let mut result = String::new();
+        for (i, c) in word.iter().enumerate() {
+            let prev = i.checked_sub(1).and_then(|i| word.get(i));
+            let next = word.get(i + 1);
+            let after_next = word.get(i + 2);
+            let is_last = i + 1 == word.len();
+            match c {
+                'A' | 'E' | 'I' | 'O' | 'U' => {
+                    if i == 0 {
+                        result.push(*c);
+                    }
+                }
+                'B' => {
+                    if !(is_last && prev == Some(&'M')) {
+                        result.push('B');
+                    }
+                }
+                'C' => {
+                    if next == Some(&'I') && after_next == Some(&'A') {
+                        result.push('X');
+                    } else if next == Some(&'H') {
+                        result.push(if prev == Some(&'S') { 'K' } else { 'X' });
+                    } else if is_front(next) {
+                        if prev != Some(&'S') {
+                            result.push('S');
+                        }
+                    } else {
+                        result.push('K');
+                    }
+                }
+                'D' => {
+                    if next == Some(&'G') && is_front(after_next) {
+                        result.push('J');
+                    } else {
+                        result.push('T');
+                    }
+                }
+                'G' => {
+                    let silent_gh = next == Some(&'H') && !is_last && !is_vowel(after_next);
+                    let silent_gn = next == Some(&'N')
+                        && (i + 2 == word.len() || word[i + 1..] == ['N', 'E', 'D']);
+                    if silent_gh || silent_gn {
+                        continue;
+                    }
+                    if is_front(next) && prev != Some(&'G') {
+                        result.push('J');
+                    } else {
+                        result.push('K');
+                    }
+                }
+                'H' => {
+                    let after_modifier = matches!(prev, Some('C' | 'S' | 'P' | 'T' | 'G'));
+                    let silent_after_vowel = is_vowel(prev) && !is_vowel(next);
+                    if !after_modifier && !silent_after_vowel {
+                        result.push('H');
+                    }
+                }
+                'K' => {
+                    if prev != Some(&'C') {
+                        result.push('K');
+                    }
+                }
+                'P' => result.push(if next == Some(&'H') { 'F' } else { 'P' }),
+                'Q' => result.push('K'),
+                'S' => {
+                    let is_x = next == Some(&'H')
+                        || (next == Some(&'I') && matches!(after_next, Some('O' | 'A')));
+                    result.push(if is_x { 'X' } else { 'S' });
+                }
+                'T' => {
+                    if next == Some(&'I') && matches!(after_next, Some('O' | 'A')) {
+                        result.push('X');
+                    } else if next == Some(&'H') {
+                        result.push('0');
+                    } else if !(next == Some(&'C') && after_next == Some(&'H')) {
+                        result.push('T');
+                    }
+                }
+                'V' => result.push('F'),
+                'W' | 'Y' => {
+                    if is_vowel(next) {
+                        result.push(*c);
+                    }
+                }
+                'X' => result.push_str("KS"),
+                'Z' => result.push('S'),
+                c => result.push(*c),
+            }
+        }
+        result
+    }
+
+    fn out_of_range<T: std::fmt::Display>(value: &T) -> resolver::Error {
+        resolver::Error::Custom(format!("int(): {value} is out of range"))
+    }
+
+    /// Truncates like `as i64`, or fails out of range when `checked`.
+    fn float_to_int(x: f64, checked: bool) -> Result<i64, resolver::Error> {
+        if checked && !(x.trunc() >= i64::MIN as f64 && x.trunc() < i64::MAX as f64) {
+            return Err(out_of_range(&x));
+        }
+        Ok(x as i64)
+    }
+
+    /// Python's `int(s, base)`: `base` 0 infers it from a `0x`, `0o` or `0b`
+    /// prefix, which is also accepted for the matching base, and single
+    /// underscores may separate digits.
+    fn parse_int_radix(s: &str, base: u64) -> Result<i128, resolver::Error> {
+        let invalid = || resolver::Error::Custom(format!("int(): invalid literal for base {base}: '{s}'"));
+        let text = s.trim();
+        let (negative, unsigned) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text.strip_prefix('+').unwrap_or(text)),
+        };
+        let lower = unsigned.to_ascii_lowercase();
+        let prefix = match base {
+            0 | 16 if lower.starts_with("0x") => Some(16),
+            0 | 8 if lower.starts_with("0o") => Some(8),
+            0 | 2 if lower.starts_with("0b") => Some(2),
+            _ => None,
+        };
+        let (radix, digits) = match (base, prefix) {
+            (_, Some(radix)) => (radix, lower[2..].strip_prefix('_').unwrap_or(&lower[2..])),
+            (0, None) => (10, lower.as_str()),
+            (2..=36, None) => (base as u32, lower.as_str()),
+            _ => return Err(resolver::Error::Custom("int(): base must be 0 or between 2 and 36".into())),
+        };
+        if digits.is_empty()
+            || digits.starts_with('_')
+            || digits.ends_with('_')
+            || digits.contains("__")
+            || !digits.chars().all(|c| c == '_' || c.is_ascii_alphanumeric())
+        {
+            return Err(invalid());
+        }
+        let magnitude = i128::from_str_radix(&digits.replace('_', ""), radix).map_err(|_| invalid())?;
+        Ok(if negative { -magnitude } else { magnitude })
+    }
+
+    fn atoi(s: String, checked: bool) -> Result<i64, resolver::Error> {
+        let mut item = s
+            .trim()
+            .split(char::is_whitespace)
+            .next()
+            .unwrap_or("")
+            .split(char::is_alphabetic)
+            .next()
+            .unwrap_or("");
+
+        let mut end_idx = 0;
+        for (pos, c) in item.chars().enumerate() {
+            if pos == 0 {
+                continue;
+            }
+
+            if !c.is_alphanumeric() {
+                end_idx = pos;
+                break;
+            }
+        }
+
+        if end_idx > 0 {
+            item = &item[0..end_idx];
+        }
+
+        let result = item.parse::<i64>();
+        match result {
+            Ok(v) => Ok(v),
+            Err(error) => match error.kind() {
+                std::num::IntErrorKind::NegOverflow | std::num::IntErrorKind::PosOverflow if checked => {
+                    Err(out_of_range(&item))
+                }
+                std::num::IntErrorKind::NegOverflow => Ok(i64::MIN),
+                std::num::IntErrorKind::PosOverflow => Ok(i64::MAX),
+                std::num::IntErrorKind::InvalidDigit => {
+                    let result = item.parse::<f64>();
+                    match result {
+                        Ok(v) => float_to_int(v.round(), checked),
+                        _ => Ok(0),
+                    }
+                }
+                _ => Ok(0),
+            },
+        }
+    }
+}
+
+mod formatting {
+    use resolver::Error;
+
+    use crate::eval_wrapper::Stringify;
+    use crate::types::*;
+
+    /// Python's format specification mini-language:
+    /// `[[fill]align][sign][#][0][width][grouping][.precision][type]`.
+    #[derive(Debug, Default, Clone)]
+    pub(crate) struct FormatSpec {
+        pub fill: Option<char>,
+        pub align: Option<char>,
+        pub sign: Option<char>,
+        pub alternate: bool,
+        pub zero: bool,
+        pub width: usize,
+        pub grouping: Option<char>,
+        pub precision: Option<usize>,
+        pub kind: Option<char>,
+    }
+
+    impl FormatSpec {
+        pub(crate) fn parse(spec: &str) -> Result<FormatSpec, Error> {
+            let chars: Vec<char> = spec.chars().collect();
+            let mut result = FormatSpec::default();
+            let mut i = 0;
+
+            let is_align = |c: &char| matches!(c, '<' | '>' | '^' | '=');
+            if chars.get(1).is_some_and(is_align) {
+                result.fill = Some(chars[0]);
+                result.align = Some(chars[1]);
+                i = 2;
+            } else if chars.first().is_some_and(is_align) {
+                result.align = Some(chars[0]);
+                i = 1;
+            }
+            if let Some(c @ ('+' | '-' | ' ')) = chars.get(i) {
+                result.sign = Some(*c);
+                i += 1;
+            }
+            if chars.get(i) == Some(&'#') {
+                result.alternate = true;
+                i += 1;
+            }
+            if chars.get(i) == Some(&'0') {
+                result.zero = true;
+                i += 1;
+            }
+            let (width, next) = parse_digits(&chars, i);
+            result.width = width.unwrap_or(0);
+            i = next;
+            if let Some(c @ (',' | '_')) = chars.get(i) {
+                result.grouping = Some(*c);
+                i += 1;
+            }
+            if chars.get(i) == Some(&'.') {
+                let (precision, next) = parse_digits(&chars, i + 1);
+                if precision.is_none() {
+                    return Err(Error::Custom("Format specifier missing precision".into()));
+                }
+                result.precision = precision;
+                i = next;
+            }
+            match &chars[i..] {
+                [] => {}
+                [c] if "sdnbcoxXeEfFgG%".contains(*c) => result.kind = Some(*c),
+                _ => return Err(Error::Custom(format!("Invalid format specifier '{spec}'"))),
+            }
+            Ok(result)
+        }
+    }
+
+    fn parse_digits(chars: &[char], start: usize) -> (Option<usize>, usize) {
+        let end = chars[start..]
+            .iter()
+            .position(|c| !c.is_ascii_digit())
+            .map_or(chars.len(), |pos| start + pos);
+        let digits: String = chars[start..end].iter().collect();
+        (digits.parse().ok(), end)
+    }
+
+    /// Formats a single value according to `spec`, following `format(value, spec)`.
+    pub(crate) fn format_value(value: &Value, spec: &FormatSpec, stringify: &Stringify) -> Result<String, Error> {
+        let (sign, prefix, body) = match (spec.kind, value) {
+            (None, Value::Number(_)) => format_number(value, spec)?,
+            (None | Some('s'), _) => {
+                let mut body = stringify.apply(value);
+                if let Some(precision) = spec.precision {
+                    body = body.chars().take(precision).collect();
+                }
+                return Ok(pad(String::new(), String::new(), body, spec, '<'));
+            }
+            (Some(kind), Value::String(_)) => {
+                return Err(Error::Custom(format!(
+                    "Unknown format code '{kind}' for value of type string"
+                )));
+            }
+            _ => format_number(value, spec)?,
+        };
+        Ok(pad(sign, prefix, body, spec, '>'))
+    }
+
+    fn format_number(value: &Value, spec: &FormatSpec) -> Result<(String, String, String), Error> {
+        let is_integer = match value {
+            Value::Number(x) => !x.is_f64(),
+            Value::Bool(_) => true,
+            _ => false,
+        };
+        match spec.kind {
+            Some('d' | 'n' | 'b' | 'o' | 'x' | 'X' | 'c') => {
+                format_integer(to_integer(value, spec.kind)?, spec)
+            }
+            None if is_integer => format_integer(to_integer(value, None)?, spec),
+            _ => format_float(to_float(value, spec.kind)?, spec),
+        }
+    }
+
+    fn to_integer(value: &Value, kind: Option<char>) -> Result<i128, Error> {
+        let integer = match value {
+            Value::Number(x) => match (x.as_i64(), x.as_u64(), x.as_f64()) {
+                (Some(i), _, _) => Some(i as i128),
+                (_, Some(u), _) => Some(u as i128),
+                (_, _, Some(f)) if f.fract() == 0.0 && f.abs() < 1e38 => Some(f as i128),
+                _ => None,
+            },
+            Value::Bool(x) => Some(*x as i128),
+            _ => None,
+        };
+        integer.ok_or_else(|| Error::Custom(format!(
+            "Unknown format code '{}' for value {value}",
+            kind.unwrap_or('d')
+        )))
+    }
+
+    fn to_float(value: &Value, kind: Option<char>) -> Result<f64, Error> {
+        match value {
+            Value::Number(x) => Ok(x.as_f64().unwrap_or(f64::NAN)),
+            Value::Bool(x) => Ok(if *x { 1.0 } else { 0.0 }),
+            _ => Err(Error::Custom(format!(
+                "Unknown format code '{}' for value {value}",
+                kind.unwrap_or('g')
+            ))),
+        }
+    }
+
+    fn sign_of(negative: bool, spec: &FormatSpec) -> String {
+        match (negative, spec.sign) {
+            (true, _) => "-".into(),
+            (false, Some('+')) => "+".into(),
+            (false, Some(' ')) => " ".into(),
+            _ => String::new(),
+        }
+    }
+
+    fn format_integer(value: i128, spec: &FormatSpec) -> Result<(String, String, String), Error> {
+        if spec.precision.is_some() {
+            return Err(Error::Custom("Precision not allowed in integer format specifier".into()));
+        }
+        let magnitude = value.unsigned_abs();
+        let (prefix, digits, group_size) = match spec.kind {
+            Some('b') => ("0b", format!("{magnitude:b}"), 4),
+            Some('o') => ("0o", format!("{magnitude:o}"), 4),
+            Some('x') => ("0x", format!("{magnitude:x}"), 4),
+            Some('X') => ("0X", format!("{magnitude:X}"), 4),
+            Some('c') => {
+                let c = u32::try_from(value).ok().and_then(char::from_u32).ok_or_else(|| {
+                    Error::Custom(format!("%c arg not in range(0x110000): {value}"))
+                })?;
+                return Ok((String::new(), String::new(), c.to_string()));
+            }
+            _ => ("", magnitude.to_string(), 3),
+        };
+        let prefix = if spec.alternate { prefix } else { "" };
+        let body = match spec.grouping {
+            Some(sep) => group_digits(&digits, sep, group_size),
+            None => digits,
+        };
+        Ok((sign_of(value < 0, spec), prefix.to_string(), body))
+    }
+
+    fn format_float(value: f64, spec: &FormatSpec) -> Result<(String, String, String), Error> {
+        let sign = sign_of(value.is_sign_negative() && !value.is_nan(), spec);
+        let upper = matches!(spec.kind, Some('E' | 'F' | 'G'));
+        let x = value.abs();
+
+        let body = if !x.is_finite() {
+            let text = if x.is_nan() { "nan" } else { "inf" };
+            let text = if upper { text.to_uppercase() } else { text.to_string() };
+            if spec.kind == Some('%') { format!("{text}%") } else { text }
+        } else {
+            let precision = spec.precision.unwrap_or(6);
+            let text = match spec.kind {
+                Some('f' | 'F') => format!("{x:.precision$}"),
+                Some('e' | 'E') => format_exp(x, precision),
+                Some('g' | 'G' | 'n') => format_general(x, precision, spec.alternate),
+                Some('%') => format!("{:.precision$}%", x * 100.0),
+                _ => match spec.precision {
+                    Some(precision) => {
+                        let text = format_general(x, precision, spec.alternate);
+                        if text.contains(['.', 'e', 'i', 'n']) { text } else { format!("{text}.0") }
+                    }
+                    None => format_repr(x),
+                },
+            };
+            let text = if spec.alternate && !text.contains('.') {
+                match text.find(['e', '%']) {
+                    Some(pos) => format!("{}.{}", &text[..pos], &text[pos..]),
+                    None => format!("{text}."),
+                }
+            } else {
+                text
+            };
+            let text = if upper { text.to_uppercase() } else { text };
+            match spec.grouping {
+                Some(sep) => {
+                    let end = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+                    format!("{}{}", group_digits(&text[..end], sep, 3), &text[end..])
+                }
+                None => text,
+            }
+        };
+        Ok((sign, String::new(), body))
+    }
+
+    /// Scientific notation with a signed, at least two digit exponent (`1.5e+02`).
+    fn format_exp(x: f64, precision: usize) -> String {
+        let text = format!("{x:.precision$e}");
+        let (mantissa, exp) = text.split_once('e').unwrap_or((&text, "0"));
+        let exp: i32 = exp.parse().unwrap_or(0);
+        format!("{mantissa}e{}{:02}", if exp < 0 { '-' } else { '+' }, exp.abs())
+    }
+
+    fn format_general(x: f64, precision: usize, alternate: bool) -> String {
+        let precision = precision.max(1);
+        let exp = if x == 0.0 {
+            0
+        } else {
+            let text = format!("{x:.*e}", precision - 1);
+            text.split_once('e').and_then(|(_, exp)| exp.parse::<i32>().ok()).unwrap_or(0)
+        };
+        let text = if -4 <= exp && exp < precision as i32 {
+            let decimals = (precision as i32 - 1 - exp).max(0) as usize;
+            format!("{x:.decimals$}")
+        } else {
+            format_exp(x, precision - 1)
+        };
+        if alternate {
+            return text;
+        }
+        let (mantissa, exp) = match text.find('e') {
+            Some(pos) => text.split_at(pos),
+            None => (text.as_str(), ""),
+        };
+        let mantissa = if mantissa.contains('.') {
+            mantissa.trim_end_matches('0').trim_end_matches('.')
+        } else {
+            mantissa
+        };
+        format!("{mantissa}{exp}")
+    }
+
+    /// Shortest round-tripping representation, as Python's `repr(float)`.
+    fn format_repr(x: f64) -> String {
+        let text = format!("{x:e}");
+        let (mantissa, exp) = text.split_once('e').unwrap_or((&text, "0"));
+        let exp: i32 = exp.parse().unwrap_or(0);
+        if x != 0.0 && !(-4..16).contains(&exp) {
+            format!("{mantissa}e{}{:02}", if exp < 0 { '-' } else { '+' }, exp.abs())
+        } else {
+            let text = x.to_string();
+            if text.contains('.') { text } else { format!("{text}.0") }
+        }
+    }
+
+    pub(crate) fn group_digits(digits: &str, sep: char, size: usize) -> String {
+        let len = digits.chars().count();
+        let mut result = String::with_capacity(digits.len() + len / size);
+        for (pos, c) in digits.chars().enumerate() {
+            if pos > 0 && (len - pos).is_multiple_of(size) {
+                result.push(sep);
+            }
+            result.push(c);
+        }
+        result
+    }
+
+    fn pad(sign: String, prefix: String, body: String, spec: &FormatSpec, default_align: char) -> String {
+        let len = sign.chars().count() + prefix.chars().count() + body.chars().count();
+        let (fill, align) = match (spec.align, spec.zero) {
+            (None, true) if default_align == '>' => (spec.fill.unwrap_or('0'), '='),
+            (align, _) => (spec.fill.unwrap_or(' '), align.unwrap_or(default_align)),
+        };
+        if len >= spec.width {
+            return format!("{sign}{prefix}{body}");
+        }
+        if let ('=', '0', Some(sep)) = (align, fill, spec.grouping) {
+            let width = spec.width - (len - body.chars().count());
+            return format!("{sign}{prefix}{}", zero_fill_grouped(&body, sep, spec, width));
+        }
+        let padding = spec.width - len;
+        let fill_str = |n: usize| std::iter::repeat_n(fill, n).collect::<String>();
+        match align {
+            '<' => format!("{sign}{prefix}{body}{}", fill_str(padding)),
+            '^' => format!(
+                "{}{sign}{prefix}{body}{}",
+                fill_str(padding / 2),
+                fill_str(padding - padding / 2)
+            ),
+            '=' => format!("{sign}{prefix}{}{body}", fill_str(padding)),
+            _ => format!("{}{sign}{prefix}{body}", fill_str(padding)),
+        }
+    }
+
+    /// Zero padding that is grouped like the digits it extends, as Python
+    /// does for `{:010,}`: `body` grows to at least `width` chars, adding one
+    /// more zero when it would otherwise start with a separator.
+    fn zero_fill_grouped(body: &str, sep: char, spec: &FormatSpec, width: usize) -> String {
+        let size = if matches!(spec.kind, Some('b' | 'o' | 'x' | 'X')) { 4 } else { 3 };
+        let end = match size {
+            4 => body.len(),
+            _ => body.find(|c: char| !c.is_ascii_digit() && c != sep).unwrap_or(body.len()),
+        };
+        let (integer, rest) = body.split_at(end);
+        let digits: String = integer.chars().filter(|c| *c != sep).collect();
+        let target = width.saturating_sub(rest.chars().count());
+        let mut count = digits.len();
+        while count + (count.max(1) - 1) / size < target {
+            count += 1;
+        }
+        let zeros = "0".repeat(count - digits.len());
+        format!("{}{rest}", group_digits(&format!("{zeros}{digits}"), sep, size))
+    }
+
+    fn repr(value: &Value, stringify: &Stringify) -> String {
+        match value {
+            Value::String(x) => format!("'{}'", x.replace('\\', "\\\\").replace('\'', "\\'")),
+            x => stringify.apply(x),
+        }
+    }
+
+    fn convert(value: Value, conversion: Option<char>, stringify: &Stringify) -> Result<Value, Error> {
+        match conversion {
+            None => Ok(value),
+            Some('s') => Ok(Value::String(stringify.apply(&value))),
+            Some('r' | 'a') => Ok(Value::String(repr(&value, stringify))),
+            Some(c) => Err(Error::Custom(format!("Unknown conversion specifier {c}"))),
+        }
+    }
+
+    /// Positional and named arguments available to replacement fields.
+    struct FieldArgs<'a> {
+        args: &'a [Value],
+        next_auto: usize,
+        manual: bool,
+        stringify: &'a Stringify,
+    }
+
+    impl FieldArgs<'_> {
+        fn resolve(&mut self, field: &str) -> Result<Value, Error> {
+            let end = field.find(['.', '[']).unwrap_or(field.len());
+            let (head, mut rest) = field.split_at(end);
+
+            let mut value = if head.is_empty() {
+                if self.manual {
+                    return Err(Error::Custom(
+                        "cannot switch from manual field specification to automatic field numbering".into(),
+                    ));
+                }
+                let idx = self.next_auto;
+                self.next_auto += 1;
+                self.positional(idx)?
+            } else {
+                if self.next_auto > 0 && head.parse::<usize>().is_ok() {
+                    return Err(Error::Custom(
+                        "cannot switch from automatic field numbering to manual field specification".into(),
+                    ));
+                }
+                match head.parse::<usize>() {
+                    Ok(idx) => {
+                        self.manual = true;
+                        self.positional(idx)?
+                    }
+                    Err(_) => self
+                        .args
+                        .iter()
+                        .find_map(|arg| arg.as_object().and_then(|obj| obj.get(head)))
+                        .cloned()
+                        .ok_or_else(|| Error::Custom(format!("format(): missing key '{head}'")))?,
+                }
+            };
+
+            while !rest.is_empty() {
+                let (key, next) = if let Some(tail) = rest.strip_prefix('.') {
+                    let end = tail.find(['.', '[']).unwrap_or(tail.len());
+                    (&tail[..end], &tail[end..])
+                } else if let Some(tail) = rest.strip_prefix('[') {
+                    let end = tail.find(']').ok_or_else(|| {
+                        Error::Custom("Missing ']' in format string".into())
+                    })?;
+                    (&tail[..end], &tail[end + 1..])
+                } else {
+                    return Err(Error::Custom(format!("Invalid field name '{field}'")));
+                };
+                value = match (&value, key.parse::<usize>()) {
+                    (Value::Array(arr), Ok(idx)) => arr.get(idx).cloned(),
+                    (Value::Object(obj), _) => obj.get(key).cloned(),
+                    _ => None,
+                }
+                .ok_or_else(|| Error::Custom(format!("format(): missing key '{key}' in '{field}'")))?;
+                rest = next;
+            }
+            Ok(value)
+        }
+
+        fn positional(&self, idx: usize) -> Result<Value, Error> {
+            self.args.get(idx).cloned().ok_or_else(|| {
+                Error::Custom(format!("format(): replacement index {idx} out of range"))
+            })
+        }
+    }
+
+    /// `str.format` style formatting: `{}`, `{0}`, `{name.attr[0]!r:>10}` and
+    /// `{{`/`}}` escapes. Named fields are looked up in the object arguments.
+    pub(crate) fn format_braces(template: &str, args: &[Value], stringify: &Stringify) -> Result<String, Error> {
+        let mut state = FieldArgs { args, next_auto: 0, manual: false, stringify };
+        format_braces_with(template, &mut state)
+    }
+
+    fn format_braces_with(template: &str, state: &mut FieldArgs) -> Result<String, Error> {
+        let mut result = String::with_capacity(template.len());
+        let mut chars = template.char_indices().peekable();
+        while let Some((pos, c)) = chars.next() {
+            match c {
+                '{' if chars.peek().map(|(_, c)| *c) == Some('{') => {
+                    chars.next();
+                    result.push('{');
+                }
+                '}' if chars.peek().map(|(_, c)| *c) == Some('}') => {
+                    chars.next();
+                    result.push('}');
+                }
+                '}' => return Err(Error::Custom("Single '}' encountered in format string".into())),
+                '{' => {
+                    let mut depth = 1;
+                    let mut end = None;
+                    for (idx, c) in chars.by_ref() {
+                        match c {
+                            '{' => depth += 1,
+                            '}' => {
+                                depth -= 1;
+                                if depth == 0 {
+                                    end = Some(idx);
+                                    break;
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                    let end = end.ok_or_else(|| {
+                        Error::Custom("Single '{' encountered in format string".into())
+                    })?;
+                    let field = &template[pos + 1..end];
+                    result.push_str(&format_field(field, state)?);
+                }
+                c => result.push(c),
+            }
+        }
+        Ok(result)
+    }
+
+    fn format_field(field: &str, state: &mut FieldArgs) -> Result<String, Error> {
+        let (name_part, spec) = match field.find(':') {
+            Some(pos) => (&field[..pos], &field[pos + 1..]),
+            None => (field, ""),
+        };
+        let (name, conversion) = match name_part.split_once('!') {
+            Some((name, conversion)) => {
+                let mut chars = conversion.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => (name, Some(c)),
+                    _ => return Err(Error::Custom("Expected ':' after conversion specifier".into())),
+                }
+            }
+            None => (name_part, None),
+        };
+        let value = convert(state.resolve(name)?, conversion, state.stringify)?;
+        let spec = if spec.contains('{') {
+            format_braces_with(spec, state)?
+        } else {
+            spec.to_string()
+        };
+        format_value(&value, &FormatSpec::parse(&spec)?, state.stringify)
+    }
+
+    /// printf-style formatting, as Python's `fmt % args`. A single object
+    /// argument enables `%(name)s` mapping keys.
+    pub(crate) fn format_percent(template: &str, args: &[Value], stringify: &Stringify) -> Result<String, Error> {
+        let mut result = String::with_capacity(template.len());
+        let mut next_arg = 0;
+        let mut used_mapping = false;
+        let chars: Vec<char> = template.chars().collect();
+        let mut i = 0;
+
+        let take_arg = |next_arg: &mut usize| -> Result<Value, Error> {
+            let value = args.get(*next_arg).cloned().ok_or_else(|| {
+                Error::Custom("not enough arguments for format string".into())
+            })?;
+            *next_arg += 1;
+            Ok(value)
+        };
+
+        while i < chars.len() {
+            if chars[i] != '%' {
+                result.push(chars[i]);
+                i += 1;
+                continue;
+            }
+            i += 1;
+            if chars.get(i) == Some(&'%') {
+                result.push('%');
+                i += 1;
+                continue;
+            }
+
+            let mut key = None;
+            if chars.get(i) == Some(&'(') {
+                let end = chars[i..].iter().position(|c| *c == ')').ok_or_else(|| {
+                    Error::Custom("incomplete format key".into())
+                })?;
+                key = Some(chars[i + 1..i + end].iter().collect::<String>());
+                i += end + 1;
+            }
+
+            let mut spec = FormatSpec::default();
+            while let Some(c @ ('-' | '+' | ' ' | '#' | '0')) = chars.get(i) {
+                match c {
+                    '-' => spec.align = Some('<'),
+                    '+' => spec.sign = Some('+'),
+                    ' ' if spec.sign.is_none() => spec.sign = Some(' '),
+                    '#' => spec.alternate = true,
+                    '0' => spec.zero = true,
+                    _ => {}
+                }
+                i += 1;
+            }
+            if spec.align.is_some() {
+                spec.zero = false;
+            }
+            if chars.get(i) == Some(&'*') {
+                let width = take_arg(&mut next_arg)?;
+                spec.width = width.as_u64().unwrap_or(0) as usize;
+                i += 1;
+            } else {
+                let (width, next) = parse_digits(&chars, i);
+                spec.width = width.unwrap_or(0);
+                i = next;
+            }
+            if chars.get(i) == Some(&'.') {
+                if chars.get(i + 1) == Some(&'*') {
+                    let precision = take_arg(&mut next_arg)?;
+                    spec.precision = Some(precision.as_u64().unwrap_or(0) as usize);
+                    i += 2;
+                } else {
+                    let (precision, next) = parse_digits(&chars, i + 1);
+                    spec.precision = Some(precision.unwrap_or(0));
+                    i = next;
+                }
+            }
+            while let Some('h' | 'l' | 'L') = chars.get(i) {
+                i += 1;
+            }
+            let kind = *chars.get(i).ok_or_else(|| Error::Custom("incomplete format".into()))?;
+            i += 1;
+
+            let value = match &key {
+                Some(key) => {
+                    used_mapping = true;
+                    args.first()
+                        .and_then(|arg| arg.as_object())
+                        .and_then(|obj| obj.get(key))
+                        .cloned()
+                        .ok_or_else(|| Error::Custom(format!("format requires a mapping with key '{key}'")))?
+                }
+                None => take_arg(&mut next_arg)?,
+            };
+
+            let text = match kind {
+                's' | 'r' | 'a' => {
+                    spec.zero = false;
+                    let value = convert(value, Some(kind), stringify)?;
+                    format_value(&value, &spec, stringify)?
+                }
+                'd' | 'i' | 'u' => {
+                    spec.kind = Some('d');
+                    let value = match value.as_f64() {
+                        Some(x) if value.is_f64() => to_value(x.trunc()),
+                        _ => value,
+                    };
+                    format_value(&value, &spec, stringify)?
+                }
+                'c' => {
+                    spec.kind = Some('c');
+                    match value {
+                        Value::String(x) if x.chars().count() == 1 => {
+                            spec.kind = None;
+                            format_value(&Value::String(x), &spec, stringify)?
+                        }
+                        value => format_value(&value, &spec, stringify)?,
+                    }
+                }
+                'o' | 'x' | 'X' | 'e' | 'E' | 'f' | 'F' | 'g' | 'G' => {
+                    spec.kind = Some(kind);
+                    format_value(&value, &spec, stringify)?
+                }
+                c => {
+                    return Err(Error::Custom(format!("unsupported format character '{c}'")));
+                }
+            };
+            result.push_str(&text);
+        }
+
+        if !used_mapping && next_arg < args.len() {
+            return Err(Error::Custom("not all arguments converted during string formatting".into()));
+        }
+        Ok(result)
+    }
+}
+
+mod locale {
+    use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+    use resolver::Error;
+
+    use crate::eval_wrapper::value_to_string;
+    use crate::types::*;
+
+    /// Names and relative-time phrases shared by every region of a language.
+    struct Language {
+        months: &'static str,
+        short_months: &'static str,
+        /// Monday first.
+        weekdays: &'static str,
+        /// `{}` is replaced by the unit phrase.
+        past: &'static str,
+        future: &'static str,
+        now: &'static str,
+        /// Seconds, minutes, hours, days, weeks, months and years, with the
+        /// plural forms in the order `plural` picks them and `{n}` standing
+        /// for the count.
+        units: [&'static str; 7],
+        plural: fn(u64) -> usize,
+    }
+
+    struct Locale {
+        tag: &'static str,
+        language: &'static Language,
+        decimal: char,
+        group: char,
+        /// Groups as 12,34,567 rather than 1,234,567.
+        indian_grouping: bool,
+        /// `{s}` is the currency symbol and `{n}` the amount.
+        currency: &'static str,
+        percent: &'static str,
+        /// Short, medium, long and full date patterns.
+        dates: [&'static str; 4],
+    }
+
+    fn one_other(n: u64) -> usize {
+        usize::from(n != 1)
+    }
+
+    fn zero_one_other(n: u64) -> usize {
+        usize::from(n > 1)
+    }
+
+    fn invariant(_: u64) -> usize {
+        0
+    }
+
+    fn few(n: u64) -> bool {
+        (2..=4).contains(&(n % 10)) && !(12..=14).contains(&(n % 100))
+    }
+
+    fn russian(n: u64) -> usize {
+        match n {
+            _ if n % 10 == 1 && n % 100 != 11 => 0,
+            _ if few(n) => 1,
+            _ => 2,
+        }
+    }
+
+    fn polish(n: u64) -> usize {
+        match n {
+            1 => 0,
+            _ if few(n) => 1,
+            _ => 2,
+        }
+    }
+
+    static EN: Language = Language {
+        months: "January|February|March|April|May|June|July|August|September|October|November|December",
+        short_months: "Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec",
+        weekdays: "Monday|Tuesday|Wednesday|Thursday|Friday|Saturday|Sunday",
+        past: "{} ago",
+        future: "in {}",
+        now: "now",
+        units: [
+            "{n} second|{n} seconds",
+            "{n} minute|{n} minutes",
+            "{n} hour|{n} hours",
+            "{n} day|{n} days",
+            "{n} week|{n} weeks",
+            "{n} month|{n} months",
+            "{n} year|{n} years",
+        ],
+        plural: one_other,
+    };
+
+    static DE: Language = Language {
+        months: "Januar|Februar|März|April|Mai|Juni|Juli|August|September|Oktober|November|Dezember",
+        short_months: "Jan.|Feb.|März|Apr.|Mai|Juni|Juli|Aug.|Sept.|Okt.|Nov.|Dez.",
+        weekdays: "Montag|Dienstag|Mittwoch|Donnerstag|Freitag|Samstag|Sonntag",
+        past: "vor {}",
+        future: "in {}",
+        now: "jetzt",
+        units: [
+            "{n} Sekunde|{n} Sekunden",
+            "{n} Minute|{n} Minuten",
+            "{n} Stunde|{n} Stunden",
+            "{n} Tag|{n} Tagen",
+            "{n} Woche|{n} Wochen",
+            "{n} Monat|{n} Monaten",
+            "{n} Jahr|{n} Jahren",
+        ],
+        plural: one_other,
+    };
+
+    static FR: Language = Language {
+        months: "janvier|février|mars|avril|mai|juin|juillet|août|septembre|octobre|novembre|décembre",
+        short_months: "janv.|févr.|mars|avr.|mai|juin|juil.|août|sept.|oct.|nov.|déc.",
+        weekdays: "lundi|mardi|mercredi|jeudi|vendredi|samedi|dimanche",
+        past: "il y a {}",
+        future: "dans {}",
+        now: "maintenant",
+        units: [
+            "{n} seconde|{n} secondes",
+            "{n} minute|{n} minutes",
+            "{n} heure|{n} heures",
+            "{n} jour|{n} jours",
+            "{n} semaine|{n} semaines",
+            "{n} mois",
+            "{n} an|{n} ans",
+        ],
+        plural: zero_one_other,
+    };
+
+    static ES: Language = Language {
+        months: "enero|febrero|marzo|abril|mayo|junio|julio|agosto|septiembre|octubre|noviembre|diciembre",
+        short_months: "ene|feb|mar|abr|may|jun|jul|ago|sept|oct|nov|dic",
+        weekdays: "lunes|martes|miércoles|jueves|viernes|sábado|domingo",
+        past: "hace {}",
+        future: "dentro de {}",
+        now: "ahora",
+        units: [
+            "{n} segundo|{n} segundos",
+            "{n} minuto|{n} minutos",
+            "{n} hora|{n} horas",
+            "{n} día|{n} días",
+            "{n} semana|{n} semanas",
+            "{n} mes|{n} meses",
+            "{n} año|{n} años",
+        ],
+        plural: one_other,
+    };
+
+    static IT: Language = Language {
+        months: "gennaio|febbraio|marzo|aprile|maggio|giugno|luglio|agosto|settembre|ottobre|novembre|dicembre",
+        short_months: "gen|feb|mar|apr|mag|giu|lug|ago|set|ott|nov|dic",
+        weekdays: "lunedì|martedì|mercoledì|giovedì|venerdì|sabato|domenica",
+        past: "{} fa",
+        future: "tra {}",
+        now: "ora",
+        units: [
+            "{n} secondo|{n} secondi",
+            "{n} minuto|{n} minuti",
+            "{n} ora|{n} ore",
+            "{n} giorno|{n} giorni",
+            "{n} settimana|{n} settimane",
+            "{n} mese|{n} mesi",
+            "{n} anno|{n} anni",
+        ],
+        plural: one_other,
+    };
+
+    static NL: Language = Language {
+        months: "januari|februari|maart|april|mei|juni|juli|augustus|september|oktober|november|december",
+        short_months: "jan|feb|mrt|apr|mei|jun|jul|aug|sep|okt|nov|dec",
+        weekdays: "maandag|dinsdag|woensdag|donderdag|vrijdag|zaterdag|zondag",
+        past: "{} geleden",
+        future: "over {}",
+        now: "nu",
+        units: [
+            "{n} seconde|{n} seconden",
+            "{n} minuut|{n} minuten",
+            "{n} uur",
+            "{n} dag|{n} dagen",
+            "{n} week|{n} weken",
+            "{n} maand|{n} maanden",
+            "{n} jaar",
+        ],
+        plural: one_other,
+    };
+
+    static PT: Language = Language {
+        months: "janeiro|fevereiro|março|abril|maio|junho|julho|agosto|setembro|outubro|novembro|dezembro",
+        short_months: "jan.|fev.|mar.|abr.|mai.|jun.|jul.|ago.|set.|out.|nov.|dez.",
+        weekdays: "segunda-feira|terça-feira|quarta-feira|quinta-feira|sexta-feira|sábado|domingo",
+        past: "há {}",
+        future: "em {}",
+        now: "agora",
+        units: [
+            "{n} segundo|{n} segundos",
+            "{n} minuto|{n} minutos",
+            "{n} hora|{n} horas",
+            "{n} dia|{n} dias",
+            "{n} semana|{n} semanas",
+            "{n} mês|{n} meses",
+            "{n} ano|{n} anos",
+        ],
+        plural: zero_one_other,
+    };
+
+    static SV: Language = Language {
+        months: "januari|februari|mars|april|maj|juni|juli|augusti|september|oktober|november|december",
+        short_months: "jan.|feb.|mars|apr.|maj|juni|juli|aug.|sep.|okt.|nov.|dec.",
+        weekdays: "måndag|tisdag|onsdag|torsdag|fredag|lördag|söndag",
+        past: "för {} sedan",
+        future: "om {}",
+        now: "nu",
+        units: [
+            "{n} sekund|{n} sekunder",
+            "{n} minut|{n} minuter",
+            "{n} timme|{n} timmar",
+            "{n} dag|{n} dagar",
+            "{n} vecka|{n} veckor",
+            "{n} månad|{n} månader",
+            "{n} år",
+        ],
+        plural: one_other,
+    };
+
+    static PL: Language = Language {
+        months: "stycznia|lutego|marca|kwietnia|maja|czerwca|lipca|sierpnia|września|października|listopada|grudnia",
+        short_months: "sty|lut|mar|kwi|maj|cze|lip|sie|wrz|paź|lis|gru",
+        weekdays: "poniedziałek|wtorek|środa|czwartek|piątek|sobota|niedziela",
+        past: "{} temu",
+        future: "za {}",
+        now: "teraz",
+        units: [
+            "{n} sekundę|{n} sekundy|{n} sekund",
+            "{n} minutę|{n} minuty|{n} minut",
+            "{n} godzinę|{n} godziny|{n} godzin",
+            "{n} dzień|{n} dni|{n} dni",
+            "{n} tydzień|{n} tygodnie|{n} tygodni",
+            "{n} miesiąc|{n} miesiące|{n} miesięcy",
+            "{n} rok|{n} lata|{n} lat",
+        ],
+        plural: polish,
+    };
+
+    static RU: Language = Language {
+        months: "января|февраля|марта|апреля|мая|июня|июля|августа|сентября|октября|ноября|декабря",
+        short_months: "янв.|февр.|мар.|апр.|мая|июн.|июл.|авг.|сент.|окт.|нояб.|дек.",
+        weekdays: "понедельник|вторник|среда|четверг|пятница|суббота|воскресенье",
+        past: "{} назад",
+        future: "через {}",
+        now: "сейчас",
+        units: [
+            "{n} секунду|{n} секунды|{n} секунд",
+            "{n} минуту|{n} минуты|{n} минут",
+            "{n} час|{n} часа|{n} часов",
+            "{n} день|{n} дня|{n} дней",
+            "{n} неделю|{n} недели|{n} недель",
+            "{n} месяц|{n} месяца|{n} месяцев",
+            "{n} год|{n} года|{n} лет",
+        ],
+        plural: russian,
+    };
+
+    static TR: Language = Language {
+        months: "Ocak|Şubat|Mart|Nisan|Mayıs|Haziran|Temmuz|Ağustos|Eylül|Ekim|Kasım|Aralık",
+        short_months: "Oca|Şub|Mar|Nis|May|Haz|Tem|Ağu|Eyl|Eki|Kas|Ara",
+        weekdays: "Pazartesi|Salı|Çarşamba|Perşembe|Cuma|Cumartesi|Pazar",
+        past: "{} önce",
+        future: "{} sonra",
+        now: "şimdi",
+        units: ["{n} saniye", "{n} dakika", "{n} saat", "{n} gün", "{n} hafta", "{n} ay", "{n} yıl"],
+        plural: invariant,
+    };
+
+    static JA: Language = Language {
+        months: "1月|2月|3月|4月|5月|6月|7月|8月|9月|10月|11月|12月",
+        short_months: "1月|2月|3月|4月|5月|6月|7月|8月|9月|10月|11月|12月",
+        weekdays: "月曜日|火曜日|水曜日|木曜日|金曜日|土曜日|日曜日",
+        past: "{}前",
+        future: "{}後",
+        now: "今",
+        units: ["{n}秒", "{n}分", "{n}時間", "{n}日", "{n}週間", "{n}か月", "{n}年"],
+        plural: invariant,
+    };
+
+    static ZH: Language = Language {
+        months: "一月|二月|三月|四月|五月|六月|七月|八月|九月|十月|十一月|十二月",
+        short_months: "1月|2月|3月|4月|5月|6月|7月|8月|9月|10月|11月|12月",
+        weekdays: "星期一|星期二|星期三|星期四|星期五|星期六|星期日",
+        past: "{}前",
+        future: "{}后",
+        now: "现在",
+        units: ["{n}秒钟", "{n}分钟", "{n}小时", "{n}天", "{n}周", "{n}个月", "{n}年"],
+        plural: invariant,
+    };
+
+    static KO: Language = Language {
+        months: "1월|2월|3월|4월|5월|6월|7월|8월|9월|10월|11월|12월",
+        short_months: "1월|2월|3월|4월|5월|6월|7월|8월|9월|10월|11월|12월",
+        weekdays: "월요일|화요일|수요일|목요일|금요일|토요일|일요일",
+        past: "{} 전",
+        future: "{} 후",
+        now: "지금",
+        units: ["{n}초", "{n}분", "{n}시간", "{n}일", "{n}주", "{n}개월", "{n}년"],
+        plural: invariant,
+    };
+
+    static HI: Language = Language {
+        months: "जनवरी|फ़रवरी|मार्च|अप्रैल|मई|जून|जुलाई|अगस्त|सितंबर|अक्तूबर|नवंबर|दिसंबर",
+        short_months: "जन॰|फ़र॰|मार्च|अप्रैल|मई|जून|जुल॰|अग॰|सित॰|अक्तू॰|नव॰|दिस॰",
+        weekdays: "सोमवार|मंगलवार|बुधवार|गुरुवार|शुक्रवार|शनिवार|रविवार",
+        past: "{} पहले",
+        future: "{} में",
+        now: "अभी",
+        units: [
+            "{n} सेकंड",
+            "{n} मिनट",
+            "{n} घंटा|{n} घंटे",
+            "{n} दिन",
+            "{n} सप्ताह",
+            "{n} महीना|{n} महीने",
+            "{n} वर्ष",
+        ],
+        plural: zero_one_other,
+    };
+
+    /// Bundled locales; a bare language code picks the first region listed
+    /// for it.
+    static LOCALES: [Locale; 17] = [
+        Locale {
+            tag: "en-US",
+            language: &EN,
+            decimal: '.',
+            group: ',',
+            indian_grouping: false,
+            currency: "{s}{n}",
+            percent: "{n}%",
+            dates: ["{M}/{d}/{yy}", "{MMM} {d}, {y}", "{MMMM} {d}, {y}", "{EEEE}, {MMMM} {d}, {y}"],
+        },
+        Locale {
+            tag: "en-GB",
+            language: &EN,
+            decimal: '.',
+            group: ',',
+            indian_grouping: false,
+            currency: "{s}{n}",
+            percent: "{n}%",
+            dates: ["{dd}/{MM}/{y}", "{d} {MMM} {y}", "{d} {MMMM} {y}", "{EEEE} {d} {MMMM} {y}"],
+        },
+        Locale {
+            tag: "de-DE",
+            language: &DE,
+            decimal: ',',
+            group: '.',
+            indian_grouping: false,
+            currency: "{n}\u{a0}{s}",
+            percent: "{n}\u{a0}%",
+            dates: ["{dd}.{MM}.{yy}", "{dd}.{MM}.{y}", "{d}. {MMMM} {y}", "{EEEE}, {d}. {MMMM} {y}"],
+        },
+        Locale {
+            tag: "fr-FR",
+            language: &FR,
+            decimal: ',',
+            group: '\u{202f}',
+            indian_grouping: false,
+            currency: "{n}\u{a0}{s}",
+            percent: "{n}\u{202f}%",
+            dates: ["{dd}/{MM}/{y}", "{d} {MMM} {y}", "{d} {MMMM} {y}", "{EEEE} {d} {MMMM} {y}"],
+        },
+        Locale {
+            tag: "es-ES",
+            language: &ES,
+            decimal: ',',
+            group: '.',
+            indian_grouping: false,
+            currency: "{n}\u{a0}{s}",
+            percent: "{n}\u{a0}%",
+            dates: ["{d}/{M}/{yy}", "{d} {MMM} {y}", "{d} de {MMMM} de {y}", "{EEEE}, {d} de {MMMM} de {y}"],
+        },
+        Locale {
+            tag: "it-IT",
+            language: &IT,
+            decimal: ',',
+            group: '.',
+            indian_grouping: false,
+            currency: "{n}\u{a0}{s}",
+            percent: "{n}%",
+            dates: ["{dd}/{MM}/{yy}", "{d} {MMM} {y}", "{d} {MMMM} {y}", "{EEEE} {d} {MMMM} {y}"],
+        },
+        Locale {
+            tag: "nl-NL",
+            language: &NL,
+            decimal: ',',
+            group: '.',
+            indian_grouping: false,
+            currency: "{s}\u{a0}{n}",
+            percent: "{n}%",
+            dates: ["{dd}-{MM}-{y}", "{d} {MMM} {y}", "{d} {MMMM} {y}", "{EEEE} {d} {MMMM} {y}"],
+        },
+        Locale {
+            tag: "pt-BR",
+            language: &PT,
+            decimal: ',',
+            group: '.',
+            indian_grouping: false,
+            currency: "{s}\u{a0}{n}",
+            percent: "{n}%",
+            dates: ["{dd}/{MM}/{y}", "{d} de {MMM} de {y}", "{d} de {MMMM} de {y}", "{EEEE}, {d} de {MMMM} de {y}"],
+        },
+        Locale {
+            tag: "pt-PT",
+            language: &PT,
+            decimal: ',',
+            group: '\u{a0}',
+            indian_grouping: false,
+            currency: "{n}\u{a0}{s}",
+            percent: "{n}%",
+            dates: ["{dd}/{MM}/{yy}", "{dd}/{MM}/{y}", "{d} de {MMMM} de {y}", "{EEEE}, {d} de {MMMM} de {y}"],
+        },
+        Locale {
+            tag: "sv-SE",
+            language: &SV,
+            decimal: ',',
+            group: '\u{a0}',
+            indian_grouping: false,
+            currency: "{n}\u{a0}{s}",
+            percent: "{n}\u{a0}%",
+            dates: ["{y}-{MM}-{dd}", "{d} {MMM} {y}", "{d} {MMMM} {y}", "{EEEE} {d} {MMMM} {y}"],
+        },
+        Locale {
+            tag: "pl-PL",
+            language: &PL,
+            decimal: ',',
+            group: '\u{a0}',
+            indian_grouping: false,
+            currency: "{n}\u{a0}{s}",
+            percent: "{n}%",
+            dates: ["{d}.{MM}.{y}", "{d} {MMM} {y}", "{d} {MMMM} {y}", "{EEEE}, {d} {MMMM} {y}"],
+        },
+        Locale {
+            tag: "ru-RU",
+            language: &RU,
+            decimal: ',',
+            group: '\u{a0}',
+            indian_grouping: false,
+            currency: "{n}\u{a0}{s}",
+            percent: "{n}\u{a0}%",
+            dates: ["{dd}.{MM}.{y}", "{d} {MMM} {y} г.", "{d} {MMMM} {y} г.", "{EEEE}, {d} {MMMM} {y} г."],
+        },
+        Locale {
+            tag: "tr-TR",
+            language: &TR,
+            decimal: ',',
+            group: '.',
+            indian_grouping: false,
+            currency: "{s}{n}",
+            percent: "%{n}",
+            dates: ["{d}.{MM}.{y}", "{d} {MMM} {y}", "{d} {MMMM} {y}", "{d} {MMMM} {y} {EEEE}"],
+        },
+        Locale {
+            tag: "ja-JP",
+            language: &JA,
+            decimal: '.',
+            group: ',',
+            indian_grouping: false,
+            currency: "{s}{n}",
+            percent: "{n}%",
+            dates: ["{y}/{MM}/{dd}", "{y}/{MM}/{dd}", "{y}年{M}月{d}日", "{y}年{M}月{d}日{EEEE}"],
+        },
+        Locale {
+            tag: "zh-CN",
+            language: &ZH,
+            decimal: '.',
+            group: ',',
+            indian_grouping: false,
+            currency: "{s}{n}",
+            percent: "{n}%",
+            dates: ["{y}/{M}/{d}", "{y}年{M}月{d}日", "{y}年{M}月{d}日", "{y}年{M}月{d}日{EEEE}"],
+        },
+        Locale {
+            tag: "ko-KR",
+            language: &KO,
+            decimal: '.',
+            group: ',',
+            indian_grouping: false,
+            currency: "{s}{n}",
+            percent: "{n}%",
+            dates: ["{yy}. {M}. {d}.", "{y}. {M}. {d}.", "{y}년 {M}월 {d}일", "{y}년 {M}월 {d}일 {EEEE}"],
+        },
+        Locale {
+            tag: "hi-IN",
+            language: &HI,
+            decimal: '.',
+            group: ',',
+            indian_grouping: true,
+            currency: "{s}{n}",
+            percent: "{n}%",
+            dates: ["{d}/{M}/{yy}", "{d} {MMM} {y}", "{d} {MMMM} {y}", "{EEEE}, {d} {MMMM} {y}"],
+        },
+    ];
+
+    /// ISO 4217 code, symbol and minor digits. Codes missing here are shown
+    /// as-is with two decimals.
+    static CURRENCIES: [(&str, &str, usize); 24] = [
+        ("USD", "$", 2),
+        ("EUR", "€", 2),
+        ("GBP", "£", 2),
+        ("JPY", "¥", 0),
+        ("CNY", "¥", 2),
+        ("KRW", "₩", 0),
+        ("INR", "₹", 2),
+        ("CHF", "CHF", 2),
+        ("SEK", "kr", 2),
+        ("NOK", "kr", 2),
+        ("DKK", "kr.", 2),
+        ("PLN", "zł", 2),
+        ("CZK", "Kč", 2),
+        ("HUF", "Ft", 2),
+        ("RUB", "₽", 2),
+        ("TRY", "₺", 2),
+        ("BRL", "R$", 2),
+        ("CAD", "CA$", 2),
+        ("AUD", "A$", 2),
+        ("NZD", "NZ$", 2),
+        ("HKD", "HK$", 2),
+        ("SGD", "S$", 2),
+        ("TWD", "NT$", 2),
+        ("MXN", "MX$", 2),
+    ];
+
+    fn locale(values: &[Value], idx: usize) -> Result<&'static Locale, Error> {
+        let tag = match values.get(idx) {
+            None | Some(Value::Null) => return Ok(&LOCALES[0]),
+            Some(value) => value_to_string(value).replace('_', "-"),
+        };
+        let language = tag.split('-').next().unwrap_or_default();
+        LOCALES
+            .iter()
+            .find(|locale| locale.tag.eq_ignore_ascii_case(&tag))
+            .or_else(|| {
+                LOCALES
+                    .iter()
+                    .find(|locale| locale.tag.split('-').next().is_some_and(|l| l.eq_ignore_ascii_case(language)))
+            })
+            .ok_or_else(|| Error::Custom(format!("unknown locale: {tag}")))
+    }
+
+    fn nth(names: &str, idx: usize) -> &str {
+        names.split('|').nth(idx).unwrap_or_default()
+    }
+
+    fn number(values: &[Value], name: &str) -> Result<f64, Error> {
+        match values.first() {
+            Some(Value::Number(x)) => Ok(x.as_f64().unwrap_or_default()),
+            Some(Value::String(x)) => x
+                .trim()
+                .parse()
+                .map_err(|_| Error::Custom(format!("{name}(): not a number: {x}"))),
+            Some(other) => Err(Error::Custom(format!("{name}(): not a number: {}", value_to_string(other)))),
+            None => Err(Error::ArgumentsLess(1)),
+        }
+    }
+
+    fn decimals(values: &[Value], idx: usize, default: usize) -> usize {
+        match values.get(idx) {
+            Some(Value::Number(x)) => x.as_u64().or_else(|| x.as_f64().map(|f| f.max(0.0) as u64)).unwrap_or_default() as usize,
+            _ => default,
+        }
+    }
+
+    /// Renders `x` with between `min` and `max` fraction digits, localized.
+    fn localize(x: f64, min: usize, max: usize, grouping: bool, locale: &Locale) -> String {
+        if x.is_nan() {
+            return "NaN".into();
+        }
+        if x.is_infinite() {
+            return if x < 0.0 { "-∞" } else { "∞" }.into();
+        }
+        let mut text = format!("{:.*}", max.max(min), x);
+        if let Some(dot) = text.find('.') {
+            let keep = dot + 1 + min;
+            while text.len() > keep && text.ends_with('0') {
+                text.pop();
+            }
+            if text.ends_with('.') {
+                text.pop();
+            }
+        }
+        localize_digits(&text, grouping, locale)
+    }
+
+    /// Swaps in the locale's separators for a plain `-1234.5` style number.
+    fn localize_digits(text: &str, grouping: bool, locale: &Locale) -> String {
+        let (sign, unsigned) = match text.strip_prefix('-') {
+            Some(unsigned) if unsigned.bytes().any(|b| (b'1'..=b'9').contains(&b)) => ("-", unsigned),
+            Some(unsigned) => ("", unsigned),
+            None => ("", text),
+        };
+        let (integer, fraction) = match unsigned.split_once('.') {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (unsigned, None),
+        };
+        let integer = match (grouping, locale.indian_grouping) {
+            (false, _) => integer.to_string(),
+            (true, true) if integer.len() > 3 => {
+                let (head, tail) = integer.split_at(integer.len() - 3);
+                format!("{}{}{tail}", crate::formatting::group_digits(head, locale.group, 2), locale.group)
+            }
+            (true, _) => crate::formatting::group_digits(integer, locale.group, 3),
+        };
+        match fraction {
+            Some(fraction) => format!("{sign}{integer}{}{fraction}", locale.decimal),
+            None => format!("{sign}{integer}"),
+        }
+    }
+
+    /// `format_number(x, locale='en-US', options)`, where `options` is a
+    /// fixed number of decimals or an object with `decimals`,
+    /// `min_decimals`, `max_decimals` (default 3) and `grouping`.
+    pub(crate) fn format_number(values: &[Value]) -> Result<String, Error> {
+        let x = number(values, "format_number")?;
+        let locale = locale(values, 1)?;
+        let (mut min, mut max, mut grouping) = (0, 3, true);
+        match values.get(2) {
+            None | Some(Value::Null) => {}
+            Some(Value::Number(_)) => {
+                min = decimals(values, 2, 0);
+                max = min;
+            }
+            Some(Value::Object(options)) => {
+                if let Some(Value::Number(n)) = options.get("decimals") {
+                    min = n.as_u64().unwrap_or_default() as usize;
+                    max = min;
+                }
+                if let Some(Value::Number(n)) = options.get("min_decimals") {
+                    min = n.as_u64().unwrap_or_default() as usize;
+                }
+                if let Some(Value::Number(n)) = options.get("max_decimals") {
+                    max = n.as_u64().unwrap_or_default() as usize;
+                }
+                if let Some(Value::Bool(b)) = options.get("grouping") {
+                    grouping = *b;
+                }
+            }
+            Some(other) => {
+                return Err(Error::Custom(format!("format_number(): invalid options: {}", value_to_string(other))));
+            }
+        }
+        // Integers are kept exact rather than going through a float.
+        match values.first() {
+            Some(Value::Number(n)) if n.is_i64() || n.is_u64() => {
+                let zeros = if min > 0 { format!(".{}", "0".repeat(min)) } else { String::new() };
+                Ok(localize_digits(&format!("{n}{zeros}"), grouping, locale))
+            }
+            _ => Ok(localize(x, min, max, grouping, locale)),
+        }
+    }
+
+    /// `format_currency(x, code, locale='en-US', decimals)`.
+    pub(crate) fn format_currency(values: &[Value]) -> Result<String, Error> {
+        let x = number(values, "format_currency")?;
+        let code = match values.get(1) {
+            Some(value) => value_to_string(value).to_uppercase(),
+            None => return Err(Error::ArgumentsLess(2)),
+        };
+        let locale = locale(values, 2)?;
+        let (symbol, digits) = CURRENCIES
+            .iter()
+            .find(|(known, _, _)| *known == code)
+            .map_or((code.as_str(), 2), |(_, symbol, digits)| (*symbol, *digits));
+        let digits = decimals(values, 3, digits);
+        let amount = localize(x.abs(), digits, digits, true, locale);
+        let text = locale.currency.replace("{s}", symbol).replace("{n}", &amount);
+        let zero = !amount.chars().any(|c| ('1'..='9').contains(&c));
+        Ok(if x < 0.0 && !zero { format!("-{text}") } else { text })
+    }
+
+    /// `format_percent(x, locale='en-US', decimals=0)`, where 1 is 100%.
+    pub(crate) fn format_percent(values: &[Value]) -> Result<String, Error> {
+        let x = number(values, "format_percent")?;
+        let locale = locale(values, 1)?;
+        let digits = decimals(values, 2, 0);
+        let amount = localize(x * 100.0, digits, digits, true, locale);
+        Ok(locale.percent.replace("{n}", &amount))
+    }
+
+    /// Reads unix seconds or an ISO 8601 string; strings without an offset
+    /// are wall-clock times in `tz`.
+    fn timestamp(value: Option<&Value>, tz: chrono_tz::Tz) -> Result<DateTime<chrono_tz::Tz>, Error> {
+        let parsed = match value {
+            Some(Value::Number(x)) => match x.as_i64() {
+                Some(seconds) => DateTime::from_timestamp(seconds, 0),
+                None => x.as_f64().and_then(|f| DateTime::from_timestamp_millis((f * 1000.0).round() as i64)),
+            }
+            .map(|dt| dt.with_timezone(&tz)),
+            Some(Value::String(text)) => {
+                let text = text.trim();
+                DateTime::parse_from_rfc3339(text)
+                    .map(|dt| dt.with_timezone(&tz))
+                    .ok()
+                    .or_else(|| {
+                        NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S")
+                            .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S"))
+                            .ok()
+                            .or_else(|| NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?.and_hms_opt(0, 0, 0))
+                            .and_then(|naive| tz.from_local_datetime(&naive).earliest())
+                    })
+            }
+            _ => None,
+        };
+        parsed.ok_or_else(|| {
+            let shown = value.map(value_to_string).unwrap_or_default();
+            Error::Custom(format!("invalid timestamp: {shown}"))
+        })
+    }
+
+    fn render_date(pattern: &str, dt: &DateTime<chrono_tz::Tz>, language: &Language) -> String {
+        let mut result = String::new();
+        let mut rest = pattern;
+        while let Some(start) = rest.find('{') {
+            result.push_str(&rest[..start]);
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            let token = &rest[start + 1..start + len];
+            let month = dt.month0() as usize;
+            let text = match token {
+                "y" => dt.year().to_string(),
+                "yy" => format!("{:02}", dt.year().rem_euclid(100)),
+                "M" => dt.month().to_string(),
+                "MM" => format!("{:02}", dt.month()),
+                "MMM" => nth(language.short_months, month).to_string(),
+                "MMMM" => nth(language.months, month).to_string(),
+                "d" => dt.day().to_string(),
+                "dd" => format!("{:02}", dt.day()),
+                "EEEE" => nth(language.weekdays, dt.weekday().num_days_from_monday() as usize).to_string(),
+                "H" => dt.hour().to_string(),
+                "HH" => format!("{:02}", dt.hour()),
+                "mm" => format!("{:02}", dt.minute()),
+                "ss" => format!("{:02}", dt.second()),
+                _ => rest[start..=start + len].to_string(),
+            };
+            result.push_str(&text);
+            rest = &rest[start + len + 1..];
+        }
+        result.push_str(rest);
+        result
+    }
+
+    /// `format_date(ts, style='medium', locale='en-US', tz='UTC')`; `style`
+    /// is short, medium, long, full, or a pattern such as `{d} {MMMM}`.
+    pub(crate) fn format_date(values: &[Value]) -> Result<String, Error> {
+        let tz = match values.get(3) {
+            None | Some(Value::Null) => chrono_tz::UTC,
+            Some(value) => {
+                let name = value_to_string(value);
+                name.parse().map_err(|_| Error::Custom(format!("unknown timezone: {name}")))?
+            }
+        };
+        let dt = timestamp(values.first(), tz)?;
+        let locale = locale(values, 2)?;
+        let style = match values.get(1) {
+            None | Some(Value::Null) => "medium".to_string(),
+            Some(value) => value_to_string(value),
+        };
+        let pattern = match style.as_str() {
+            "short" => locale.dates[0],
+            "medium" => locale.dates[1],
+            "long" => locale.dates[2],
+            "full" => locale.dates[3],
+            custom if custom.contains('{') => custom,
+            _ => return Err(Error::Custom(format!("format_date(): unknown style: {style}"))),
+        };
+        Ok(render_date(pattern, &dt, locale.language))
+    }
+
+    /// `format_relative(ts, locale='en-US', now)`, e.g. "3 days ago" or
+    /// "in 2 hours"; `now` defaults to the current time.
+    pub(crate) fn format_relative(values: &[Value]) -> Result<String, Error> {
+        let then = timestamp(values.first(), chrono_tz::UTC)?;
+        let locale = locale(values, 1)?;
+        let now = match values.get(2) {
+            None | Some(Value::Null) => Utc::now().with_timezone(&chrono_tz::UTC),
+            value => timestamp(value, chrono_tz::UTC)?,
+        };
+        let seconds = (then - now).num_seconds();
+        let elapsed = seconds.unsigned_abs();
+        let (count, unit) = match elapsed {
+            0 => return Ok(locale.language.now.to_string()),
+            1..60 => (elapsed, 0),
+            60..3_600 => (elapsed / 60, 1),
+            3_600..86_400 => (elapsed / 3_600, 2),
+            86_400..604_800 => (elapsed / 86_400, 3),
+            604_800..2_592_000 => (elapsed / 604_800, 4),
+            2_592_000..31_536_000 => (elapsed / 2_592_000, 5),
+            _ => (elapsed / 31_536_000, 6),
+        };
+        let forms = locale.language.units[unit];
+        let form = (locale.language.plural)(count);
+        let phrase = forms
+            .split('|')
+            .nth(form)
+            .or_else(|| forms.split('|').next_back())
+            .unwrap_or_default()
+            .replace("{n}", &count.to_string());
+        let wrapper = if seconds < 0 { locale.language.past } else { locale.language.future };
+        Ok(wrapper.replace("{}", &phrase))
+    }
+}
+
+/// Evaluates arithmetic outside of the resolver. Its operators always go
+/// through `f64`/`i64`, so when `EvalConfig::arithmetic` asks for something
+/// else the expression is rewritten to call hidden built-ins instead, e.g.
+/// `$.a[0] + 1 > 2` becomes `__gt(__add(__index($.a, 0), 1), 2)`.
+mod arithmetic {
+    use std::str::FromStr;
+
+    use std::num::NonZeroU64;
+
+    use bigdecimal::{BigDecimal, RoundingMode, ToPrimitive, Zero};
+    use num_bigint::{BigInt, Sign};
+    use resolver::Error;
+
+    use crate::types::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(crate) enum Operator {
+        Add,
+        Sub,
+        Mul,
+        Div,
+        Rem,
+        Pow,
+        Neg,
+        Eq,
+        Ne,
+        Lt,
+        Gt,
+        Le,
+        Ge,
+    }
+
+    impl Operator {
+        fn symbol(self) -> &'static str {
+            match self {
+                Operator::Add => "+",
+                Operator::Sub | Operator::Neg => "-",
+                Operator::Mul => "*",
+                Operator::Div => "/",
+                Operator::Rem => "%",
+                Operator::Pow => "**",
+                Operator::Eq => "==",
+                Operator::Ne => "!=",
+                Operator::Lt => "<",
+                Operator::Gt => ">",
+                Operator::Le => "<=",
+                Operator::Ge => ">=",
+            }
+        }
+    }
+
+    /// Hidden built-ins the rewritten operators call.
+    pub(crate) const OPERATORS: [(&str, Operator); 13] = [
+        ("__add", Operator::Add),
+        ("__sub", Operator::Sub),
+        ("__mul", Operator::Mul),
+        ("__div", Operator::Div),
+        ("__rem", Operator::Rem),
+        ("__pow", Operator::Pow),
+        ("__neg", Operator::Neg),
+        ("__eq", Operator::Eq),
+        ("__ne", Operator::Ne),
+        ("__lt", Operator::Lt),
+        ("__gt", Operator::Gt),
+        ("__le", Operator::Le),
+        ("__ge", Operator::Ge),
+    ];
+
+    /// Stands in for `x[i]`, which the resolver rejects inside function
+    /// arguments.
+    pub(crate) const INDEX: &str = "__index";
+
+    /// Binding power of the binary operators, as the resolver ranks them.
+    fn binary(token: &str) -> Option<u8> {
+        Some(match token {
+            "??" => 1,
+            "||" => 2,
+            "|" => 3,
+            "&&" => 4,
+            "^" => 5,
+            "==" | "!=" | "<" | ">" | "<=" | ">=" | "in" | "not in" => 6,
+            "&" => 7,
+            "+" | "-" => 8,
+            "<<" | ">>" => 9,
+            "*" | "/" | "%" => 10,
+            "**" => 12,
+            _ => return None,
+        })
+    }
+
+    fn hidden(token: &str) -> Option<&'static str> {
+        Some(match token {
+            "+" => "__add",
+            "-" => "__sub",
+            "*" => "__mul",
+            "/" => "__div",
+            "%" => "__rem",
+            "**" => "__pow",
+            "==" => "__eq",
+            "!=" => "__ne",
+            "<" => "__lt",
+            ">" => "__gt",
+            "<=" => "__le",
+            ">=" => "__ge",
+            _ => return None,
+        })
+    }
+
+    const PUNCTUATION: [&str; 27] = [
+        "**", "==", "!=", "<=", ">=", "<<", ">>", "&&", "||", "??", "(", ")", "[", "]", ",", ".", "+", "-", "*",
+        "/", "%", "<", ">", "!", "&", "|", "^",
+    ];
+
+    fn is_delimiter(c: char) -> bool {
+        c.is_whitespace() || "()+-*/,!=><'\"[].%&|?^~".contains(c)
+    }
+
+    /// Rewritten text, and whether it can be used as an operand as is.
+    struct Operand {
+        text: String,
+        atomic: bool,
+    }
+
+    impl Operand {
+        fn atom(text: String) -> Operand {
+            Operand { text, atomic: true }
+        }
+
+        fn grouped(&self) -> String {
+            if self.atomic { self.text.clone() } else { format!("({})", self.text) }
+        }
+    }
+
+    struct Parser<'a> {
+        source: &'a str,
+        pos: usize,
+    }
+
+    impl<'a> Parser<'a> {
+        fn error(&self, message: &str) -> Error {
+            Error::Custom(format!("{message} at offset {} of `{}`", self.pos, self.source))
+        }
+
+        fn peek(&mut self) -> Option<&'a str> {
+            let rest = self.source[self.pos..].trim_start();
+            self.pos = self.source.len() - rest.len();
+            let first = rest.chars().next()?;
+            if first == '\'' || first == '"' {
+                let end = rest[1..].find(first).map_or(rest.len(), |end| end + 2);
+                return Some(&rest[..end]);
+            }
+            if let Some(punctuation) = PUNCTUATION.iter().find(|p| rest.starts_with(**p)) {
+                return Some(punctuation);
+            }
+            if first == '~' || first == '?' || first == '=' {
+                return Some(&rest[..1]);
+            }
+            let end = rest.find(is_delimiter).unwrap_or(rest.len());
+            Some(&rest[..end])
+        }
+
+        fn next(&mut self) -> Option<&'a str> {
+            let token = self.peek()?;
+            self.pos += token.len();
+            Some(token)
+        }
+
+        fn expect(&mut self, expected: &str) -> Result<(), Error> {
+            match self.next() {
+                Some(token) if token == expected => Ok(()),
+                _ => Err(self.error(&format!("expected `{expected}`"))),
+            }
+        }
+
+        /// `not in` is the only operator spelt with two words.
+        fn peek_binary(&mut self) -> Option<(&'static str, u8)> {
+            let token = self.peek()?;
+            if token == "not" {
+                let start = self.pos;
+                self.pos += token.len();
+                let following = self.peek();
+                self.pos = start;
+                return (following == Some("in")).then_some(("not in", 6));
+            }
+            let bp = binary(token)?;
+            let known = PUNCTUATION.iter().copied().chain(["in"]).find(|p| *p == token)?;
+            Some((known, bp))
+        }
+
+        fn expression(&mut self, min_bp: u8) -> Result<Operand, Error> {
+            let mut lhs = self.prefix()?;
+            while let Some((operator, bp)) = self.peek_binary() {
+                if bp <= min_bp {
+                    break;
+                }
+                if operator == "not in" {
+                    self.next();
+                }
+                self.next();
+                let rhs = self.expression(bp)?;
+                lhs = match hidden(operator) {
+                    Some(name) => Operand::atom(format!("{name}({}, {})", lhs.text, rhs.text)),
+                    None => Operand {
+                        text: format!("{} {operator} {}", lhs.grouped(), rhs.grouped()),
+                        atomic: false,
+                    },
+                };
+            }
+            Ok(lhs)
+        }
+
+        fn prefix(&mut self) -> Result<Operand, Error> {
+            let Some(token) = self.next() else {
+                return Err(self.error("expected a value"));
+            };
+            let operand = match token {
+                "-" => {
+                    let operand = self.expression(11)?;
+                    return Ok(Operand::atom(format!("__neg({})", operand.text)));
+                }
+                "!" | "~" => {
+                    let operand = self.expression(99)?;
+                    return Ok(Operand { text: format!("{token}{}", operand.grouped()), atomic: false });
+                }
+                "(" => {
+                    let inner = self.expression(0)?;
+                    self.expect(")")?;
+                    Operand::atom(format!("({})", inner.text))
+                }
+                _ if token.starts_with(['\'', '"']) => Operand::atom(token.to_string()),
+                _ if token.starts_with(is_delimiter) => return Err(self.error(&format!("unexpected `{token}`"))),
+                _ if self.peek() == Some("(") => {
+                    self.next();
+                    let mut arguments = Vec::new();
+                    if self.peek() == Some(")") {
+                        self.next();
+                    } else {
+                        loop {
+                            arguments.push(self.expression(0)?.text);
+                            match self.next() {
+                                Some(",") => continue,
+                                Some(")") => break,
+                                _ => return Err(self.error("expected `,` or `)`")),
+                            }
+                        }
+                    }
+                    Operand::atom(format!("{token}({})", arguments.join(", ")))
+                }
+                _ => Operand::atom(token.to_string()),
+            };
+            self.postfix(operand)
+        }
+
+        fn postfix(&mut self, mut operand: Operand) -> Result<Operand, Error> {
+            loop {
+                match self.peek() {
+                    Some(".") => {
+                        let mut dots = String::new();
+                        while self.peek() == Some(".") {
+                            self.next();
+                            dots.push('.');
+                        }
+                        match self.next() {
+                            Some(word) if !word.starts_with(is_delimiter) => {
+                                operand.text = format!("{}{dots}{word}", operand.grouped());
+                                operand.atomic = true;
+                            }
+                            _ => return Err(self.error("expected a name after `.`")),
+                        }
+                    }
+                    Some("[") => {
+                        self.next();
+                        let index = self.expression(0)?;
+                        self.expect("]")?;
+                        operand = Operand::atom(format!("{INDEX}({}, {})", operand.text, index.text));
+                    }
+                    _ => return Ok(operand),
+                }
+            }
+        }
+    }
+
+    /// Rewrites the operators of `expression` into calls of `OPERATORS`.
+    pub(crate) fn rewrite(expression: &str) -> Result<String, Error> {
+        let mut parser = Parser { source: expression, pos: 0 };
+        let operand = parser.expression(0)?;
+        match parser.peek() {
+            None => Ok(operand.text),
+            Some(token) => Err(parser.error(&format!("unexpected `{token}`"))),
+        }
+    }
+
+    /// Replaces the words the resolver would read as a NaN or infinite float,
+    /// such as `NAN`, `INFINITY` and `1e999`, with the strings `'NaN'`, `'inf'`
+    /// and `'-inf'` that stand for them, as JSON has no such numbers.
+    pub(crate) fn non_finite_literals(expression: &str) -> String {
+        let mut parser = Parser { source: expression, pos: 0 };
+        let mut result = String::with_capacity(expression.len());
+        let mut copied = 0;
+        while let Some(token) = parser.next() {
+            let start = parser.pos - token.len();
+            let sentinel = match token.parse::<f64>() {
+                Ok(x) if x.is_finite() || parser.peek() == Some("(") => None,
+                Ok(x) if x.is_nan() => Some("'NaN'"),
+                Ok(x) if x > 0.0 => Some("'inf'"),
+                Ok(_) => Some("'-inf'"),
+                Err(_) => None,
+            };
+            if let Some(sentinel) = sentinel {
+                result.push_str(&expression[copied..start]);
+                result.push_str(sentinel);
+                copied = start + token.len();
+            }
+        }
+        result.push_str(&expression[copied..]);
+        result
+    }
+
+    /// `__index(x, i)`, with the resolver's semantics for `x[i]`.
+    pub(crate) fn index(values: &[Value]) -> Result<Value, Error> {
+        let (container, key) = match values {
+            [container, key] => (container, key),
+            _ => return Err(Error::ArgumentsLess(2)),
+        };
+        match (container, key) {
+            (Value::Null, _) => Ok(Value::Null),
+            (Value::Object(map), Value::String(key)) => Ok(map.get(key).cloned().unwrap_or(Value::Null)),
+            (Value::Object(_), _) => Err(Error::ExpectedIdentifier),
+            (Value::Array(items), Value::Number(n)) => match (n.as_u64(), n.as_i64()) {
+                (Some(idx), _) => Ok(usize::try_from(idx).ok().and_then(|idx| items.get(idx)).cloned().unwrap_or(Value::Null)),
+                (None, Some(idx)) => Err(Error::Custom(format!("array index must be non-negative, got {idx}"))),
+                _ => Err(Error::ExpectedNumber),
+            },
+            (Value::Array(_), _) => Err(Error::ExpectedNumber),
+            (Value::String(_), _) => Ok(Value::Null),
+            _ => Err(Error::ExpectedArray),
+        }
+    }
+
+    /// The resolver's behaviour for operands that are not both numbers.
+    pub(crate) fn non_numeric(operator: Operator, a: &Value, b: &Value) -> Result<Value, Error> {
+        match (operator, a, b) {
+            (Operator::Add, Value::String(a), Value::String(b)) => Ok(to_value(format!("{a}{b}"))),
+            (Operator::Eq, _, _) => Ok(to_value(a == b)),
+            (Operator::Ne, _, _) => Ok(to_value(a != b)),
+            (Operator::Lt | Operator::Gt | Operator::Le | Operator::Ge, _, _) if a.is_null() || b.is_null() => {
+                Ok(to_value(false))
+            }
+            (Operator::Neg, _, _) => Err(Error::ExpectedNumber),
+            _ => Err(Error::UnsupportedTypes(format!("{a:?}"), format!("{b:?}"))),
+        }
+    }
+
+    /// Significant digits of `dec_div` quotients, as in Python's `decimal`
+    /// module.
+    const PRECISION: u64 = 28;
+
+    /// Reads a number as the decimal it was written as: floats go through
+    /// their shortest round-tripping representation, so `0.1` is exactly
+    /// one tenth.
+    pub(crate) fn to_decimal(value: &Value) -> Result<BigDecimal, Error> {
+        let text = match value {
+            Value::Number(n) if n.is_i64() || n.is_u64() => n.to_string(),
+            Value::Number(n) => n.as_f64().unwrap_or_default().to_string(),
+            Value::String(s) => s.trim().to_string(),
+            _ => return Err(Error::ExpectedNumber),
+        };
+        BigDecimal::from_str(&text).map_err(|_| Error::Custom(format!("decimal: cannot represent {text}")))
+    }
+
+    /// Whether `value`, read as `decimal`, was written as an integer.
+    pub(crate) fn is_integral(value: &Value, decimal: &BigDecimal) -> bool {
+        match value {
+            Value::Number(n) => n.is_i64() || n.is_u64(),
+            _ => decimal.fractional_digit_count() <= 0,
+        }
+    }
+
+    /// Converts back to the nearest JSON number, an integer if `integer` and
+    /// it fits.
+    pub(crate) fn from_decimal(decimal: &BigDecimal, integer: bool) -> Value {
+        if integer && decimal.is_integer() {
+            let whole = decimal.with_scale(0);
+            if let Some(n) = whole.to_i64() {
+                return to_value(n);
+            }
+            if let Some(n) = whole.to_u64() {
+                return to_value(n);
+            }
+        }
+        to_value(decimal.to_scientific_notation().parse::<f64>().unwrap_or(f64::NAN))
+    }
+
+    /// `dec_add`, `dec_sub`, `dec_mul` and `dec_div` on values read by
+    /// `to_decimal`, so `dec_add(0.1, 0.2)` is `0.3`. Sums, differences and
+    /// products are exact, quotients keep `PRECISION` significant digits, and
+    /// the result is always a JSON number from `from_decimal`: an integer when
+    /// both arguments are and it fits, the nearest float otherwise. The
+    /// comparison operators give `dec_cmp`'s -1, 0 or 1.
+    pub(crate) fn decimal(operator: Operator, values: &[Value]) -> Result<Value, Error> {
+        let (a, b) = match values {
+            [a, b] => (a, b),
+            _ => return Err(Error::ArgumentsLess(2)),
+        };
+        let (x, y) = (to_decimal(a)?, to_decimal(b)?);
+        let integer = is_integral(a, &x) && is_integral(b, &y);
+        let result = match operator {
+            Operator::Add => x + y,
+            Operator::Sub => x - y,
+            Operator::Mul => x * y,
+            Operator::Div if y.is_zero() => return Err(Error::DivisionByZero),
+            Operator::Div => {
+                let precision = NonZeroU64::new(PRECISION).expect("precision is not zero");
+                return Ok(from_decimal(&(x / y).with_precision_round(precision, RoundingMode::HalfEven), false));
+            }
+            _ => return Ok(to_value(x.cmp(&y) as i8)),
+        };
+        Ok(from_decimal(&result, integer))
+    }
+
+    /// `round_half_even(x, n=0)` and `round_half_up(x, n=0)`; a negative `n`
+    /// rounds to tens, hundreds and so on.
+    pub(crate) fn round(values: &[Value], mode: RoundingMode) -> Result<Value, Error> {
+        let Some(value) = values.first() else {
+            return Err(Error::ArgumentsLess(1));
+        };
+        let x = to_decimal(value)?;
+        let digits = match values.get(1) {
+            None | Some(Value::Null) => 0,
+            Some(Value::Number(n)) => n.as_i64().ok_or(Error::ExpectedNumber)?.clamp(-4096, 4096),
+            Some(_) => return Err(Error::ExpectedNumber),
+        };
+        Ok(from_decimal(&x.with_scale_round(digits, mode), is_integral(value, &x)))
+    }
+
+    pub(crate) fn as_i128(value: &Value) -> Option<i128> {
+        match value {
+            Value::Number(n) => n.as_i64().map(i128::from).or_else(|| n.as_u64().map(i128::from)),
+            _ => None,
+        }
+    }
+
+    /// A JSON number for `n`, if it fits in `i64` or `u64`.
+    pub(crate) fn from_i128(n: i128) -> Option<Value> {
+        i64::try_from(n).map(to_value).or_else(|_| u64::try_from(n).map(to_value)).ok()
+    }
+
+    /// Operators of `Arithmetic::Checked`.
+    pub(crate) fn checked(operator: Operator, values: &[Value]) -> Result<Value, Error> {
+        let overflow = |shown: String| Error::Custom(format!("integer overflow: {shown}"));
+        if operator == Operator::Neg {
+            return match values.first() {
+                Some(value @ Value::Number(n)) => match as_i128(value) {
+                    Some(x) => from_i128(-x).ok_or_else(|| overflow(format!("-{x}"))),
+                    None => Ok(to_value(-n.as_f64().unwrap_or_default())),
+                },
+                _ => Err(Error::ExpectedNumber),
+            };
+        }
+        let (a, b) = match values {
+            [a, b] => (a, b),
+            _ => return Err(Error::ArgumentsLess(2)),
+        };
+        if !a.is_number() || !b.is_number() {
+            return non_numeric(operator, a, b);
+        }
+        if let (Some(x), Some(y)) = (as_i128(a), as_i128(b)) {
+            let result = match operator {
+                Operator::Add => x.checked_add(y),
+                Operator::Sub => x.checked_sub(y),
+                Operator::Mul => x.checked_mul(y),
+                Operator::Rem if y == 0 => return Err(Error::ModuloByZero),
+                Operator::Rem => x.checked_rem(y),
+                Operator::Eq => return Ok(to_value(x == y)),
+                Operator::Ne => return Ok(to_value(x != y)),
+                Operator::Lt => return Ok(to_value(x < y)),
+                Operator::Gt => return Ok(to_value(x > y)),
+                Operator::Le => return Ok(to_value(x <= y)),
+                Operator::Ge => return Ok(to_value(x >= y)),
+                // Division and powers give floats, as in `Arithmetic::Float`.
+                Operator::Div | Operator::Pow | Operator::Neg => None,
+            };
+            if !matches!(operator, Operator::Div | Operator::Pow) {
+                return result
+                    .and_then(from_i128)
+                    .ok_or_else(|| overflow(format!("{x} {} {y}", operator.symbol())));
+            }
+        }
+        let (x, y) = (a.as_f64().unwrap_or_default(), b.as_f64().unwrap_or_default());
+        Ok(match operator {
+            Operator::Add => to_value(x + y),
+            Operator::Sub => to_value(x - y),
+            Operator::Mul => to_value(x * y),
+            Operator::Div if y == 0.0 => return Err(Error::DivisionByZero),
+            Operator::Div => to_value(x / y),
+            Operator::Rem if y == 0.0 => return Err(Error::ModuloByZero),
+            Operator::Rem => to_value(x % y),
+            Operator::Pow => to_value(x.powf(y)),
+            Operator::Eq => to_value(x == y),
+            Operator::Ne => to_value(x != y),
+            Operator::Lt => to_value(x < y),
+            Operator::Gt => to_value(x > y),
+            Operator::Le => to_value(x <= y),
+            Operator::Ge => to_value(x >= y),
+            Operator::Neg => unreachable!(),
+        })
+    }
+
+    /// Reads an integer number, an integral float or a string of digits.
+    pub(crate) fn to_bigint(value: &Value) -> Result<BigInt, Error> {
+        match value {
+            Value::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+                (Some(x), _, _) => Ok(BigInt::from(x)),
+                (_, Some(x), _) => Ok(BigInt::from(x)),
+                (_, _, Some(x)) if x.is_finite() && x.fract() == 0.0 => {
+                    Ok(format!("{x:.0}").parse().map_err(|_| Error::ExpectedNumber)?)
+                }
+                _ => Err(Error::Custom(format!("bigint: {n} is not an integer"))),
+            },
+            Value::String(s) => {
+                let digits = s.trim();
+                digits
+                    .strip_prefix('+')
+                    .unwrap_or(digits)
+                    .parse()
+                    .map_err(|_| Error::Custom(format!("bigint: invalid integer '{s}'")))
+            }
+            _ => Err(Error::ExpectedNumber),
+        }
+    }
+
+    /// `bigint_add` and friends, on values read by `to_bigint`. Results are
+    /// strings of digits, as JSON numbers cannot hold them; `bigint_div` and
+    /// `bigint_mod` round towards negative infinity, as Python does. The
+    /// comparison operators give `bigint_cmp`'s -1, 0 or 1.
+    pub(crate) fn bigint(operator: Operator, values: &[Value]) -> Result<Value, Error> {
+        let (a, b) = match values {
+            [a, b] => (to_bigint(a)?, to_bigint(b)?),
+            _ => return Err(Error::ArgumentsLess(2)),
+        };
+        let floor = |a: &BigInt, b: &BigInt| {
+            let (quotient, remainder) = (a / b, a % b);
+            if remainder.sign() != Sign::NoSign && remainder.sign() != b.sign() {
+                (quotient - 1, remainder + b)
+            } else {
+                (quotient, remainder)
+            }
+        };
+        let result = match operator {
+            Operator::Add => a + b,
+            Operator::Sub => a - b,
+            Operator::Mul => a * b,
+            Operator::Div if b == BigInt::ZERO => return Err(Error::DivisionByZero),
+            Operator::Div => floor(&a, &b).0,
+            Operator::Rem if b == BigInt::ZERO => return Err(Error::ModuloByZero),
+            Operator::Rem => floor(&a, &b).1,
+            Operator::Pow => match u32::try_from(&b) {
+                Ok(exponent) if exponent <= 65_536 => a.pow(exponent),
+                _ => return Err(Error::Custom("bigint_pow: exponent must be between 0 and 65536".into())),
+            },
+            _ => return Ok(to_value(a.cmp(&b) as i8)),
+        };
+        Ok(to_value(result.to_string()))
+    }
+}
+
+
+#[cfg(test)]
+mod eval {
+    use chrono::offset::Utc as Date;
+    use chrono::{Datelike, Timelike};
+    use resolver::to_value;
+    use serde_json::json;
+
+    use crate::{eval_wrapper::{EvalConfig, ExprWrapper}, template};
+
+    #[derive(Default)]
+    struct Spec {
+        config: EvalConfig,
+    }
+
+    impl Spec {
+        pub fn eval<S: AsRef<str>>(&self, expression: S) -> resolver::Value {
+            let mut expr = ExprWrapper::new(expression.as_ref())
+                .config(self.config.clone())
+                .init();
+            let result = expr.exec();
+
+            if result.is_err() {
+                panic!(
+                    "Failed to parse expression: \"{}\" {:?}",
+                    expression.as_ref(),
+                    result
+                )
+            }
+
+            result.unwrap()
+        }
+    }
+
+    #[test]
+    fn maths_consts() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("NAN"), to_value("NaN"));
+        assert_eq!(user_spec.eval("INFINITY"), to_value("inf"));
+        assert_eq!(user_spec.eval("NEG_INFINITY"), to_value("-inf"));
+        assert_eq!(user_spec.eval("maths.INC"), to_value("NaN"));
+        assert_eq!(user_spec.eval("maths.NOT_A_NUMBER"), to_value("NaN"));
+        assert_eq!(user_spec.eval("maths.INFINITE"), to_value("inf"));
+        assert_eq!(user_spec.eval("maths.NEG_INFINITE"), to_value("-inf"));
+
+        let user_spec = Spec { config: EvalConfig { include_predicates: false, ..Default::default() } };
+        assert_eq!(user_spec.eval("NAN"), to_value(f64::NAN));
+        assert_eq!(user_spec.eval("INFINITY"), to_value(f64::INFINITY));
+        assert_eq!(user_spec.eval("NEG_INFINITY"), to_value(f64::NEG_INFINITY));
+        assert_eq!(user_spec.eval("maths.MAX_INT"), to_value(i64::MAX));
+        assert_eq!(user_spec.eval("maths.MAX_FLOAT"), to_value(f64::MAX));
+        assert_eq!(user_spec.eval("maths.MIN_FLOAT"), to_value(f64::MIN));
+        assert_eq!(user_spec.eval("maths.INC"), to_value(f64::NAN));
+        assert_eq!(user_spec.eval("maths.NOT_A_NUMBER"), to_value(f64::NAN));
+        assert_eq!(user_spec.eval("maths.INFINITE"), to_value(f64::INFINITY));
+        assert_eq!(user_spec.eval("maths.NEG_INFINITE"), to_value(f64::NEG_INFINITY));
+        assert_eq!(user_spec.eval("maths.E"), to_value(std::f64::consts::E));
+        assert_eq!(user_spec.eval("maths.FRAC_1_SQRT_2"), to_value(std::f64::consts::FRAC_1_SQRT_2));
+        assert_eq!(user_spec.eval("maths.FRAC_2_SQRT_PI"), to_value(std::f64::consts::FRAC_2_SQRT_PI));
+        assert_eq!(user_spec.eval("maths.FRAC_1_PI"), to_value(std::f64::consts::FRAC_1_PI));
+        assert_eq!(user_spec.eval("maths.FRAC_PI_2"), to_value(std::f64::consts::FRAC_PI_2));
+        assert_eq!(user_spec.eval("maths.FRAC_PI_3"), to_value(std::f64::consts::FRAC_PI_3));
+        assert_eq!(user_spec.eval("maths.FRAC_PI_4"), to_value(std::f64::consts::FRAC_PI_4));
+        assert_eq!(user_spec.eval("maths.FRAC_PI_6"), to_value(std::f64::consts::FRAC_PI_6));
+        assert_eq!(user_spec.eval("maths.FRAC_PI_8"), to_value(std::f64::consts::FRAC_PI_8));
+        assert_eq!(user_spec.eval("maths.LN_2"), to_value(std::f64::consts::LN_2));
+        assert_eq!(user_spec.eval("maths.LN_10"), to_value(std::f64::consts::LN_10));
+        assert_eq!(user_spec.eval("maths.LOG2_10"), to_value(std::f64::consts::LOG2_10));
+        assert_eq!(user_spec.eval("maths.LOG2_E"), to_value(std::f64::consts::LOG2_E));
+        assert_eq!(user_spec.eval("maths.LOG10_2"), to_value(std::f64::consts::LOG10_2));
+        assert_eq!(user_spec.eval("maths.LOG10_E"), to_value(std::f64::consts::LOG10_E));
+        assert_eq!(user_spec.eval("maths.PI"), to_value(std::f64::consts::PI));
+        assert_eq!(user_spec.eval("maths.SQRT_2"), to_value(std::f64::consts::SQRT_2));
+        assert_eq!(user_spec.eval("maths.TAU"), to_value(std::f64::consts::TAU));
+    }
+
+    #[test]
+    fn maths_round() {
+        let user_spec = Spec::default();
+
+        assert_eq!(user_spec.eval("round(2.5)"), to_value(3.0));
+        assert_eq!(user_spec.eval("round(1.23456, 3)"), to_value(1.235));
+        assert_eq!(user_spec.eval("round(1234.5, 0-2)"), to_value(1200.0));
+        assert!(ExprWrapper::new("round('a')").init().exec().is_err());
+    }
+
+    #[test]
+    fn literal() {
+        let user_spec = Spec::default();
+
+        assert_eq!(user_spec.eval("42"), 42);
+        assert_eq!(user_spec.eval("0-42"), -42);
+        assert_eq!(user_spec.eval("true"), true);
+        assert_eq!(user_spec.eval("false"), false);
+        assert_eq!(user_spec.eval("\"42\""), "42");
+        assert_eq!(user_spec.eval("'42'"), "42");
+        assert_eq!(user_spec.eval("array(42, 42)"), to_value(vec![42; 2]));
+        assert_eq!(user_spec.eval("array()"), to_value(vec![0; 0]));
+        assert_eq!(user_spec.eval("0..5"), to_value(vec![0, 1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn _str() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("str(42)"), "42");
+        assert_eq!(user_spec.eval("str(42.42)"), "42.42");
+        assert_eq!(user_spec.eval("str(true)"), "true");
+        assert_eq!(user_spec.eval("str(array(42, 42))"), to_value("[42,42]"));
+        assert_eq!(user_spec.eval("str(array())"), to_value("[]"));
+        assert_eq!(user_spec.eval("str(null)"), to_value("null"));
+    }
+
+    #[test]
+    fn bool() {
+        let user_spec = Spec::default();
+
+        assert_eq!(user_spec.eval("bool(1)"), true);
+        assert_eq!(user_spec.eval("bool(1.0)"), true);
+        assert_eq!(user_spec.eval("bool(0)"), false);
+        assert_eq!(user_spec.eval("bool(0.0)"), false);
+        assert_eq!(user_spec.eval("bool(true)"), true);
+        assert_eq!(user_spec.eval("bool(false)"), false);
+
+        assert_eq!(user_spec.eval("bool(42)"), true);
+        assert_eq!(user_spec.eval("bool(42.42)"), true);
+        assert_eq!(user_spec.eval("bool(0-42)"), true);
+        assert_eq!(user_spec.eval("bool(0-42.42)"), true);
+
+        assert_eq!(user_spec.eval("bool('')"), false);
+        assert_eq!(user_spec.eval("bool(\"\")"), false);
+        assert_eq!(user_spec.eval("bool('42')"), true);
+        assert_eq!(user_spec.eval("bool(\"42\")"), true);
+
+        assert_eq!(user_spec.eval("bool(array(42, 42))"), true);
+        assert_eq!(user_spec.eval("bool(array())"), false);
+        assert_eq!(user_spec.eval("bool(0..42)"), true);
+        assert_eq!(user_spec.eval("bool(0..0)"), false);
+        assert_eq!(user_spec.eval("bool(null)"), false);
+    }
+
+    #[test]
+    fn float() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("float('')"), to_value("NaN"));
+        assert_eq!(user_spec.eval("float(null)"), to_value("NaN"));
+        assert_eq!(user_spec.eval("float('-infinity')"), to_value("-inf"));
+        assert_eq!(user_spec.eval("float('1e999')"), to_value("inf"));
+
+        let user_spec = Spec { config: EvalConfig { include_predicates: false, ..Default::default() } };
+        assert_eq!(user_spec.eval("float(42)"), 42.0);
+        assert_eq!(user_spec.eval("float(42.42)"), 42.42);
+        assert_eq!(user_spec.eval("float('42.42')"), 42.42);
+        assert_eq!(user_spec.eval("float('42')"), 42.0);
+        assert_eq!(user_spec.eval("float(true)"), 1.0);
+        assert_eq!(user_spec.eval("float(false)"), 0.0);
+        assert_eq!(user_spec.eval("float('')"), to_value(f64::NAN));
+        assert_eq!(
+            user_spec.eval("float('not a num')"),
+            to_value(f64::NAN)
+        );
+        assert_eq!(user_spec.eval("float(ctx)"), to_value(f64::NAN));
+        assert_eq!(
+            user_spec.eval("float(array(42, 42))"),
+            to_value(f64::NAN)
+        );
+        assert_eq!(user_spec.eval("float(0..42)"), to_value(f64::NAN));
+        assert_eq!(user_spec.eval("float(null)"), to_value(f64::NAN));
+    }
+
+    #[test]
+    fn int() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("int(42)"), 42);
+        assert_eq!(user_spec.eval("int(42.42)"), 42);
+        assert_eq!(user_spec.eval("int('42.42')"), 42);
+        assert_eq!(user_spec.eval("int('42')"), 42);
+        assert_eq!(user_spec.eval("int(true)"), 1);
+        assert_eq!(user_spec.eval("int(false)"), 0);
+        assert_eq!(user_spec.eval("int('')"), 0);
+        assert_eq!(user_spec.eval("int('not a num')"), 0);
+        assert_eq!(user_spec.eval("int(ctx)"), 0);
+        assert_eq!(user_spec.eval("int(array(42, 42))"), 0);
+        assert_eq!(user_spec.eval("int(0..42)"), 0);
+        assert_eq!(user_spec.eval("int(null)"), 0);
+    }
+
+    #[test]
+    fn day() {
+        let user_spec = Spec::default();
+        let date = Date::now().date_naive();
+        let day = date.day();
+
+        assert_eq!(user_spec.eval("get_day()"), day);
+        assert_eq!(user_spec.eval("get_day('_')"), day);
+    }
+
+    #[test]
+    fn month() {
+        let user_spec = Spec::default();
+        let date = Date::now().date_naive();
+        let month = date.month();
+
+        assert_eq!(user_spec.eval("get_month()"), month);
+        assert_eq!(user_spec.eval("get_month('_')"), month);
+    }
+
+    #[test]
+    fn year() {
+        let user_spec = Spec::default();
+        let date = Date::now().date_naive();
+        let year = date.year();
+        assert_eq!(user_spec.eval("get_year()"), year);
+        assert_eq!(user_spec.eval("get_year('_')"), year);
+    }
+
+    #[test]
+    fn weekday() {
+        let user_spec = Spec::default();
+        let weekday_num = Date::now().weekday().number_from_monday();
+        assert_eq!(user_spec.eval("get_weekday('_')"), weekday_num);
+        assert_eq!(user_spec.eval("is_weekday('_')"), weekday_num < 6);
+
+        assert_eq!(user_spec.eval("get_weekday()"), weekday_num);
+        assert_eq!(user_spec.eval("is_weekday()"), weekday_num < 6);
+    }
+
+    #[test]
+    fn time() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("get_time('_', 'h')"), Date::now().time().hour());
+        assert_eq!(user_spec.eval("get_time('_', 'm')"), Date::now().time().minute());
+        assert_eq!(user_spec.eval("get_time('_', 's')"), Date::now().time().second());
+
+        assert_eq!(user_spec.eval("get_time('_', 'hour')"), Date::now().time().hour());
+        assert_eq!(
+            user_spec.eval("get_time('_', 'minute')"),
+            Date::now().time().minute()
+        );
+        assert_eq!(
+            user_spec.eval("get_time('_', 'second')"),
+            Date::now().time().second()
+        );
+
+        assert_eq!(user_spec.eval("get_time('_', 'hours')"), Date::now().time().hour());
+        assert_eq!(user_spec.eval("get_time()"), Date::now().time().hour());
+        assert_eq!(
+            user_spec.eval("get_time('_', 'minutes')"),
+            Date::now().time().minute()
+        );
+        assert_eq!(
+            user_spec.eval("get_time('_', 'seconds')"),
+            Date::now().time().second()
+        );
+    }
+
+    #[test]
+    fn is_match() {
         let user_spec = Spec::default();
-        assert_eq!(user_spec.eval("NAN"), to_value(f64::NAN));
-        assert_eq!(user_spec.eval("INFINITY"), to_value(f64::INFINITY));
-        assert_eq!(user_spec.eval("NEG_INFINITY"), to_value(f64::NEG_INFINITY));
-        assert_eq!(user_spec.eval("maths.MAX_INT"), to_value(i64::MAX));
-        assert_eq!(user_spec.eval("maths.MAX_FLOAT"), to_value(f64::MAX));
-        assert_eq!(user_spec.eval("maths.MIN_FLOAT"), to_value(f64::MIN));
-        assert_eq!(user_spec.eval("maths.INC"), to_value(f64::NAN));
-        assert_eq!(user_spec.eval("maths.NOT_A_NUMBER"), to_value(f64::NAN));
-        assert_eq!(user_spec.eval("maths.INFINITE"), to_value(f64::INFINITY));
-        assert_eq!(user_spec.eval("maths.NEG_INFINITE"), to_value(f64::NEG_INFINITY));
-        assert_eq!(user_spec.eval("maths.E"), to_value(std::f64::consts::E));
-        assert_eq!(user_spec.eval("maths.FRAC_1_SQRT_2"), to_value(std::f64::consts::FRAC_1_SQRT_2));
-        assert_eq!(user_spec.eval("maths.FRAC_2_SQRT_PI"), to_value(std::f64::consts::FRAC_2_SQRT_PI));
-        assert_eq!(user_spec.eval("maths.FRAC_1_PI"), to_value(std::f64::consts::FRAC_1_PI));
-        assert_eq!(user_spec.eval("maths.FRAC_PI_2"), to_value(std::f64::consts::FRAC_PI_2));
-        assert_eq!(user_spec.eval("maths.FRAC_PI_3"), to_value(std::f64::consts::FRAC_PI_3));
-        assert_eq!(user_spec.eval("maths.FRAC_PI_4"), to_value(std::f64::consts::FRAC_PI_4));
-        assert_eq!(user_spec.eval("maths.FRAC_PI_6"), to_value(std::f64::consts::FRAC_PI_6));
-        assert_eq!(user_spec.eval("maths.FRAC_PI_8"), to_value(std::f64::consts::FRAC_PI_8));
-        assert_eq!(user_spec.eval("maths.LN_2"), to_value(std::f64::consts::LN_2));
-        assert_eq!(user_spec.eval("maths.LN_10"), to_value(std::f64::consts::LN_10));
-        assert_eq!(user_spec.eval("maths.LOG2_10"), to_value(std::f64::consts::LOG2_10));
-        assert_eq!(user_spec.eval("maths.LOG2_E"), to_value(std::f64::consts::LOG2_E));
-        assert_eq!(user_spec.eval("maths.LOG10_2"), to_value(std::f64::consts::LOG10_2));
-        assert_eq!(user_spec.eval("maths.LOG10_E"), to_value(std::f64::consts::LOG10_E));
-        assert_eq!(user_spec.eval("maths.PI"), to_value(std::f64::consts::PI));
-        assert_eq!(user_spec.eval("maths.SQRT_2"), to_value(std::f64::consts::SQRT_2));
-        assert_eq!(user_spec.eval("maths.TAU"), to_value(std::f64::consts::TAU));
+        assert_eq!(user_spec.eval("is_match('http', '^https?$')"), to_value(true));
+        assert_eq!(user_spec.eval("is_match('http', 'https')"), to_value(false));
+        assert_eq!(user_spec.eval("is_match('http://', '^udp://')"), to_value(false));
+        assert_eq!(user_spec.eval("is_match('http://', '^(https?|wss?)://$')"), to_value(true));
+        assert_eq!(user_spec.eval(r"is_match('2014-01-01', '^\d{4}-\d{2}-\d{2}$')"), to_value(true));
+    }
+
+    #[test]
+    fn extract() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("extract('http://www.floa', 'https?://')"), "http://");
+        assert_eq!(user_spec.eval("extract('foo', 'bar')"), "");
+    }
+
+    #[test]
+    fn string_case() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("upper('straße')"), "STRASSE");
+        assert_eq!(user_spec.eval("lower('ÀÉÎ')"), "àéî");
+        assert_eq!(user_spec.eval("title('hello wORLD, élan 1st')"), "Hello World, Élan 1St");
+        assert_eq!(user_spec.eval("capitalize('éCOLE')"), "École");
+        assert_eq!(user_spec.eval("capitalize('')"), "");
+        assert_eq!(user_spec.eval("upper(42)"), "42");
+    }
+
+    #[test]
+    fn string_strip() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("strip('  spam  ')"), "spam");
+        assert_eq!(user_spec.eval("lstrip('  spam  ')"), "spam  ");
+        assert_eq!(user_spec.eval("rstrip('  spam  ')"), "  spam");
+        assert_eq!(user_spec.eval("strip('www.example.com', 'cmowz.')"), "example");
+        assert_eq!(user_spec.eval("strip('ééhiéé', 'é')"), "hi");
+        assert_eq!(user_spec.eval("rstrip('mississippi', 'ipz')"), "mississ");
+    }
+
+    #[test]
+    fn string_search() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("startswith('héllo', 'hé')"), true);
+        assert_eq!(user_spec.eval("startswith('héllo', 'llo', 2)"), true);
+        assert_eq!(user_spec.eval("startswith('héllo', array('x', 'hé'))"), true);
+        assert_eq!(user_spec.eval("endswith('héllo', 'llo')"), true);
+        assert_eq!(user_spec.eval("endswith('héllo', 'hé', 0, 2)"), true);
+        assert_eq!(user_spec.eval("endswith('héllo', 'x')"), false);
+
+        assert_eq!(user_spec.eval("find('日本語の本', '本')"), 1);
+        assert_eq!(user_spec.eval("find('日本語の本', '本', 2)"), 4);
+        assert_eq!(user_spec.eval("find('日本語の本', 'x')"), -1);
+        assert_eq!(user_spec.eval("rfind('日本語の本', '本')"), 4);
+        assert_eq!(user_spec.eval("rfind('日本語の本', '本', 0, 0-1)"), 1);
+
+        assert_eq!(user_spec.eval("count('banana', 'a')"), 3);
+        assert_eq!(user_spec.eval("count('banana', 'ana')"), 1);
+        assert_eq!(user_spec.eval("count('banana', 'a', 2)"), 2);
+        assert_eq!(user_spec.eval("count('añb', '')"), 4);
+    }
+
+    #[test]
+    fn string_replace_split_join() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("replace('aaa', 'a', 'b')"), "bbb");
+        assert_eq!(user_spec.eval("replace('aaa', 'a', 'b', 2)"), "bba");
+        assert_eq!(user_spec.eval("replace('aaa', 'a', 'b', 0-1)"), "bbb");
+
+        assert_eq!(user_spec.eval("split('a,b,,c', ',')"), json!(["a", "b", "", "c"]));
+        assert_eq!(user_spec.eval("split('a,b,c', ',', 1)"), json!(["a", "b,c"]));
+        assert_eq!(user_spec.eval("split('  a  b c  ')"), json!(["a", "b", "c"]));
+        assert_eq!(user_spec.eval("split('  a  b c  ', null, 1)"), json!(["a", "b c  "]));
+        assert_eq!(user_spec.eval("split('')"), json!([]));
+
+        assert_eq!(user_spec.eval("join('-', array('a', 'b', 42))"), "a-b-42");
+        assert_eq!(user_spec.eval("join(array('a', 'b'), ', ')"), "a, b");
+        assert_eq!(user_spec.eval("join('-', array())"), "");
+    }
+
+    #[test]
+    fn string_padding() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("zfill('42', 5)"), "00042");
+        assert_eq!(user_spec.eval("zfill('-42', 5)"), "-0042");
+        assert_eq!(user_spec.eval("zfill('+42', 2)"), "+42");
+        assert_eq!(user_spec.eval("center('ab', 5)"), "  ab ");
+        assert_eq!(user_spec.eval("center('abc', 6, '*')"), "*abc**");
+        assert_eq!(user_spec.eval("center('é', 3, 'ü')"), "üéü");
+        assert_eq!(user_spec.eval("ljust('ab', 4, '.')"), "ab..");
+        assert_eq!(user_spec.eval("rjust('ab', 4)"), "  ab");
+        assert_eq!(user_spec.eval("rjust('abcdef', 4)"), "abcdef");
+    }
+
+    #[test]
+    fn string_predicates() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("isdigit('0123')"), true);
+        assert_eq!(user_spec.eval("isdigit('12a')"), false);
+        assert_eq!(user_spec.eval("isdigit('٣²①')"), true);
+        assert_eq!(user_spec.eval("isdigit('½')"), false);
+        assert_eq!(user_spec.eval("isdigit('Ⅻ')"), false);
+        assert_eq!(user_spec.eval("isdigit('')"), false);
+        assert_eq!(user_spec.eval("isalpha('Ñandú')"), true);
+        assert_eq!(user_spec.eval("isalpha('abc1')"), false);
+        assert_eq!(user_spec.eval("isalnum('abc1')"), true);
+        assert_eq!(user_spec.eval("isalnum('abc 1')"), false);
+        assert_eq!(user_spec.eval("isspace(' ')"), true);
+        assert_eq!(user_spec.eval("isspace('')"), false);
+    }
+
+    #[test]
+    fn inflection() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("camelize('device_type')"), "DeviceType");
+        assert_eq!(user_spec.eval("camelize('device_type_id')"), "DeviceTypeId");
+        assert_eq!(user_spec.eval("camelize('device_type', false)"), "deviceType");
+        assert_eq!(user_spec.eval("camelize('été_brûlant')"), "ÉtéBrûlant");
+        assert_eq!(user_spec.eval("underscore('DeviceType')"), "device_type");
+        assert_eq!(user_spec.eval("snake_case('HTTPServerError')"), "http_server_error");
+        assert_eq!(user_spec.eval("dasherize('puni_puni')"), "puni-puni");
+        assert_eq!(user_spec.eval("kebab_case('DeviceType')"), "device-type");
+        assert_eq!(user_spec.eval("humanize('employee_salary')"), "Employee salary");
+        assert_eq!(user_spec.eval("humanize('author_id')"), "Author");
+        assert_eq!(user_spec.eval("humanize('élan_vital')"), "Élan vital");
+        assert_eq!(user_spec.eval("titleize('TheManWithoutAPast')"), "The Man Without A Past");
+        assert_eq!(user_spec.eval("titleize('x-men: the last stand')"), "X Men: The Last Stand");
+        assert_eq!(user_spec.eval("titleize('élan_vital')"), "Élan Vital");
+        assert_eq!(user_spec.eval("pluralize('person')"), "people");
+        assert_eq!(user_spec.eval("pluralize('box')"), "boxes");
+        assert_eq!(user_spec.eval("singularize('octopi')"), "octopus");
+        assert_eq!(user_spec.eval("singularize('sheep')"), "sheep");
+        assert_eq!(user_spec.eval("ordinalize(1)"), "1st");
+        assert_eq!(user_spec.eval("ordinalize(12)"), "12th");
+        assert_eq!(user_spec.eval("ordinalize('23')"), "23rd");
+        assert_eq!(user_spec.eval("parameterize('Donald E. Knuth')"), "donald-e-knuth");
+        assert_eq!(user_spec.eval("parameterize('Ñandú Café', '_')"), "nandu_cafe");
+    }
+
+    #[test]
+    fn unicode() {
+        let user_spec = Spec::default();
+        let decomposed = "e\u{301}";
+        assert_eq!(user_spec.eval(format!("char_len(normalize('{decomposed}'))")), 1);
+        assert_eq!(user_spec.eval("char_len(normalize('é', 'NFD'))"), 2);
+        assert_eq!(user_spec.eval("normalize('ﬁ', 'NFKC')"), "fi");
+        assert_eq!(user_spec.eval("normalize('ﬁ', 'nfkd')"), "fi");
+        assert_eq!(user_spec.eval(format!("normalize('{decomposed}') == 'é'")), true);
+        assert!(ExprWrapper::new("normalize('a', 'NFX')").init().exec().is_err());
+
+        assert_eq!(user_spec.eval(format!("char_len('{decomposed}')")), 2);
+        assert_eq!(user_spec.eval(format!("grapheme_len('{decomposed}')")), 1);
+        assert_eq!(user_spec.eval("grapheme_len('🇫🇷👍🏽')"), 2);
+        assert_eq!(user_spec.eval("display_width('日本')"), 4);
+        assert_eq!(user_spec.eval("display_width('abc')"), 3);
+
+        assert_eq!(user_spec.eval("remove_accents('Crème Brûlée à São Paulo')"), "Creme Brulee a Sao Paulo");
+        assert_eq!(user_spec.eval("remove_accents('日本')"), "日本");
+        assert_eq!(user_spec.eval("casefold('Straße')"), "strasse");
+        assert_eq!(user_spec.eval("casefold('ΣΊΣΥΦΟΣ') == casefold('σίσυφος')"), true);
+        assert_eq!(user_spec.eval("casefold('ẞ ﬃ µ ϐ ᾈ')"), "ss ffi μ β ἀι");
+        assert_eq!(user_spec.eval("casefold('Ꮳ') == casefold('ꮳ')"), true);
+        assert_eq!(user_spec.eval("casefold('İ')"), "i\u{307}");
+
+        assert_eq!(user_spec.eval("truncate('Hello world', 8)"), "Hello w…");
+        assert_eq!(user_spec.eval("truncate('Hello world', 8, '...')"), "Hello...");
+        assert_eq!(user_spec.eval("truncate('Hello', 8)"), "Hello");
+        assert_eq!(user_spec.eval(format!("truncate('ae{decomposed}bc', 3, '')")), format!("ae{decomposed}"));
+        assert_eq!(user_spec.eval("truncate('👍🏽👍🏽👍🏽', 2)"), "👍🏽…");
+        assert_eq!(user_spec.eval("truncate('abc', 0, '')"), "");
+        assert!(ExprWrapper::new("truncate('abc', 0-1)").init().exec().is_err());
+        assert!(ExprWrapper::new("truncate('abc', 1.5)").init().exec().is_err());
+    }
+
+    #[test]
+    fn similarity() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("levenshtein('kitten', 'sitting')"), 3);
+        assert_eq!(user_spec.eval("levenshtein('café', 'cafe')"), 1);
+        assert_eq!(user_spec.eval("levenshtein('ab', 'ba')"), 2);
+        assert_eq!(user_spec.eval("damerau_levenshtein('ab', 'ba')"), 1);
+        assert_eq!(user_spec.eval("jaro_winkler('martha', 'martha')"), 1.0);
+        assert_eq!(user_spec.eval("jaro_winkler('', 'abc')"), 0.0);
+        assert_eq!(user_spec.eval("jaro_winkler('dixon', 'dicksonx') > 0.8"), true);
+        assert_eq!(user_spec.eval("similarity('abcd', 'abcx')"), 0.75);
+        assert_eq!(user_spec.eval("similarity('', '')"), 1.0);
+        assert_eq!(user_spec.eval("similarity('abcd', 'abdc')"), 0.75);
+    }
+
+    #[test]
+    fn phonetic() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("soundex('Robert')"), "R163");
+        assert_eq!(user_spec.eval("soundex('Rupert')"), "R163");
+        assert_eq!(user_spec.eval("soundex('Ashcraft')"), "A261");
+        assert_eq!(user_spec.eval("soundex('Tymczak')"), "T522");
+        assert_eq!(user_spec.eval("soundex('Pfister')"), "P236");
+        assert_eq!(user_spec.eval("soundex('Lee')"), "L000");
+        assert_eq!(user_spec.eval("soundex('')"), "");
+
+        assert_eq!(user_spec.eval("metaphone('Thumb')"), "0M");
+        assert_eq!(user_spec.eval("metaphone('knight')"), "NT");
+        assert_eq!(user_spec.eval("metaphone('Smith') == metaphone('Smyth')"), true);
+        assert_eq!(user_spec.eval("metaphone('Xavier')"), "SFR");
+        assert_eq!(user_spec.eval("metaphone('science')"), "SNS");
+        assert_eq!(user_spec.eval("metaphone('Catherine') == metaphone('Kathryn')"), true);
+    }
+
+    #[test]
+    fn fuzzy_match() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("fuzzy_match('appel', array('ape', 'apple', 'peach'))"), "apple");
+        assert_eq!(user_spec.eval("fuzzy_match('zzz', array('ape', 'apple'))"), json!(null));
+        assert_eq!(user_spec.eval("fuzzy_match('zzz', array('ape', 'apple'), 0.0)"), "ape");
+        assert_eq!(user_spec.eval("fuzzy_match('apple', array())"), json!(null));
+        assert!(ExprWrapper::new("fuzzy_match('a', 'b')").init().exec().is_err());
+    }
+
+    #[test]
+    fn format() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("format('{} {}', 'a', 42)"), "a 42");
+        assert_eq!(user_spec.eval("format('{1} {0} {1}', 'a', 'b')"), "b a b");
+        assert_eq!(user_spec.eval("format('{{}} {}', 1)"), "{} 1");
+        assert_eq!(user_spec.eval("format('{!r}', 'it')"), "'it'");
+
+        let mut expr = ExprWrapper::new("format('{name} owes {amount:.2f} to {who.first} {tags[1]}', ctx)")
+            .value("ctx", json!({
+                "name": "Kar",
+                "amount": 1234.5,
+                "who": {"first": "Ana"},
+                "tags": ["a", "b"],
+            }))
+            .init();
+        assert_eq!(expr.exec().unwrap(), "Kar owes 1234.50 to Ana b");
+
+        assert!(ExprWrapper::new("format('{} {}', 1)").init().exec().is_err());
+        assert!(ExprWrapper::new("format('{} {0}', 1)").init().exec().is_err());
+        assert!(ExprWrapper::new("format('{missing}', 1)").init().exec().is_err());
+    }
+
+    #[test]
+    fn format_spec() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("format('[{:>6}]', 'ab')"), "[    ab]");
+        assert_eq!(user_spec.eval("format('[{:<6}]', 42)"), "[42    ]");
+        assert_eq!(user_spec.eval("format('[{:*^7}]', 'ab')"), "[**ab***]");
+        assert_eq!(user_spec.eval("format('[{:6}]', 42)"), "[    42]");
+        assert_eq!(user_spec.eval("format('[{:06}]', 0-42)"), "[-00042]");
+        assert_eq!(user_spec.eval("format('[{:=+8.2f}]', 3.14159)"), "[+   3.14]");
+        assert_eq!(user_spec.eval("format('{:.3}', 'abcdef')"), "abc");
+        assert_eq!(user_spec.eval("format('{:{}}|', 'a', 3)"), "a  |");
+
+        assert_eq!(user_spec.eval("format('{:,}', 1234567)"), "1,234,567");
+        assert_eq!(user_spec.eval("format('{:_}', 1234567)"), "1_234_567");
+        assert_eq!(user_spec.eval("format('{:,.2f}', 1234567.891)"), "1,234,567.89");
+        assert_eq!(user_spec.eval("format('{:010,}', 1234)"), "00,001,234");
+        assert_eq!(user_spec.eval("format('{:08,}', 1234)"), "0,001,234");
+        assert_eq!(user_spec.eval("format('{:010,}', 0-1234)"), "-0,001,234");
+        assert_eq!(user_spec.eval("format('{:0=10,}', 1234)"), "00,001,234");
+        assert_eq!(user_spec.eval("format('{:0>10,}', 1234)"), "000001,234");
+        assert_eq!(user_spec.eval("format('{:010,.2f}', 1234.5)"), "001,234.50");
+        assert_eq!(user_spec.eval("format('{:#012_x}', 1234)"), "0x0_0000_04d2");
+        assert_eq!(user_spec.eval("format('{:+d} {: d}', 5, 5)"), "+5  5");
+
+        assert_eq!(user_spec.eval("format('{:x} {:X} {:#x}', 255, 255, 255)"), "ff FF 0xff");
+        assert_eq!(user_spec.eval("format('{:b} {:#b} {:#o}', 5, 5, 8)"), "101 0b101 0o10");
+        assert_eq!(user_spec.eval("format('{:#010b}', 5)"), "0b00000101");
+        assert_eq!(user_spec.eval("format('{:_b}', 255)"), "1111_1111");
+        assert_eq!(user_spec.eval("format('{:c}', 233)"), "é");
+
+        assert_eq!(user_spec.eval("format('{:e}', 12345.678)"), "1.234568e+04");
+        assert_eq!(user_spec.eval("format('{:.2E}', 0.000123)"), "1.23E-04");
+        assert_eq!(user_spec.eval("format('{:g}', 0.00001234)"), "1.234e-05");
+        assert_eq!(user_spec.eval("format('{:g}', 1234.5)"), "1234.5");
+        assert_eq!(user_spec.eval("format('{:.2%}', 0.256)"), "25.60%");
+        assert_eq!(user_spec.eval("format('{}', 0.1)"), "0.1");
+        assert_eq!(user_spec.eval("format('{}', 3.0)"), "3.0");
+        assert_eq!(user_spec.eval("format('{:.3}', 3.0)"), "3.0");
+        assert_eq!(user_spec.eval("format('{:.3}', 1234.5)"), "1.23e+03");
+
+        assert!(ExprWrapper::new("format('{:d}', 'abc')").init().exec().is_err());
+        assert!(ExprWrapper::new("format('{:d}', 1.5)").init().exec().is_err());
+        assert!(ExprWrapper::new("format('{:q}', 1)").init().exec().is_err());
+    }
+
+    #[test]
+    fn sprintf() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("sprintf('%s owes %.2f', 'Kar', 3.14159)"), "Kar owes 3.14");
+        assert_eq!(user_spec.eval("sprintf('%5d|%-5d|%05d', 42, 42, 42)"), "   42|42   |00042");
+        assert_eq!(user_spec.eval("sprintf('%x %X %#o %e', 255, 255, 8, 1.5)"), "ff FF 0o10 1.500000e+00");
+        assert_eq!(user_spec.eval("sprintf('%d%%', 99.9)"), "99%");
+        assert_eq!(user_spec.eval("sprintf('%r %c%c', 'a', 'b', 99)"), "'a' bc");
+        assert_eq!(user_spec.eval("sprintf('%*d|%.*f', 4, 7, 1, 2.25)"), "   7|2.2");
+
+        let mut expr = ExprWrapper::new("sprintf('%(name)s is %(age)d', ctx)")
+            .value("ctx", json!({"name": "Kar", "age": 42}))
+            .init();
+        assert_eq!(expr.exec().unwrap(), "Kar is 42");
+
+        assert!(ExprWrapper::new("sprintf('%s %s', 1)").init().exec().is_err());
+        assert!(ExprWrapper::new("sprintf('%s', 1, 2)").init().exec().is_err());
+    }
+
+    #[test]
+    fn slicing() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("slice('héllo!', 1, 0-1)"), "éllo");
+        assert_eq!(user_spec.eval("slice('héllo', 0-3)"), "llo");
+        assert_eq!(user_spec.eval("slice('héllo', null, null, 0-1)"), "olléh");
+        assert_eq!(user_spec.eval("slice('héllo', null, null, 2)"), "hlo");
+        assert_eq!(user_spec.eval("slice('héllo', 3, 0, 0-1)"), "llé");
+        assert_eq!(user_spec.eval("slice('héllo', 10, 20)"), "");
+        assert_eq!(user_spec.eval("slice('日本語', 0-100, 100)"), "日本語");
+
+        assert_eq!(user_spec.eval("slice(array(1, 2, 3, 4, 5), null, null, 2)"), json!([1, 3, 5]));
+        assert_eq!(user_spec.eval("slice(array(1, 2, 3, 4, 5), 0-2)"), json!([4, 5]));
+        assert_eq!(user_spec.eval("slice(array(1, 2, 3, 4, 5), null, null, 0-2)"), json!([5, 3, 1]));
+        assert_eq!(user_spec.eval("slice(array(), 1, 2)"), json!([]));
+        assert_eq!(user_spec.eval("slice(null, 1)"), json!(null));
+        assert_eq!(user_spec.eval("slice('abcdef', 1, null, maths.MAX_INT)"), "b");
+        assert_eq!(user_spec.eval("slice('abcdef', 4, null, maths.MIN_INT)"), "e");
+        assert_eq!(user_spec.eval("slice(array(1, 2, 3), 1, null, maths.MAX_INT)"), json!([2]));
+        assert_eq!(user_spec.eval("slice(array(1, 2, 3), null, null, maths.MIN_INT)"), json!([3]));
+        assert!(ExprWrapper::new("slice('abc', 0, 1, 0)").init().exec().is_err());
+    }
+
+    #[test]
+    fn indexing() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("at('héllo', 1)"), "é");
+        assert_eq!(user_spec.eval("at('héllo', 0-1)"), "o");
+        assert_eq!(user_spec.eval("at('héllo', 5)"), json!(null));
+        assert_eq!(user_spec.eval("at('héllo', 0-6)"), json!(null));
+        assert_eq!(user_spec.eval("at(array(1, 2, 3), 0-1)"), 3);
+        assert_eq!(user_spec.eval("at(array(1, 2, 3), 0)"), 1);
+        assert_eq!(user_spec.eval("at(array(), 0)"), json!(null));
+        assert_eq!(user_spec.eval("at('abc', maths.MIN_INT)"), json!(null));
+        assert!(ExprWrapper::new("at('abc', 1.5)").init().exec().is_err());
+        assert!(ExprWrapper::new("at('abc', '1')").init().exec().is_err());
+    }
+
+    #[test]
+    fn template_engine() {
+        let context = json! {{
+            "name": "Kar",
+            "location": "foo-bar",
+            "some": {
+                "deep": {
+                    "value": 42
+                }
+            }
+        }};
+
+        assert_eq!(
+            template::resolve_template(
+                "Hi, my name is <? $.name ?> and I live in <? $.location ?> <? $.some.deep.value ?>".to_string(),
+                context.clone(),
+            ).expect("Failed to resolve template"),
+            "Hi, my name is Kar and I live in foo-bar 42".to_string()
+        );
+
+        assert_eq!(
+            template::resolve_template(
+                "Hi, my name is Kar and I live in foo-bar 42".to_string(),
+                context.clone(),
+            ).expect("Failed to resolve template"),
+            "Hi, my name is Kar and I live in foo-bar 42".to_string()
+        );
+
+        assert_eq!(
+            template::resolve_template(
+                "".to_string(),
+                context.clone(),
+            ).expect("Failed to resolve template"),
+            "".to_string()
+        );
+
+        assert_eq!(
+            template::resolve_template(
+                "Hello, <? ?>".to_string(),
+                context,
+            ).expect("Failed to resolve template"),
+            "Hello, ".to_string(),
+        );
+    }
+
+    #[test]
+    fn template_single_pass() {
+        let context = json! {{
+            "a": "<? $.b ?>",
+            "b": "B",
+            "c": "<? $.a ?>",
+            "tag": "<?if true ?>x<?end?>",
+        }};
+        assert_eq!(
+            template::resolve_template("<? $.a ?>|<? $.b ?>".to_string(), context.clone())
+                .expect("Failed to resolve template"),
+            "<? $.b ?>|B"
+        );
+        assert_eq!(
+            template::resolve_template("<? $.b ?>|<? $.a ?>|<? $.c ?>".to_string(), context.clone())
+                .expect("Failed to resolve template"),
+            "B|<? $.b ?>|<? $.a ?>"
+        );
+        assert_eq!(
+            template::resolve_template("<? $.tag ?><? $.tag ?>".to_string(), context.clone())
+                .expect("Failed to resolve template"),
+            "<?if true ?>x<?end?><?if true ?>x<?end?>"
+        );
+        assert_eq!(
+            template::resolve_template("<?for x in array($.a, $.c) ?>[<? x ?>]<?end?>".to_string(), context)
+                .expect("Failed to resolve template"),
+            "[<? $.b ?>][<? $.a ?>]"
+        );
+    }
+
+    #[test]
+    fn template_repeated_placeholders() {
+        let context = json!({"n": 1, "items": [1, 2, 3]});
+        assert_eq!(
+            template::resolve_template(
+                "<? $.n ?>-<? $.n ?>-<?for i in $.items ?><? $.n ?><? i ?><?end?>-<? $.n ?>".to_string(),
+                context,
+            ).expect("Failed to resolve template"),
+            "1-1-111213-1"
+        );
+    }
+
+    #[test]
+    fn template_conditionals() {
+        let template = "<?if $.vip ?>Dear VIP<?elif $.member ?>Dear member<?else?>Hello<?end?> <? $.name ?>";
+        let render = |context: resolver::Value| {
+            template::resolve_template(template.to_string(), context)
+                .expect("Failed to resolve template")
+        };
+
+        assert_eq!(render(json!({"vip": true, "member": true, "name": "Kar"})), "Dear VIP Kar");
+        assert_eq!(render(json!({"vip": false, "member": true, "name": "Kar"})), "Dear member Kar");
+        assert_eq!(render(json!({"name": "Kar"})), "Hello Kar");
+        assert_eq!(render(json!({"vip": "", "member": [], "name": "Kar"})), "Hello Kar");
+
+        assert_eq!(
+            template::resolve_template(
+                "<?if upper($.name) == 'KAR' ?>yes<?end?><?if len($.name) > 5 ?>long<?end?>".to_string(),
+                json!({"name": "Kar"}),
+            ).expect("Failed to resolve template"),
+            "yes"
+        );
+
+        assert_eq!(
+            template::resolve_template(
+                "<?if $.a ?>a<?if $.b ?>b<?else?>!b<?end?><?end?>".to_string(),
+                json!({"a": true, "b": false}),
+            ).expect("Failed to resolve template"),
+            "a!b"
+        );
+    }
+
+    #[test]
+    fn template_loops() {
+        let context = json! {{
+            "items": [
+                {"name": "apple", "qty": 2},
+                {"name": "pear", "qty": 1},
+                {"name": "fig", "qty": 5},
+            ],
+            "empty": [],
+        }};
+
+        assert_eq!(
+            template::resolve_template(
+                "<?for item in $.items ?><? loop.index ?>:<? upper(item.name) ?>x<? item.qty ?><?if !loop.last ?>, <?end?><?end?>".to_string(),
+                context.clone(),
+            ).expect("Failed to resolve template"),
+            "0:APPLEx2, 1:PEARx1, 2:FIGx5"
+        );
+
+        assert_eq!(
+            template::resolve_template(
+                "<?for item in $.items ?><?if loop.first ?>[<?end?><? item.name ?><?if loop.last ?>]<?end?><?end?>".to_string(),
+                context.clone(),
+            ).expect("Failed to resolve template"),
+            "[applepearfig]"
+        );
+
+        assert_eq!(
+            template::resolve_template(
+                "<?for row in array(array(1, 2), array(3)) ?>(<?for x in row ?><? x ?><? loop.length ?><?end?>)<?end?>".to_string(),
+                context.clone(),
+            ).expect("Failed to resolve template"),
+            "(1222)(31)"
+        );
+
+        assert_eq!(
+            template::resolve_template(
+                "a<?for item in $.empty ?>x<?end?><?for item in $.missing ?>y<?end?>b".to_string(),
+                context,
+            ).expect("Failed to resolve template"),
+            "ab"
+        );
+    }
+
+    #[test]
+    fn template_builtins() {
+        let context = json!({"name": "kar", "age": "42", "date": "2014-01-01"});
+        let resolver = template::TemplateResolver::new();
+        assert_eq!(
+            resolver.resolve(
+                "<? upper($.name) ?> <? int($.age) + 1 ?> <? is_match($.date, '^[0-9-]+$') ?> <? maths.PI > 3 ?>",
+                &context,
+            ).expect("Failed to resolve template"),
+            "KAR 43 true true"
+        );
+        assert_eq!(
+            template::resolve_template("<? title($.name) ?>".to_string(), context.clone())
+                .expect("Failed to resolve template"),
+            "Kar"
+        );
+
+        let resolver = template::TemplateResolver::new()
+            .config(EvalConfig { include_strings: false, ..Default::default() })
+            .value("greeting", "hi")
+            .function("shout", |values| {
+                let s = values.first().and_then(|v| v.as_str()).unwrap_or_default();
+                Ok(to_value(format!("{}!", s.to_uppercase())))
+            })
+            .function("upper", |_| Ok(to_value("custom")));
+        assert_eq!(
+            resolver.resolve("<? shout(greeting) ?> <? upper($.name) ?>", &context)
+                .expect("Failed to resolve template"),
+            "HI! custom"
+        );
+        assert!(resolver.resolve("<? capitalize($.name) ?>", &context).is_err());
+    }
+
+    #[test]
+    fn template_block_errors() {
+        for template in [
+            "<?if $.a ?>unterminated",
+            "<?end?>",
+            "<?else?>",
+            "<?if $.a ?>a<?else?>b<?elif $.b ?>c<?end?>",
+            "<?for $.items ?><?end?>",
+        ] {
+            assert!(
+                template::resolve_template(template.to_string(), json!({})).is_err(),
+                "expected {template:?} to fail"
+            );
+        }
+    }
+
+    #[test]
+    fn template_compile_once() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<template::Template>();
+
+        let compiled = template::Template::compile(
+            "<?for x in $.items ?><? upper(x) ?><?if !loop.last ?>, <?end?><?end?> (<? maths.PI > 3 ?>)",
+            &EvalConfig::default(),
+        ).unwrap();
+        assert_eq!(compiled.render(&json!({"items": ["a", "b"]})).unwrap(), "A, B (true)");
+        assert_eq!(compiled.render(&json!({"items": ["c"]})).unwrap(), "C (true)");
+
+        let mut out = Vec::new();
+        compiled.render_to(&json!({"items": []}), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), " (true)");
+
+        let compiled = std::sync::Arc::new(compiled);
+        let handles: Vec<_> = (0..4).map(|i| {
+            let compiled = compiled.clone();
+            std::thread::spawn(move || compiled.render(&json!({"items": [i.to_string()]})).unwrap())
+        }).collect();
+        for (i, handle) in handles.into_iter().enumerate() {
+            assert_eq!(handle.join().unwrap(), format!("{i} (true)"));
+        }
+
+        assert!(template::Template::compile("<? upper($.a ?>", &EvalConfig::default()).is_err());
+
+        let cached = template::compiled_templates();
+        for i in 0..100 {
+            let once = template::Template::compile("<? $ + 1 ?>", &EvalConfig::default()).unwrap();
+            assert_eq!(once.render(&json!(i)).unwrap(), (i + 1).to_string());
+        }
+        assert_eq!(template::compiled_templates(), cached);
+        drop(compiled);
+        assert_eq!(template::compiled_templates(), cached - 1);
     }
 
     #[test]
-    fn literal() {
-        let user_spec = Spec::default();
+    fn template_compiled_custom_values() {
+        let compiled = template::TemplateResolver::new()
+            .value("greeting", "Hello")
+            .function("shout", |args| Ok(json!(format!("{}!", args[0].as_str().unwrap_or_default()))))
+            .compile("<? shout(greeting) ?> <? $.name ?>")
+            .unwrap();
+        assert_eq!(compiled.render(&json!({"name": "Ann"})).unwrap(), "Hello! Ann");
+        assert_eq!(compiled.render(&json!({"name": "Bob"})).unwrap(), "Hello! Bob");
 
-        assert_eq!(user_spec.eval("42"), 42);
-        assert_eq!(user_spec.eval("0-42"), -42);
-        assert_eq!(user_spec.eval("true"), true);
-        assert_eq!(user_spec.eval("false"), false);
-        assert_eq!(user_spec.eval("\"42\""), "42");
-        assert_eq!(user_spec.eval("'42'"), "42");
-        assert_eq!(user_spec.eval("array(42, 42)"), to_value(vec![42; 2]));
-        assert_eq!(user_spec.eval("array()"), to_value(vec![0; 0]));
-        assert_eq!(user_spec.eval("0..5"), to_value(vec![0, 1, 2, 3, 4]));
+        let nested = template::Template::compile("<? inner($.n) ?>", &EvalConfig::default())
+            .unwrap()
+            .function("inner", |args| {
+                let inner = template::Template::compile("[<? $ ?>]", &EvalConfig::default())?;
+                inner.render(&args[0]).map(serde_json::Value::String)
+            });
+        assert_eq!(nested.render(&json!({"n": 3})).unwrap(), "[3]");
     }
 
     #[test]
-    fn _str() {
-        let user_spec = Spec::default();
-        assert_eq!(user_spec.eval("str(42)"), "42");
-        assert_eq!(user_spec.eval("str(42.42)"), "42.42");
-        assert_eq!(user_spec.eval("str(true)"), "true");
-        assert_eq!(user_spec.eval("str(array(42, 42))"), to_value("[42,42]"));
-        assert_eq!(user_spec.eval("str(array())"), to_value("[]"));
-        assert_eq!(user_spec.eval("str(null)"), to_value("null"));
+    fn template_filters() {
+        let context = json!({
+            "name": null,
+            "user": "  ada  ",
+            "price": 19.987,
+            "tags": ["a", "b"],
+            "html": "<b>\"Tom\" & 'Jerry'</b>",
+            "count": 0,
+        });
+        for (source, expected) in [
+            ("<? $.name | default('guest') | upper ?>", "GUEST"),
+            ("<? $.user | trim | upper ?>", "ADA"),
+            ("<? $.user|trim|lower ?>", "ada"),
+            ("<? $.price | round(2) ?>", "19.99"),
+            ("<? $.tags | join(', ') ?>", "a, b"),
+            ("<? $.tags | json ?>", r#"["a","b"]"#),
+            ("<? $.html | escape ?>", "&lt;b&gt;&quot;Tom&quot; &amp; &#39;Jerry&#39;&lt;/b&gt;"),
+            ("<? 'abcdef' | truncate(4) ?>", "abc…"),
+            ("<? $.count | default(5) ?>", "0"),
+            ("<? $.count | default(5, true) ?>", "5"),
+            ("<? 'a|b' | upper ?>", "A|B"),
+            ("<? (1 | 2) ?>", "3"),
+            ("<? false || true ?>", "true"),
+            ("<?if $.name | default('') ?>named<?else?>anonymous<?end?>", "anonymous"),
+            ("<?for t in $.tags | join('-') ?>[<? t ?>]<?end?>", "[a][-][b]"),
+        ] {
+            assert_eq!(
+                template::resolve_template(source.to_string(), context.clone()).unwrap(),
+                expected,
+                "{source}"
+            );
+        }
+
+        let minimal = EvalConfig {
+            include_strings: false,
+            include_unicode: false,
+            ..Default::default()
+        };
+        let compiled = template::Template::compile("<? $.s | trim | truncate(3) | escape ?>", &minimal).unwrap();
+        assert_eq!(compiled.render(&json!({"s": " <abc "})).unwrap(), "&lt;a…");
+
+        for source in ["<? $.a | ?>", "<? | upper ?>", "<? $.a | 2 ?>", "<? $.a | f(x ?>"] {
+            assert!(template::resolve_template(source.to_string(), json!({})).is_err(), "{source}");
+        }
     }
 
     #[test]
-    fn bool() {
-        let user_spec = Spec::default();
+    fn template_escaping() {
+        let context = json!({"value": "<a href=\"x\">Tom & Jerry's</a>\n/ ok", "n": 1.5});
+        for (escape, expected) in [
+            (template::Escape::None, "<a href=\"x\">Tom & Jerry's</a>\n/ ok"),
+            (template::Escape::Html, "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;\n/ ok"),
+            (template::Escape::Xml, "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;\n/ ok"),
+            (template::Escape::Json, "<a href=\\\"x\\\">Tom & Jerry's</a>\\n/ ok"),
+            (template::Escape::Url, "%3Ca%20href%3D%22x%22%3ETom%20%26%20Jerry%27s%3C%2Fa%3E%0A%2F%20ok"),
+            (template::Escape::Shell, "'<a href=\"x\">Tom & Jerry'\\''s</a>\n/ ok'"),
+        ] {
+            let resolver = template::TemplateResolver::new().escape(escape);
+            assert_eq!(resolver.resolve("<? $.value ?>", &context).unwrap(), expected, "{escape:?}");
+            assert_eq!(
+                resolver.resolve("<b><? $.value | raw ?></b>", &context).unwrap(),
+                "<b><a href=\"x\">Tom & Jerry's</a>\n/ ok</b>",
+                "{escape:?}"
+            );
+        }
 
-        assert_eq!(user_spec.eval("bool(1)"), true);
-        assert_eq!(user_spec.eval("bool(1.0)"), true);
-        assert_eq!(user_spec.eval("bool(0)"), false);
-        assert_eq!(user_spec.eval("bool(0.0)"), false);
-        assert_eq!(user_spec.eval("bool(true)"), true);
-        assert_eq!(user_spec.eval("bool(false)"), false);
+        let compiled = template::Template::compile(
+            "<?if $.n > 1 ?><? $.n ?> <? '<i>' | upper | raw ?> <? '<i>' | raw | upper ?><?end?>",
+            &EvalConfig::default(),
+        ).unwrap().escape(template::Escape::Html);
+        assert_eq!(compiled.render(&context).unwrap(), "1.5 <I> <I>");
 
-        assert_eq!(user_spec.eval("bool(42)"), true);
-        assert_eq!(user_spec.eval("bool(42.42)"), true);
-        assert_eq!(user_spec.eval("bool(0-42)"), true);
-        assert_eq!(user_spec.eval("bool(0-42.42)"), true);
+        let compiled = template::Template::compile(
+            "<? raw ?>|<? raw | raw ?>|<? raw | upper ?>|<?for raw in $.items ?><? raw ?><?end?>",
+            &EvalConfig::default(),
+        ).unwrap().value("raw", "<b>").escape(template::Escape::Html);
+        assert_eq!(
+            compiled.render(&json!({"items": ["<i>"]})).unwrap(),
+            "&lt;b&gt;|<b>|&lt;B&gt;|&lt;i&gt;"
+        );
+    }
 
-        assert_eq!(user_spec.eval("bool('')"), false);
-        assert_eq!(user_spec.eval("bool(\"\")"), false);
-        assert_eq!(user_spec.eval("bool('42')"), true);
-        assert_eq!(user_spec.eval("bool(\"42\")"), true);
+    #[test]
+    fn template_delimiters() {
+        let context = json!({"name": "Ann", "q": "?>", "n": null});
+        for (source, expected) in [
+            ("<? $.n ?? 'none' ?>", "none"),
+            ("<? '?>' ?>|<? \"a?>b\" ?>", "?>|a?>b"),
+            ("<? \"it's ?>\" ?>", "it's ?>"),
+            ("<? $.q == '?>' ?>", "true"),
+            (r"literal \<? $.name ?> and <? $.name ?>", "literal <? $.name ?> and Ann"),
+            (r"path C:\\<? $.name ?>", r"path C:\Ann"),
+            (r"\<?", "<?"),
+            ("a ?> b", "a ?> b"),
+        ] {
+            assert_eq!(
+                template::resolve_template(source.to_string(), context.clone()).unwrap(),
+                expected,
+                "{source}"
+            );
+        }
+        assert!(template::resolve_template("<? $.name".to_string(), json!({})).is_err());
+        assert!(template::resolve_template("<? '?> ".to_string(), json!({})).is_err());
 
-        assert_eq!(user_spec.eval("bool(array(42, 42))"), true);
-        assert_eq!(user_spec.eval("bool(array())"), false);
-        assert_eq!(user_spec.eval("bool(0..42)"), true);
-        assert_eq!(user_spec.eval("bool(0..0)"), false);
-        assert_eq!(user_spec.eval("bool(null)"), false);
+        for (delimiters, source) in [
+            (template::Delimiters::new("{{", "}}"), r"{{if $.name}}Hi {{ $.name | upper }}{{end}} \{{x}} <? raw ?>"),
+            (template::Delimiters::new("${", "}"), r"${if $.name}Hi ${ $.name | upper }${end} \${x} <? raw ?>"),
+        ] {
+            let resolver = template::TemplateResolver::new().delimiters(delimiters.clone());
+            let expected = format!("Hi ANN {}x{} <? raw ?>", delimiters.open(), delimiters.close());
+            assert_eq!(resolver.resolve(source, &context).unwrap(), expected);
+        }
+        assert!(template::TemplateResolver::new()
+            .delimiters(template::Delimiters::new("", "}}"))
+            .resolve("x", &context)
+            .is_err());
     }
 
     #[test]
-    fn float() {
-        let user_spec = Spec::default();
-        assert_eq!(user_spec.eval("float(42)"), 42.0);
-        assert_eq!(user_spec.eval("float(42.42)"), 42.42);
-        assert_eq!(user_spec.eval("float('42.42')"), 42.42);
-        assert_eq!(user_spec.eval("float('42')"), 42.0);
-        assert_eq!(user_spec.eval("float(true)"), 1.0);
-        assert_eq!(user_spec.eval("float(false)"), 0.0);
-        assert_eq!(user_spec.eval("float('')"), to_value(f64::NAN));
+    fn template_missing_values() {
+        use template::{MissingValues, NullRendering, RenderOptions, Template};
+
+        let compiled = Template::compile(
+            "Hi <? $.user.name ?>!\n<?for t in $.tags ?><? t.label ?>,<?end?> <? $.nothing ?>|<? $.gone | default('-') ?>",
+            &EvalConfig::default(),
+        ).unwrap();
+        let context = json!({"user": {"name": null}, "tags": [{"label": "a"}, {}], "nothing": null});
+
+        assert_eq!(compiled.render(&context).unwrap(), "Hi null!\na,null, null|-");
+        let empty = RenderOptions { null: NullRendering::Empty, ..Default::default() };
+        assert_eq!(compiled.render_with(&context, &empty).unwrap(), "Hi !\na,, |-");
+
+        let null_error = RenderOptions { null: NullRendering::Error, ..Default::default() };
         assert_eq!(
-            user_spec.eval("float('not a num')"),
-            to_value(f64::NAN)
+            compiled.render_with(&context, &null_error).unwrap_err().to_string(),
+            resolver::Error::Custom("template: null value in `<? $.user.name ?>` at line 1, column 4".into()).to_string()
         );
-        assert_eq!(user_spec.eval("float(ctx)"), to_value(f64::NAN));
+
+        let missing = RenderOptions { missing: MissingValues::Error, ..Default::default() };
         assert_eq!(
-            user_spec.eval("float(array(42, 42))"),
-            to_value(f64::NAN)
+            compiled.render_with(&context, &missing).unwrap_err().to_string(),
+            resolver::Error::Custom("template: missing `t.label` in `<? t.label ?>` at line 2, column 21".into()).to_string()
+        );
+        assert_eq!(
+            compiled.render_with(&json!({"user": {"name": "Ann"}, "tags": [], "nothing": 1}), &missing).unwrap(),
+            "Hi Ann!\n 1|-"
+        );
+
+        let strict = RenderOptions { strict: true, ..Default::default() };
+        assert_eq!(
+            compiled.render_with(&json!({"tags": [{}]}), &strict).unwrap_err().to_string(),
+            resolver::Error::Custom(
+                "template: missing `$.user.name` in `<? $.user.name ?>` at line 1, column 4; \
+                 missing `t.label` in `<? t.label ?>` at line 2, column 21; \
+                 missing `$.nothing` in `<? $.nothing ?>` at line 2, column 43"
+                    .into()
+            )
+            .to_string()
+        );
+        assert_eq!(
+            Template::compile("<? $.a ?> <? $.b[0]        ?> <? $.c['d'] ?> <? $.e ?? 1 ?>", &EvalConfig::default())
+                .unwrap()
+                .render_with(&json!({"c": {}}), &RenderOptions::strict())
+                .unwrap_err()
+                .to_string(),
+            resolver::Error::Custom(
+                "template: missing `$.a` in `<? $.a ?>` at line 1, column 1; \
+                 missing `$.b[0]` in `<? $.b[0]        ?>` at line 1, column 11; \
+                 missing `$.c.d` in `<? $.c['d'] ?>` at line 1, column 31"
+                    .into()
+            )
+            .to_string()
         );
-        assert_eq!(user_spec.eval("float(0..42)"), to_value(f64::NAN));
-        assert_eq!(user_spec.eval("float(null)"), to_value(f64::NAN));
     }
 
     #[test]
-    fn int() {
-        let user_spec = Spec::default();
-        assert_eq!(user_spec.eval("int(42)"), 42);
-        assert_eq!(user_spec.eval("int(42.42)"), 42);
-        assert_eq!(user_spec.eval("int('42.42')"), 42);
-        assert_eq!(user_spec.eval("int('42')"), 42);
-        assert_eq!(user_spec.eval("int(true)"), 1);
-        assert_eq!(user_spec.eval("int(false)"), 0);
-        assert_eq!(user_spec.eval("int('')"), 0);
-        assert_eq!(user_spec.eval("int('not a num')"), 0);
-        assert_eq!(user_spec.eval("int(ctx)"), 0);
-        assert_eq!(user_spec.eval("int(array(42, 42))"), 0);
-        assert_eq!(user_spec.eval("int(0..42)"), 0);
-        assert_eq!(user_spec.eval("int(null)"), 0);
+    fn template_sets() {
+        use template::{MemoryLoader, RenderOptions, TemplateResolver, TemplateSet};
+
+        let loader = MemoryLoader::new()
+            .template("base", "<title><?block title ?>Site<?end?></title>\n<?block body ?>empty<?end?>\n<?include 'footer' ?>")
+            .template("footer", "-- <? $.sender ?>")
+            .template("page", "<?extends 'base' ?>ignored<?block title ?>Page<?end?><?block body ?><?for i in $.items ?><?include 'item' ?><?end?><?end?>")
+            .template("item", "[<? i ?>]")
+            .template("special", "<?extends 'page' ?><?block title ?>Special <? $.sender | upper ?><?end?>")
+            .template("loop", "a<?include 'loop2' ?>")
+            .template("loop2", "b<?if false ?><?include 'loop' ?><?end?>")
+            .template("self", "<?extends 'self' ?>");
+        let set = TemplateSet::new(loader);
+        let context = json!({"sender": "ann", "items": [1, 2]});
+
+        assert_eq!(set.render("footer", &context).unwrap(), "-- ann");
+        assert_eq!(set.render("base", &context).unwrap(), "<title>Site</title>\nempty\n-- ann");
+        assert_eq!(set.render("page", &context).unwrap(), "<title>Page</title>\n[1][2]\n-- ann");
+        assert_eq!(set.render("special", &context).unwrap(), "<title>Special ANN</title>\n[1][2]\n-- ann");
+
+        let compiled = set.get("page").unwrap();
+        assert_eq!(compiled.render(&json!({"sender": "bob", "items": []})).unwrap(), "<title>Page</title>\n\n-- bob");
+
+        for (name, message) in [
+            ("loop", "recursive template `loop`: loop -> loop2 -> loop"),
+            ("self", "recursive template `self`: self -> self"),
+            ("unknown", "unknown template `unknown`"),
+        ] {
+            assert_eq!(
+                set.render(name, &context).unwrap_err().to_string(),
+                resolver::Error::Custom(format!("template: {message}")).to_string()
+            );
+        }
+
+        let strict = TemplateSet::new(MemoryLoader::new().template("missing", "\n <? $.nope ?>").template("nested", "x\n<?include 'missing' ?>"))
+            .resolver(TemplateResolver::new().options(RenderOptions::strict()));
+        assert_eq!(
+            strict.render("nested", &context).unwrap_err().to_string(),
+            resolver::Error::Custom("template: missing `$.nope` in `<? $.nope ?>` at line 2, column 2 of `missing`".into()).to_string()
+        );
+
+        assert!(template::resolve_template("<?include 'footer' ?>".to_string(), json!({})).is_err());
+        assert_eq!(template::resolve_template("<?block a ?>A<?end?>".to_string(), json!({})).unwrap(), "A");
     }
 
     #[test]
-    fn day() {
-        let user_spec = Spec::default();
-        let date = Date::now().date_naive();
-        let day = date.day();
+    fn template_directory_loader() {
+        use template::{DirectoryLoader, TemplateSet};
 
-        assert_eq!(user_spec.eval("get_day()"), day);
-        assert_eq!(user_spec.eval("get_day('_')"), day);
+        let root = std::env::temp_dir().join(format!("eval-utility-templates-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("partials")).unwrap();
+        std::fs::write(root.join("mail.tpl"), "Hello <? $.name ?>\n<?include 'partials/footer' ?>").unwrap();
+        std::fs::write(root.join("partials/footer.tpl"), "Bye").unwrap();
+
+        let set = TemplateSet::new(DirectoryLoader::new(&root).extension("tpl"));
+        let rendered = set.render("mail", &json!({"name": "Ann"}));
+        let escaped = set.render("../mail", &json!({}));
+        let absent = set.render("other.tpl", &json!({}));
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(rendered.unwrap(), "Hello Ann\nBye");
+        assert!(escaped.is_err());
+        assert!(absent.is_err());
     }
 
     #[test]
-    fn month() {
+    fn template_whitespace_control() {
+        let context = json!({"items": ["a", "b"], "name": "Ann", "show": true});
+        for (source, expected) in [
+            ("<ul>\n<?- for i in $.items ?>\n  <li><? i ?></li>\n<?- end ?>\n</ul>", "<ul>\n  <li>a</li>\n  <li>b</li>\n</ul>"),
+            ("a  <?- $.name -?>  \n b", "aAnnb"),
+            ("a <?- $.name ?> b", "aAnn b"),
+            ("a <? $.name -?> b", "a Annb"),
+            ("<?if $.show -?>\n\tyes\n<?- else -?>\n\tno\n<?- end ?>", "yes"),
+            ("x \\<?- y", "x <?- y"),
+            ("<? 0-1 ?> <?-0-1?>", "-1-1"),
+            ("<?-?>", ""),
+            ("<? upper(\n    $.name\n) ?>", "ANN"),
+            ("<?for i\n in $.items\n?><? i ?><?end?>", "ab"),
+            ("<?\n  $.name\n  | lower\n  | default('x')\n?>", "ann"),
+            ("<? 'keep\ttab' ?>", "keep\ttab"),
+        ] {
+            assert_eq!(
+                template::resolve_template(source.to_string(), context.clone()).unwrap(),
+                expected,
+                "{source:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn stringify_policy() {
+        use crate::eval_wrapper::{CollectionFormat, NumberFormat, Stringify};
+
+        let default = Stringify::default();
+        assert_eq!(default.apply(&json!(9007199254740993_i64)), "9007199254740993");
+        assert_eq!(default.apply(&json!(u64::MAX)), "18446744073709551615");
+        assert_eq!(default.apply(&json!(2.0)), "2.0");
+        assert_eq!(default.apply(&json!(-0.25)), "-0.25");
+        let integral = Stringify {
+            numbers: NumberFormat { float_point: false, ..Default::default() },
+            ..Default::default()
+        };
+        assert_eq!(integral.apply(&json!(2.0)), "2");
+        assert_eq!(integral.apply(&json!(2.5)), "2.5");
+        assert_eq!(default.apply(&json!([1, 2.5, "a"])), r#"[1,2.5,"a"]"#);
+
+        let custom = Stringify {
+            numbers: NumberFormat {
+                float_point: true,
+                decimals: None,
+                group_separator: Some('.'),
+                decimal_separator: ',',
+            },
+            collections: CollectionFormat::Joined(", ".into()),
+            true_text: "yes".into(),
+            false_text: "no".into(),
+        };
+        assert_eq!(custom.apply(&json!(1234567)), "1.234.567");
+        assert_eq!(custom.apply(&json!(-1234.5)), "-1.234,5");
+        assert_eq!(custom.apply(&json!(2.0)), "2,0");
+        assert_eq!(custom.apply(&json!([true, false, [1, 2]])), "yes, no, 1, 2");
+        assert_eq!(custom.apply(&json!({"a": 1, "b": null})), "a: 1, b: null");
+
+        let fixed = Stringify {
+            numbers: NumberFormat { decimals: Some(2), ..Default::default() },
+            collections: CollectionFormat::PrettyJson,
+            ..Default::default()
+        };
+        assert_eq!(fixed.apply(&json!(3)), "3.00");
+        assert_eq!(fixed.apply(&json!(2.345)), "2.35");
+        assert_eq!(fixed.apply(&json!({"a": 1})), "{\n  \"a\": 1\n}");
+
         let user_spec = Spec::default();
-        let date = Date::now().date_naive();
-        let month = date.month();
+        assert_eq!(user_spec.eval("upper(9007199254740993)"), to_value("9007199254740993"));
+        assert_eq!(user_spec.eval(r"is_match(2.0, '^2\.0$')"), to_value(true));
+        assert_eq!(user_spec.eval("is_match(2.0, '^2$')"), to_value(false));
+        assert_eq!(user_spec.eval("extract(2.5, '[0-9.]+')"), to_value("2.5"));
+        let integral_spec = Spec { config: EvalConfig { stringify: integral, ..Default::default() } };
+        assert_eq!(integral_spec.eval("is_match(2.0, '^2$')"), to_value(true));
+        assert_eq!(integral_spec.eval("extract(2.0, '[0-9.]+')"), to_value("2"));
+        assert_eq!(
+            template::resolve_template("<? $.a ?> <? $.b ?>".into(), json!({"a": 2.0, "b": 2})).unwrap(),
+            "2.0 2"
+        );
 
-        assert_eq!(user_spec.eval("get_month()"), month);
-        assert_eq!(user_spec.eval("get_month('_')"), month);
+        let config = EvalConfig { stringify: custom, ..Default::default() };
+        let user_spec = Spec { config: config.clone() };
+        assert_eq!(user_spec.eval("upper(true)"), to_value("YES"));
+        assert_eq!(user_spec.eval("is_match(1234567, '^1\\.234\\.567$')"), to_value(true));
+        assert_eq!(user_spec.eval("extract(true, '[a-z]+')"), to_value("yes"));
+        assert_eq!(user_spec.eval("format('{} {}', 'x', false)"), to_value("x no"));
+        assert_eq!(user_spec.eval("join(array(1000, 2), ' | ')"), to_value("1.000 | 2"));
+        assert_eq!(
+            template::TemplateResolver::new()
+                .config(config.clone())
+                .resolve("<? $.n ?> <? $.ok ?> <? $.tags ?> <? $.n | upper ?>", &json!({"n": 10000, "ok": true, "tags": ["x", "y"]}))
+                .unwrap(),
+            "10.000 yes x, y 10.000"
+        );
+
+        let compiled = template::Template::compile("<? $.s | truncate(4) ?>", &config).unwrap();
+        assert_eq!(compiled.render(&json!({"s": 1234567})).unwrap(), "1.2…");
+        let upper = crate::eval_wrapper::builtins(&config).functions.remove("upper").unwrap();
+        assert_eq!(std::thread::spawn(move || (upper.compiled)(vec![json!(true)])).join().unwrap().unwrap(), "YES");
     }
 
     #[test]
-    fn year() {
-        let user_spec = Spec::default();
-        let date = Date::now().date_naive();
-        let year = date.year();
-        assert_eq!(user_spec.eval("get_year()"), year);
-        assert_eq!(user_spec.eval("get_year('_')"), year);
+    fn template_analysis() {
+        use template::{Reference, Segment, TagKind};
+
+        let source = "Hi <? $.user.name | default('guest') | upper ?>!\n\
+                      <?if $.vip && len($.orders) > 0 ?><?for o in $.orders ?><? o.id ?>: <? format('{}', $.price) ?><? $.prices[0] ?><?end?><?end?>\n\
+                      <? 'a $.b c' | raw ?><?block x ?><?end?>";
+        let placeholders = template::analyze(source).unwrap();
+
+        let summary: Vec<_> = placeholders
+            .iter()
+            .map(|p| {
+                (
+                    p.kind,
+                    &source[p.span.clone()],
+                    p.context_paths().map(|r| r.to_string()).collect::<Vec<_>>(),
+                    p.functions.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(summary, vec![
+            (TagKind::Output, "<? $.user.name | default('guest') | upper ?>", vec!["$.user.name".to_string()], vec!["upper".to_string(), "default".to_string()]),
+            (TagKind::Condition, "<?if $.vip && len($.orders) > 0 ?>", vec!["$.vip".to_string(), "$.orders".to_string()], vec!["len".to_string()]),
+            (TagKind::Loop, "<?for o in $.orders ?>", vec!["$.orders".to_string()], vec![]),
+            (TagKind::Output, "<? o.id ?>", vec![], vec![]),
+            (TagKind::Output, "<? format('{}', $.price) ?>", vec!["$.price".to_string()], vec!["format".to_string()]),
+            (TagKind::Output, "<? $.prices[0] ?>", vec!["$.prices[0]".to_string()], vec![]),
+            (TagKind::Output, "<? 'a $.b c' | raw ?>", vec![], vec![]),
+        ]);
+        assert_eq!(placeholders[3].references, vec![Reference {
+            root: "o".into(),
+            path: vec![Segment::Key("id".into())],
+        }]);
+        assert_eq!(placeholders[0].expression, "$.user.name | default('guest') | upper");
+        assert_eq!(placeholders[6].expression, "'a $.b c'");
+
+        let custom = template::analyze_with("{{ $.a }} <? $.b ?>", &template::Delimiters::new("{{", "}}")).unwrap();
+        assert_eq!(custom.len(), 1);
+        assert_eq!(custom[0].span, 0..9);
+
+        assert!(template::analyze("<?if $.a ?>").is_err());
+        assert!(template::analyze("<? upper($.a ?>").is_err());
     }
 
     #[test]
-    fn weekday() {
-        let user_spec = Spec::default();
-        let weekday_num = Date::now().weekday().number_from_monday();
-        assert_eq!(user_spec.eval("get_weekday('_')"), weekday_num);
-        assert_eq!(user_spec.eval("is_weekday('_')"), weekday_num < 6);
+    fn template_render_errors() {
+        use template::{RenderError, RenderOptions, Template, TemplateSet, MemoryLoader};
 
-        assert_eq!(user_spec.eval("get_weekday()"), weekday_num);
-        assert_eq!(user_spec.eval("is_weekday()"), weekday_num < 6);
+        let source = "line one\n  <? $.name * 2 ?> and <? $.n + 1 ?>\n<?if $.name * 2 ?>x<?end?><?for c in 5 ?>y<?end?> <? $.ok ?>";
+        let compiled = Template::compile(source, &EvalConfig::default()).unwrap();
+        let context = json!({"name": "a", "n": "a", "ok": "fine"});
+
+        let error = compiled.render(&context).unwrap_err().to_string();
+        assert!(error.contains("in `<? $.name * 2 ?>` at line 2, column 3"), "{error}");
+
+        let lenient = RenderOptions { continue_on_error: true, error_marker: "#".into(), ..Default::default() };
+        let rendered = compiled.render_with_errors(&context, &lenient).unwrap();
+        assert_eq!(rendered.output, "line one\n  # and #\n## fine");
+        let positions: Vec<_> = rendered.errors.iter().map(|e| (e.line, e.column, e.tag.as_str())).collect();
+        assert_eq!(positions, vec![
+            (2, 3, "<? $.name * 2 ?>"),
+            (2, 24, "<? $.n + 1 ?>"),
+            (3, 1, "<?if $.name * 2 ?>"),
+            (3, 27, "<?for c in 5 ?>"),
+        ]);
+        assert_eq!(rendered.errors[3].message, "cannot iterate over 5");
+        assert_eq!(compiled.render_with(&context, &lenient).unwrap(), rendered.output);
+        let rendered = compiled.render_with_errors(&json!({"name": 2, "n": 1}), &lenient).unwrap();
+        assert_eq!(rendered.output, "line one\n  4 and 2\nx# null");
+        assert_eq!(rendered.errors.len(), 1);
+
+        let strict = RenderOptions { continue_on_error: true, ..RenderOptions::strict() };
+        let rendered = Template::compile("<? $.a ?>-<? $.b ?>", &EvalConfig::default())
+            .unwrap()
+            .render_with_errors(&json!({"b": null}), &strict)
+            .unwrap();
+        assert_eq!(rendered.output, "[ERROR]-[ERROR]");
+        assert_eq!(rendered.errors[1], RenderError {
+            message: "null value".into(),
+            template: None,
+            line: 1,
+            column: 11,
+            tag: "<? $.b ?>".into(),
+        });
+
+        let set = TemplateSet::new(MemoryLoader::new().template("page", "a\n<?include 'part' ?>").template("part", "\n\n <? 1 + 'x' ?>"));
+        let error = set.get("page").unwrap().render_with_errors(&json!({}), &lenient).unwrap().errors.remove(0);
+        assert_eq!((error.template.as_deref(), error.line, error.column), (Some("part"), 3, 2));
     }
 
     #[test]
-    fn time() {
-        let user_spec = Spec::default();
-        assert_eq!(user_spec.eval("get_time('_', 'h')"), Date::now().time().hour());
-        assert_eq!(user_spec.eval("get_time('_', 'm')"), Date::now().time().minute());
-        assert_eq!(user_spec.eval("get_time('_', 's')"), Date::now().time().second());
+    fn template_bindings() {
+        use template::{Bindings, RenderOptions, Template, TemplateResolver};
 
-        assert_eq!(user_spec.eval("get_time('_', 'hour')"), Date::now().time().hour());
-        assert_eq!(
-            user_spec.eval("get_time('_', 'minute')"),
-            Date::now().time().minute()
-        );
+        let context = json!({"name": "Ann", "items": [1, 2], "maths": "shadowed"});
+        let resolver = TemplateResolver::new().context_symbol("ctx");
+        assert_eq!(resolver.resolve("<? ctx.name ?> <? $.name ?>", &context).unwrap(), "Ann null");
+
+        let resolver = TemplateResolver::new().spread_context(true);
         assert_eq!(
-            user_spec.eval("get_time('_', 'second')"),
-            Date::now().time().second()
+            resolver.resolve("<? name ?> <?for i in items ?><? i ?><?end?> <? $.name ?> <? maths ?>", &context).unwrap(),
+            "Ann 12 Ann shadowed"
         );
+        assert_eq!(resolver.resolve("<? name ?>|<? $ ?>", &json!([1])).unwrap(), "null|[1]");
 
-        assert_eq!(user_spec.eval("get_time('_', 'hours')"), Date::now().time().hour());
-        assert_eq!(user_spec.eval("get_time()"), Date::now().time().hour());
+        let compiled = Template::compile(
+            "<? user.name ?> ordered <? len(order.items) ?> items on <? env ?><? missing ?>",
+            &EvalConfig::default(),
+        ).unwrap();
+        let bindings = Bindings::new()
+            .spread(json!({"env": "staging", "user": "overridden"}))
+            .value("user", json!({"name": "Bob"}))
+            .value("order", json!({"items": ["a", "b"]}));
         assert_eq!(
-            user_spec.eval("get_time('_', 'minutes')"),
-            Date::now().time().minute()
+            compiled.render_bindings(&bindings, &RenderOptions::default()).unwrap().output,
+            "Bob ordered 2 items on stagingnull"
         );
+        assert!(compiled.render_bindings(&bindings, &RenderOptions::strict()).is_err());
+    }
+
+    #[test]
+    fn locale_formatting() {
+        let user_spec = Spec::default();
+        assert_eq!(user_spec.eval("format_number(1234567.891)"), to_value("1,234,567.891"));
+        assert_eq!(user_spec.eval("format_number(1234567.891, 'de-DE', 2)"), to_value("1.234.567,89"));
+        assert_eq!(user_spec.eval("format_number(-1234.5, 'fr')"), to_value("-1\u{202f}234,5"));
+        assert_eq!(user_spec.eval("format_number(12345678, 'hi-IN')"), to_value("1,23,45,678"));
+        assert_eq!(user_spec.eval("format_number(9007199254740993, 'en_GB')"), to_value("9,007,199,254,740,993"));
+        assert_eq!(user_spec.eval("format_number(-0.0001, 'en', 2)"), to_value("0.00"));
+
+        assert_eq!(user_spec.eval("format_currency(1234.5, 'EUR', 'de-DE')"), to_value("1.234,50\u{a0}€"));
+        assert_eq!(user_spec.eval("format_currency(-1234.5, 'usd')"), to_value("-$1,234.50"));
+        assert_eq!(user_spec.eval("format_currency(1234.5, 'JPY', 'ja-JP')"), to_value("¥1,234"));
+        assert_eq!(user_spec.eval("format_currency(5, 'XYZ', 'nl')"), to_value("XYZ\u{a0}5,00"));
+        assert_eq!(user_spec.eval("format_percent(0.256)"), to_value("26%"));
+        assert_eq!(user_spec.eval("format_percent(0.256, 'tr', 1)"), to_value("%25,6"));
+
+        assert_eq!(user_spec.eval("format_date(1709632800)"), to_value("Mar 5, 2024"));
+        assert_eq!(user_spec.eval("format_date(1709632800, 'full', 'de')"), to_value("Dienstag, 5. März 2024"));
+        assert_eq!(user_spec.eval("format_date('2024-03-05', 'long', 'es')"), to_value("5 de marzo de 2024"));
+        assert_eq!(user_spec.eval("format_date('2024-03-05T23:30:00Z', 'long', 'ja', 'Asia/Tokyo')"), to_value("2024年3月6日"));
+        assert_eq!(user_spec.eval("format_date('2024-03-05 23:30:00', 'short', 'en-US', 'Asia/Tokyo')"), to_value("3/5/24"));
+        assert_eq!(user_spec.eval("format_date(1709632800, '{d} {MMMM} {HH}:{mm}', 'ru')"), to_value("5 марта 10:00"));
+
+        let now = "'2024-03-05T10:00:00Z'";
+        assert_eq!(user_spec.eval(format!("format_relative('2024-03-02T09:00:00Z', 'en', {now})")), to_value("3 days ago"));
+        assert_eq!(user_spec.eval(format!("format_relative('2024-03-05T12:00:00Z', 'en', {now})")), to_value("in 2 hours"));
+        assert_eq!(user_spec.eval(format!("format_relative('2024-03-05T10:00:00Z', 'fr', {now})")), to_value("maintenant"));
+        assert_eq!(user_spec.eval(format!("format_relative('2024-02-10', 'ru', {now})")), to_value("3 недели назад"));
+        assert_eq!(user_spec.eval(format!("format_relative('2019-01-01', 'pl', {now})")), to_value("5 lat temu"));
+        assert_eq!(user_spec.eval(format!("format_relative('2024-03-05T10:05:00Z', 'ko', {now})")), to_value("5분 후"));
+
+        assert!(ExprWrapper::new("format_number(1, 'xx-YY')").init().exec().is_err());
+        assert!(ExprWrapper::new("format_date('yesterday')").init().exec().is_err());
+        assert!(ExprWrapper::new("format_date(0, 'medium', 'en', 'Mars/Base')").init().exec().is_err());
+
+        let resolver = template::TemplateResolver::new();
         assert_eq!(
-            user_spec.eval("get_time('_', 'seconds')"),
-            Date::now().time().second()
+            resolver.resolve(
+                "<? format_currency($.total, $.currency, $.locale) ?> on <? format_date($.at, 'long', $.locale) ?>",
+                &json!({"total": 99.9, "currency": "SEK", "locale": "sv-SE", "at": 1709632800}),
+            ).unwrap(),
+            "99,90\u{a0}kr on 5 mars 2024"
         );
     }
 
     #[test]
-    fn is_match() {
-        let user_spec = Spec::default();
-        assert_eq!(user_spec.eval("is_match('http', '^https?$')"), to_value(true));
-        assert_eq!(user_spec.eval("is_match('http', 'https')"), to_value(false));
-        assert_eq!(user_spec.eval("is_match('http://', '^udp://')"), to_value(false));
-        assert_eq!(user_spec.eval("is_match('http://', '^(https?|wss?)://$')"), to_value(true));
-        assert_eq!(user_spec.eval(r"is_match('2014-01-01', '^\d{4}-\d{2}-\d{2}$')"), to_value(true));
+    fn decimal_arithmetic() {
+        let without = EvalConfig { include_decimal: false, ..Default::default() };
+        assert!(ExprWrapper::new("decimal('0.1')").config(without).init().exec().is_err());
+
+        let config = EvalConfig::default();
+        let user_spec = Spec { config: config.clone() };
+        assert_eq!(user_spec.eval("0.1 + 0.2 == 0.3"), to_value(false));
+        assert_eq!(user_spec.eval("round_half_even(2.5)"), to_value(2.0));
+        assert_eq!(user_spec.eval("round_half_up(2.5)"), to_value(3.0));
+        assert_eq!(user_spec.eval("round_half_up(-2.5)"), to_value(-3.0));
+        assert_eq!(user_spec.eval("round(1.005, 2)"), to_value(1.0));
+        assert_eq!(user_spec.eval("round_half_up(1.005, 2)"), to_value(1.01));
+        assert_eq!(user_spec.eval("round_half_even(0.125, 2)"), to_value(0.12));
+        assert_eq!(user_spec.eval("round_half_even(1250, -2)"), to_value(1200));
+        assert_eq!(user_spec.eval("round_half_up('2.675', 2)"), to_value(2.68));
+        assert_eq!(user_spec.eval("decimal('19.990')"), to_value(19.99));
+        assert_eq!(user_spec.eval("decimal('20')"), to_value(20));
+
+        assert_eq!(user_spec.eval("dec_add(0.1, 0.2) == 0.3"), to_value(true));
+        assert_eq!(user_spec.eval("dec_add(0.1, 0.2)"), to_value(0.3));
+        assert_eq!(user_spec.eval("dec_sub(0.3, 0.1)"), to_value(0.2));
+        assert_eq!(user_spec.eval("dec_mul(1.1, 3)"), to_value(3.3));
+        assert_eq!(user_spec.eval("dec_mul(19.99, 3)"), to_value(59.97));
+        assert_eq!(user_spec.eval("dec_add(2, 3)"), to_value(5));
+        assert_eq!(user_spec.eval("dec_div(10, 4)"), to_value(2.5));
+        assert_eq!(user_spec.eval("dec_div(6, 3)"), to_value(2.0));
+        assert_eq!(user_spec.eval("dec_div(1, 3)"), to_value(1.0 / 3.0));
+        assert_eq!(user_spec.eval("dec_add('12345678901234567890123', 1)"), to_value(1.2345678901234568e22));
+        assert_eq!(user_spec.eval("dec_cmp('12345678901234567890123', '12345678901234567890122')"), to_value(1));
+        assert_eq!(user_spec.eval("dec_cmp(dec_add(0.1, 0.2), '0.30')"), to_value(0));
+        assert_eq!(user_spec.eval("dec_cmp(0.1, 0.2)"), to_value(-1));
+        assert_eq!(user_spec.eval("round_half_up(dec_mul(19.99, 0.15), 2)"), to_value(3.0));
+        for expression in ["dec_div(1, 0)", "dec_add(1)", "dec_add('a', 1)", "dec_mul(null, 2)"] {
+            assert!(ExprWrapper::new(expression).config(config.clone()).init().exec().is_err(), "{expression}");
+        }
+
+        // Objects are never numbers, whatever their keys.
+        for expression in ["decimal($.x)", "dec_add($.x, 1)", "dec_cmp($.x, '1')", "round_half_up($.x)"] {
+            let mut expr = ExprWrapper::new(expression)
+                .value("$", json!({"x": {"$decimal": "1"}}))
+                .config(config.clone())
+                .init();
+            assert!(expr.exec().is_err(), "{expression}");
+        }
     }
 
     #[test]
-    fn extract() {
-        let user_spec = Spec::default();
-        assert_eq!(user_spec.eval("extract('http://www.floa', 'https?://')"), "http://");
-        assert_eq!(user_spec.eval("extract('foo', 'bar')"), "");
+    fn arithmetic_modes_agree() {
+        use crate::eval_wrapper::Arithmetic;
+
+        let context = json!({"items": [{"price": 2.5, "tags": ["a", "b"]}, 4], "name": "box", "n": -3});
+        let eval = |arithmetic, expression: &str| {
+            ExprWrapper::new(expression)
+                .value("$", &context)
+                .config(EvalConfig { arithmetic, ..Default::default() })
+                .init()
+                .exec()
+        };
+        for expression in [
+            "2 + 3 * 4 - 10 / 4",
+            "(2 + 3) * 4 % 7",
+            "2 * 3 ** 2",
+            "-2 ** 2",
+            "-(2 + 3) * -4",
+            "-$.n + 1",
+            "1 + 2 == 3 && 4 > 3 || false",
+            "1 < 2 == true",
+            "3 in array(1, 2, 3)",
+            "1 + 2 in array(1, 2, 3)",
+            "'b' in $.items[0].tags",
+            "4 not in array(1, 2, 3)",
+            "'ox' in $.name",
+            "$.items[0].price * $.items[1]",
+            "$.items[0].tags[1] + 'c'",
+            "$.items[1 + 1] ?? 'none'",
+            "'a' + 'b' + 'c'",
+            "$.name + '!'",
+            "len($.items) * 2 + 1",
+            "max(1, 2) + min(3, 4) * 2",
+            "abs(-2) ** 2",
+            "if(1 + 1 == 2, 'yes', 'no') + '?'",
+            "upper($.name) == 'BOX'",
+            "!(1 + 1 > 2)",
+            "array(1 + 1, 'x', 2.5 * 2)",
+            "null == null",
+        ] {
+            let float = eval(Arithmetic::Float, expression).unwrap();
+            assert_eq!(eval(Arithmetic::Checked, expression).unwrap(), float, "{expression}");
+        }
+
+        let config = EvalConfig { arithmetic: Arithmetic::Checked, ..Default::default() };
+        let err = ExprWrapper::new("(1 + 2").config(config.clone()).init().exec().unwrap_err();
+        assert!(err.to_string().contains("offset"), "{err}");
+        assert!(template::Template::compile("<? (1 + 2 ?>", &config).is_err());
+        assert!(template::Template::compile("<? (1 + 2 ?>", &EvalConfig::default()).is_err());
     }
 
     #[test]
-    fn template_engine() {
-        let context = json! {{
-            "name": "Kar",
-            "location": "foo-bar",
-            "some": {
-                "deep": {
-                    "value": 42
-                }
-            }
-        }};
+    fn checked_and_big_integers() {
+        use crate::eval_wrapper::Arithmetic;
 
-        assert_eq!(
-            template::resolve_template(
-                "Hi, my name is <? $.name ?> and I live in <? $.location ?> <? $.some.deep.value ?>".to_string(),
-                context.clone(),
-            ).expect("Failed to resolve template"),
-            "Hi, my name is Kar and I live in foo-bar 42".to_string()
-        );
+        let without = EvalConfig { include_bigint: false, ..Default::default() };
+        assert!(ExprWrapper::new("bigint('1')").config(without).init().exec().is_err());
 
+        let bigint = EvalConfig::default();
+        let user_spec = Spec { config: bigint.clone() };
+        assert_eq!(user_spec.eval("int('99999999999999999999')"), to_value(i64::MAX));
+        assert_eq!(user_spec.eval("9007199254740993 == 9007199254740992"), to_value(true));
         assert_eq!(
-            template::resolve_template(
-                "Hi, my name is Kar and I live in foo-bar 42".to_string(),
-                context.clone(),
-            ).expect("Failed to resolve template"),
-            "Hi, my name is Kar and I live in foo-bar 42".to_string()
+            user_spec.eval("bigint('123456789012345678901234567890')"),
+            to_value("123456789012345678901234567890")
         );
-
         assert_eq!(
-            template::resolve_template(
-                "".to_string(),
-                context.clone(),
-            ).expect("Failed to resolve template"),
-            "".to_string()
+            user_spec.eval("bigint_add('123456789012345678901234567890', 10)"),
+            to_value("123456789012345678901234567900")
         );
+        assert_eq!(user_spec.eval("bigint_sub(' +5 ', '-123456789012345678901')"), to_value("123456789012345678906"));
+        assert_eq!(user_spec.eval("bigint_mul(maths.MAX_INT, maths.MAX_INT)"), to_value("85070591730234615847396907784232501249"));
+        assert_eq!(user_spec.eval("bigint_pow(2, 100)"), to_value("1267650600228229401496703205376"));
+        assert_eq!(user_spec.eval("bigint_div(-7, 2)"), to_value("-4"));
+        assert_eq!(user_spec.eval("bigint_mod(-7, 2)"), to_value("1"));
+        assert_eq!(user_spec.eval("bigint_cmp('99999999999999999999', 1e19)"), to_value(1));
+        assert_eq!(user_spec.eval("bigint_cmp(bigint_pow(10, 20), '100000000000000000000')"), to_value(0));
+        assert!(ExprWrapper::new("bigint('12.5')").config(bigint.clone()).init().exec().is_err());
+        assert!(ExprWrapper::new("bigint_div(1, 0)").config(bigint).init().exec().is_err());
 
-        assert_eq!(
-            template::resolve_template(
-                "Hello, <? ?>".to_string(),
-                context,
-            ).expect("Failed to resolve template"),
-            "Hello, ".to_string(),
-        );
+        let config = EvalConfig { arithmetic: Arithmetic::Checked, ..Default::default() };
+        let user_spec = Spec { config: config.clone() };
+        assert_eq!(user_spec.eval("9223372036854775807 - 1"), to_value(i64::MAX - 1));
+        assert_eq!(user_spec.eval("4000000000 * 4000000000"), to_value(16_000_000_000_000_000_000_u64));
+        assert_eq!(user_spec.eval("9007199254740993 == 9007199254740992"), to_value(false));
+        assert_eq!(user_spec.eval("1.5 + 1"), to_value(2.5));
+        assert_eq!(user_spec.eval("7 / 2"), to_value(3.5));
+        assert_eq!(user_spec.eval("-7 % 3"), to_value(-1));
+        assert_eq!(user_spec.eval("int(42.9)"), to_value(42));
+        assert_eq!(user_spec.eval("int('-17')"), to_value(-17));
+        for expression in [
+            "18446744073709551615 + 1",
+            "-9223372036854775807 - 2",
+            "5000000000 * 5000000000",
+            "-(18446744073709551615)",
+            "int('99999999999999999999')",
+            "int(42.9e30)",
+            "int(18446744073709551615)",
+            "7 % 0",
+        ] {
+            assert!(ExprWrapper::new(expression).config(config.clone()).init().exec().is_err(), "{expression}");
+        }
+    }
+    #[test]
+    fn number_bases_and_bits() {
+        let without = EvalConfig { include_bits: false, ..Default::default() };
+        assert!(ExprWrapper::new("hex(255)").config(without.clone()).init().exec().is_err());
+        // The base argument of `int` belongs to the cast group, not to bits.
+        assert_eq!(ExprWrapper::new("int('ff', 16)").init().exec(), Ok(to_value(255)));
+        assert_eq!(ExprWrapper::new("int('ff', 16)").config(without).init().exec(), Ok(to_value(255)));
+        assert_eq!(ExprWrapper::new("int('0x1f', 0)").init().exec(), Ok(to_value(31)));
+
+        let config = EvalConfig::default();
+        let user_spec = Spec { config: config.clone() };
+        assert_eq!(user_spec.eval("hex(255)"), to_value("0xff"));
+        assert_eq!(user_spec.eval("hex(-255)"), to_value("-0xff"));
+        assert_eq!(user_spec.eval("oct(8)"), to_value("0o10"));
+        assert_eq!(user_spec.eval("bin(5)"), to_value("0b101"));
+        assert_eq!(user_spec.eval("hex(18446744073709551615)"), to_value("0xffffffffffffffff"));
+        assert_eq!(user_spec.eval("int('ff', 16)"), to_value(255));
+        assert_eq!(user_spec.eval("int('0xFF', 16)"), to_value(255));
+        assert_eq!(user_spec.eval("int(' -0b1010 ', 0)"), to_value(-10));
+        assert_eq!(user_spec.eval("int('0o17', 0)"), to_value(15));
+        assert_eq!(user_spec.eval("int('1_000', 0)"), to_value(1000));
+        assert_eq!(user_spec.eval("int('z', 36)"), to_value(35));
+        assert_eq!(user_spec.eval("int('ffffffffffffffff', 16)"), to_value(u64::MAX));
+        assert_eq!(user_spec.eval("int('42')"), to_value(42));
+
+        assert_eq!(user_spec.eval("bit_and(12, 10)"), to_value(8));
+        assert_eq!(user_spec.eval("bit_or(12, 10, 1)"), to_value(15));
+        assert_eq!(user_spec.eval("bit_xor(12, 10)"), to_value(6));
+        assert_eq!(user_spec.eval("bit_not(5)"), to_value(-6));
+        assert_eq!(user_spec.eval("shl(1, 63)"), to_value(1_u64 << 63));
+        assert_eq!(user_spec.eval("shr(-16, 2)"), to_value(-4));
+        assert_eq!(user_spec.eval("bit_test(int('0x10', 16), 4)"), to_value(true));
+        assert_eq!(user_spec.eval("bit_test(16, 3)"), to_value(false));
+        assert_eq!(user_spec.eval("bit_test(-1, 200)"), to_value(true));
+        assert_eq!(user_spec.eval("popcount(255)"), to_value(8));
+        assert_eq!(user_spec.eval("popcount(-7)"), to_value(3));
+
+        for expression in [
+            "hex(1.5)",
+            "int('0x1g', 16)",
+            "int('12', 1)",
+            "int('1__0', 10)",
+            "int(12, 10)",
+            "int('1ffffffffffffffff', 16)",
+            "shl(1, 65)",
+            "shl(3, 64)",
+            "bit_and(1)",
+            "bit_test(1, -1)",
+        ] {
+            assert!(ExprWrapper::new(expression).config(config.clone()).init().exec().is_err(), "{expression}");
+        }
+    }
+    #[test]
+    fn numeric_predicates() {
+        let without = EvalConfig { include_predicates: false, ..Default::default() };
+        assert!(ExprWrapper::new("is_int(3)").config(without).init().exec().is_err());
+
+        let config = EvalConfig::default();
+        let user_spec = Spec { config: config.clone() };
+        assert_eq!(user_spec.eval("NAN == NAN"), to_value(true));
+        assert_eq!(user_spec.eval("is_nan(NAN)"), to_value(true));
+        assert_eq!(user_spec.eval("is_nan(maths.NOT_A_NUMBER)"), to_value(true));
+        assert_eq!(user_spec.eval("is_nan(null)"), to_value(false));
+        assert_eq!(user_spec.eval("is_finite(null)"), to_value(false));
+        assert_eq!(user_spec.eval("is_infinite(INFINITY)"), to_value(true));
+        assert_eq!(user_spec.eval("is_infinite(NEG_INFINITY)"), to_value(true));
+        assert_eq!(user_spec.eval("is_infinite(maths.INFINITE)"), to_value(true));
+        assert_eq!(user_spec.eval("is_nan(float('nan'))"), to_value(true));
+        assert_eq!(user_spec.eval("is_nan(float(''))"), to_value(true));
+        assert_eq!(user_spec.eval("is_infinite(float('inf'))"), to_value(true));
+        assert_eq!(user_spec.eval("is_finite(float('1.5'))"), to_value(true));
+        assert_eq!(user_spec.eval("is_infinite(1e999)"), to_value(true));
+        assert_eq!(user_spec.eval("'NAN'"), to_value("NAN"));
+        let checked = EvalConfig { arithmetic: crate::eval_wrapper::Arithmetic::Checked, ..config.clone() };
+        assert_eq!(Spec { config: checked }.eval("is_nan(NAN) && is_infinite(INFINITY)"), to_value(true));
+        let template = template::Template::compile("<? is_nan(NAN) ?> <? INFINITY ?>", &config).unwrap();
+        assert_eq!(template.render(&json!({})).unwrap(), "true inf");
+        assert_eq!(user_spec.eval("is_nan('NaN')"), to_value(true));
+        assert_eq!(user_spec.eval("is_nan(1.5)"), to_value(false));
+        assert_eq!(user_spec.eval("is_nan('abc')"), to_value(false));
+        assert_eq!(user_spec.eval("is_finite(maths.MAX_FLOAT)"), to_value(true));
+        assert_eq!(user_spec.eval("is_finite(NAN)"), to_value(false));
+        assert_eq!(user_spec.eval("is_infinite('-inf')"), to_value(true));
+        assert_eq!(user_spec.eval("is_infinite(maths.MAX_FLOAT)"), to_value(false));
+        assert_eq!(user_spec.eval("is_int(3)"), to_value(true));
+        assert_eq!(user_spec.eval("is_int(3.0)"), to_value(false));
+        assert_eq!(user_spec.eval("is_int('3')"), to_value(false));
+        assert_eq!(user_spec.eval("is_float(3.0)"), to_value(true));
+        assert_eq!(user_spec.eval("is_float(18446744073709551615)"), to_value(false));
+        assert_eq!(user_spec.eval("is_max_int(maths.MAX_INT)"), to_value(true));
+        assert_eq!(user_spec.eval("is_max_int(maths.MAX_INT - 1)"), to_value(false));
+        assert_eq!(user_spec.eval("is_min_int(maths.MIN_INT)"), to_value(true));
+        assert_eq!(user_spec.eval("is_min_int(0)"), to_value(false));
+
+        assert_eq!(user_spec.eval("is_between(5, 1, 5)"), to_value(true));
+        assert_eq!(user_spec.eval("is_between(5, 1, 5, false)"), to_value(false));
+        assert_eq!(user_spec.eval("is_between(2.5, 1, 5, false)"), to_value(true));
+        assert_eq!(user_spec.eval("is_between(9007199254740993, 0, 9007199254740992)"), to_value(false));
+        assert_eq!(user_spec.eval("is_between('b', 'a', 'c')"), to_value(true));
+        assert_eq!(user_spec.eval("is_between(missing, 1, 5)"), to_value(false));
+
+        assert_eq!(user_spec.eval("approx_eq(0.1 + 0.2, 0.3)"), to_value(true));
+        assert_eq!(user_spec.eval("approx_eq(1, 1.001)"), to_value(false));
+        assert_eq!(user_spec.eval("approx_eq(1, 1.001, 0.01)"), to_value(true));
+        assert_eq!(user_spec.eval("approx_eq(0, 0.000000000001)"), to_value(false));
+        assert_eq!(user_spec.eval("approx_eq(0, 0.000000000001, 0.000000001, 0.0000000001)"), to_value(true));
+        assert_eq!(user_spec.eval("approx_eq('nan', 'nan')"), to_value(false));
+        assert_eq!(user_spec.eval("approx_eq(NAN, NAN)"), to_value(false));
+        assert_eq!(user_spec.eval("approx_eq(INFINITY, INFINITY)"), to_value(true));
+        assert!(ExprWrapper::new("approx_eq(1, 1, -1)").config(config.clone()).init().exec().is_err());
+        assert!(ExprWrapper::new("is_between(1, 0, 2, 'yes')").config(config).init().exec().is_err());
     }
 }